@@ -3,6 +3,13 @@
 layout(location = 0) in vec3 a_position;
 // layout(location = 1) in vec2 a_tex_coords;
 // layout(location = 2) in vec3 a_normal;
+layout(location = 3) in uvec4 a_joints;
+layout(location = 4) in vec4 a_weights;
+// モーフターゲットの差分 (model.rs の MorphVertex)
+layout(location = 5) in vec3 a_morph_position0;
+layout(location = 6) in vec3 a_morph_position1;
+layout(location = 7) in vec3 a_morph_position2;
+layout(location = 8) in vec3 a_morph_position3;
 
 layout(set = 0, binding = 0)
 uniform Uniforms {
@@ -12,6 +19,9 @@ uniform Uniforms {
 struct Instance {
     mat4 transform;
     mat4 transform_norm;
+    uint flags;
+    uint joint_offset;
+    vec4 morph_weights;
 };
 
 layout(set = 1, binding = 0)
@@ -19,8 +29,48 @@ buffer Instances {
     Instance instances[];
 };
 
+// 影もスキンに合わせて変形させる
+layout(set = 1, binding = 1)
+readonly buffer Joints {
+    mat4 joints[];
+};
+
+// スキンを持たない頂点は weights が全て 0 なので単位行列になる
+mat4 skin_matrix() {
+    float total = a_weights.x + a_weights.y + a_weights.z + a_weights.w;
+    if (total <= 0.0) {
+        return mat4(1.0);
+    }
+    uint base = instances[gl_InstanceIndex].joint_offset;
+    return a_weights.x * joints[base + a_joints.x]
+         + a_weights.y * joints[base + a_joints.y]
+         + a_weights.z * joints[base + a_joints.z]
+         + a_weights.w * joints[base + a_joints.w];
+}
+
+// MAX_MORPH_TARGETS 個のターゲットを重みで足し合わせる
+vec3 morph_position() {
+    vec4 w = instances[gl_InstanceIndex].morph_weights;
+    return a_position
+        + w.x * a_morph_position0
+        + w.y * a_morph_position1
+        + w.z * a_morph_position2
+        + w.w * a_morph_position3;
+}
+
+// model.rs の INSTANCE_* と合わせること
+const uint INSTANCE_CASTS_SHADOW = 1;
+const uint INSTANCE_VISIBLE = 4;
+
 void main() {
+    uint flags = instances[gl_InstanceIndex].flags;
+    if ((flags & INSTANCE_CASTS_SHADOW) == 0 || (flags & INSTANCE_VISIBLE) == 0) {
+        // クリップ空間の外に追い出して描かせない
+        gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+        return;
+    }
+
     mat4 instance_matrix = instances[gl_InstanceIndex].transform;
-    vec4 instance_space = instance_matrix * vec4(a_position, 1.0);
+    vec4 instance_space = instance_matrix * skin_matrix() * vec4(morph_position(), 1.0);
     gl_Position = u_view_proj * instance_space;
 }
\ No newline at end of file
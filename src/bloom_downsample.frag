@@ -0,0 +1,45 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_source;
+layout(set = 0, binding = 1) uniform sampler s_source;
+layout(set = 0, binding = 2)
+uniform BloomUniform {
+    vec2 u_texel_size; // 読み出す側の1テクセル
+    float u_threshold;
+    float u_knee;
+    float u_intensity;
+    uint u_prefilter;
+};
+
+vec3 fetch(vec2 uv) {
+    return texture(sampler2D(t_source, s_source), uv).rgb;
+}
+
+// 閾値より明るい部分だけ残す。knee の幅でなめらかに切り替える
+vec3 prefilter(vec3 color) {
+    float brightness = max(color.r, max(color.g, color.b));
+    float soft = clamp(brightness - u_threshold + u_knee, 0.0, 2.0 * u_knee);
+    soft = soft * soft / (4.0 * u_knee + 0.00001);
+    float contribution = max(soft, brightness - u_threshold) / max(brightness, 0.00001);
+    return color * contribution;
+}
+
+void main() {
+    // 4点の平均で半分の大きさにする
+    vec4 o = u_texel_size.xyxy * vec4(-1.0, -1.0, 1.0, 1.0);
+    vec3 color = (
+        fetch(v_tex_coords + o.xy) +
+        fetch(v_tex_coords + o.zy) +
+        fetch(v_tex_coords + o.xw) +
+        fetch(v_tex_coords + o.zw)
+    ) * 0.25;
+
+    if (u_prefilter == 1) {
+        color = prefilter(color);
+    }
+
+    f_color = vec4(color, 1.0);
+}
@@ -0,0 +1,35 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_source;
+layout(set = 0, binding = 1) uniform sampler s_source;
+layout(set = 0, binding = 2)
+uniform BloomUniform {
+    vec2 u_texel_size;
+    float u_threshold;
+    float u_knee;
+    float u_intensity;
+    uint u_prefilter;
+};
+
+vec3 fetch(vec2 uv) {
+    return texture(sampler2D(t_source, s_source), uv).rgb;
+}
+
+// 3x3 のテントフィルタで拡大し、加算合成で一段上に足し込む
+void main() {
+    vec4 o = u_texel_size.xyxy * vec4(1.0, 1.0, -1.0, 0.0);
+    vec3 color = fetch(v_tex_coords - o.xy);
+    color += fetch(v_tex_coords - o.wy) * 2.0;
+    color += fetch(v_tex_coords - o.zy);
+    color += fetch(v_tex_coords + o.zw) * 2.0;
+    color += fetch(v_tex_coords) * 4.0;
+    color += fetch(v_tex_coords + o.xw) * 2.0;
+    color += fetch(v_tex_coords + o.zy);
+    color += fetch(v_tex_coords + o.wy) * 2.0;
+    color += fetch(v_tex_coords + o.xy);
+
+    f_color = vec4(color * (u_intensity / 16.0), 1.0);
+}
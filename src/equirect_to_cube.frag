@@ -0,0 +1,33 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_equirect;
+layout(set = 0, binding = 1) uniform sampler s_equirect;
+layout(set = 0, binding = 2)
+uniform FaceUniform {
+    uint u_face; // +X, -X, +Y, -Y, +Z, -Z
+    float u_lod; // 書き込むミップの1テクセルに見合う正距円筒図のミップ
+};
+
+const float PI = 3.14159265359;
+
+// キューブマップの面上の点から見た向き
+vec3 face_direction(uint face, vec2 uv) {
+    vec2 st = uv * 2.0 - 1.0;
+    switch (face) {
+        case 0: return normalize(vec3(1.0, -st.y, -st.x));
+        case 1: return normalize(vec3(-1.0, -st.y, st.x));
+        case 2: return normalize(vec3(st.x, 1.0, st.y));
+        case 3: return normalize(vec3(st.x, -1.0, -st.y));
+        case 4: return normalize(vec3(st.x, -st.y, 1.0));
+        default: return normalize(vec3(-st.x, -st.y, -1.0));
+    }
+}
+
+void main() {
+    vec3 dir = face_direction(u_face, v_tex_coords);
+    vec2 uv = vec2(atan(dir.z, dir.x) / (2.0 * PI) + 0.5, acos(clamp(dir.y, -1.0, 1.0)) / PI);
+    f_color = vec4(textureLod(sampler2D(t_equirect, s_equirect), uv, u_lod).rgb, 1.0);
+}
@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 0) in vec3 v_color;
+layout(location = 1) in vec2 v_corner;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    float r = length(v_corner);
+    if (r > 1.0) {
+        discard;
+    }
+    // 縁を少し暗くして背景と見分けやすくする
+    float edge = smoothstep(0.7, 1.0, r);
+    f_color = vec4(mix(v_color, v_color * 0.3, edge), 1.0);
+}
@@ -0,0 +1,35 @@
+#version 450
+
+// インスタンスごと
+layout(location = 0) in vec3 a_position;
+layout(location = 1) in vec3 a_color;
+
+layout(location = 0) out vec3 v_color;
+layout(location = 1) out vec2 v_corner;
+
+layout(set = 0, binding = 0)
+uniform Uniforms {
+    vec3 u_view_position; // unused
+    mat4 u_view_proj;
+    uint u_light_num; // unused
+};
+
+layout(set = 1, binding = 0)
+uniform GizmoUniform {
+    vec2 u_scale; // NDC 上の半径 (アスペクト比補正済み)
+};
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-1.0, -1.0), vec2( 1.0, -1.0), vec2( 1.0,  1.0),
+    vec2(-1.0, -1.0), vec2( 1.0,  1.0), vec2(-1.0,  1.0)
+);
+
+void main() {
+    v_color = a_color;
+    v_corner = CORNERS[gl_VertexIndex];
+
+    // 画面上で常に同じ大きさになるようにクリップ空間でずらす
+    vec4 clip = u_view_proj * vec4(a_position, 1.0);
+    clip.xy += v_corner * u_scale * clip.w;
+    gl_Position = clip;
+}
@@ -0,0 +1,111 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform textureCube t_env;
+layout(set = 0, binding = 1) uniform sampler s_env;
+layout(set = 0, binding = 2)
+uniform FilterUniform {
+    uint u_face;
+    uint u_mode; // 0: 放射照度, 1: 鏡面反射の前計算
+    float u_roughness;
+    uint u_samples;
+    float u_env_size; // t_env の一番大きいミップの一辺
+    float u_lod; // 放射照度で読むミップ
+};
+
+const float PI = 3.14159265359;
+
+vec3 face_direction(uint face, vec2 uv) {
+    vec2 st = uv * 2.0 - 1.0;
+    switch (face) {
+        case 0: return normalize(vec3(1.0, -st.y, -st.x));
+        case 1: return normalize(vec3(-1.0, -st.y, st.x));
+        case 2: return normalize(vec3(st.x, 1.0, st.y));
+        case 3: return normalize(vec3(st.x, -1.0, -st.y));
+        case 4: return normalize(vec3(st.x, -st.y, 1.0));
+        default: return normalize(vec3(-st.x, -st.y, -1.0));
+    }
+}
+
+mat3 tangent_frame(vec3 n) {
+    vec3 up = abs(n.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(up, n));
+    return mat3(tangent, cross(n, tangent), n);
+}
+
+vec3 irradiance(vec3 n) {
+    mat3 frame = tangent_frame(n);
+    vec3 sum = vec3(0.0);
+    float count = 0.0;
+    const float DELTA = 0.05;
+    for (float phi = 0.0; phi < 2.0 * PI; phi += DELTA) {
+        for (float theta = 0.0; theta < 0.5 * PI; theta += DELTA) {
+            vec3 t = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            sum += textureLod(samplerCube(t_env, s_env), frame * t, u_lod).rgb * cos(theta) * sin(theta);
+            count += 1.0;
+        }
+    }
+    return PI * sum / count;
+}
+
+float radical_inverse(uint bits) {
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return float(bits) * 2.3283064365386963e-10;
+}
+
+vec2 hammersley(uint i, uint n) {
+    return vec2(float(i) / float(n), radical_inverse(i));
+}
+
+// GGX の法線分布に従う半ベクトル (接空間)
+vec3 importance_sample_ggx(vec2 xi, float a) {
+    float phi = 2.0 * PI * xi.x;
+    float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+}
+
+float distribution_ggx(float n_dot_h, float a) {
+    float a2 = a * a;
+    float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// 視線と法線が同じ向きだと仮定して畳み込む (split sum の近似)
+vec3 prefilter(vec3 n) {
+    float a = u_roughness * u_roughness;
+    mat3 frame = tangent_frame(n);
+    // 1テクセルが受け持つ立体角
+    float texel_solid_angle = 4.0 * PI / (6.0 * u_env_size * u_env_size);
+
+    vec3 sum = vec3(0.0);
+    float weight = 0.0;
+    for (uint i = 0; i < u_samples; i++) {
+        vec3 h = frame * importance_sample_ggx(hammersley(i, u_samples), a);
+        vec3 l = normalize(2.0 * dot(n, h) * h - n);
+        float n_dot_l = dot(n, l);
+        if (n_dot_l <= 0.0) {
+            continue;
+        }
+        // 確率の低い向きほど粗いミップで読み、少ないサンプル数でのちらつきを抑える
+        float n_dot_h = max(dot(n, h), 0.0);
+        float pdf = distribution_ggx(n_dot_h, a) * 0.25 + 0.0001;
+        float sample_solid_angle = 1.0 / (float(u_samples) * pdf);
+        float lod = u_roughness == 0.0 ? 0.0 : 0.5 * log2(sample_solid_angle / texel_solid_angle);
+        sum += textureLod(samplerCube(t_env, s_env), l, max(lod, 0.0)).rgb * n_dot_l;
+        weight += n_dot_l;
+    }
+    return sum / max(weight, 0.0001);
+}
+
+void main() {
+    vec3 n = face_direction(u_face, v_tex_coords);
+    vec3 color = u_mode == 0 ? irradiance(n) : prefilter(n);
+    f_color = vec4(color, 1.0);
+}
@@ -4,6 +4,8 @@ use obj_viewer::shader_settings::{
     ShaderState,
     shadowmap,
     camera,
+    animation::{self, AnimationTarget},
+    input_binding::InputBindings,
 };
 use model::*;
 use light::*;
@@ -37,6 +39,52 @@ fn main() -> Result<()> {
         },
     };
 
+    // 主光源と青い光源を y 軸周りに 60 秒で一周させる
+    for &id in [0, 2].iter() {
+        let position = state.light(id)?.borrow().position;
+        state.animator.add_clip(orbit_clip(
+            format!("light{}_orbit", id),
+            AnimationTarget::Light(id),
+            position,
+            60.0,
+        ));
+    }
+
+    // 設定ファイルは config_dir に置く
+    let config = config_dir();
+
+    load_config(&config.join("scene.anim"), |path| state.load_animation(path));
+
+    load_config(&config.join("input_bindings.txt"), |path| {
+        state.input_bindings = InputBindings::load(path)?;
+        Ok(())
+    });
+
+    // ブルームや LUT などの後処理
+    load_config(&config.join("post_process.txt"), |path| state.load_post_process(path));
+
+    // 霧。無ければ霧は掛けない
+    load_config(&config.join("fog.txt"), |path| state.load_fog(path));
+
+    // 雪の降り方。無ければ既定の値で降らせる
+    load_config(&config.join("snow.txt"), |path| state.load_snow(path));
+
+    // 積もる雪。fade で時間をかけて積もらせることもできる
+    load_config(&config.join("snow_cover.txt"), |path| state.load_snow_cover(path));
+
+    // 背景と環境光。画像なのでモデルと一緒に assets に置く。無ければ単色の背景のまま
+    let environment_path = std::path::Path::new(env!("OUT_DIR")).join("assets").join("environment.hdr");
+    load_config(&environment_path, |path| state.load_environment(path));
+
+    // 書き出した連番と一緒に保存されるカメラの経路。置いておけばそのまま書き出し直せる
+    load_config(&config.join("camera_path.txt"), |path| state.load_camera_path(path));
+
+    // カメラのブックマークと前回終了時の位置。読めなければブックマーク無しで続ける
+    let bookmark_path = config.join("camera_bookmarks.txt");
+    if let Err(e) = state.load_camera_bookmarks(&bookmark_path) {
+        log::warn!("Camera bookmarks are not loaded and will not be saved: {:?}", e);
+    }
+
     let mut last_render_time = std::time::Instant::now();
     event_loop.run(move |event, _, control_flow| {
         match event {
@@ -70,53 +118,20 @@ fn main() -> Result<()> {
                 let now = std::time::Instant::now();
                 let dt = now - last_render_time;
                 last_render_time = now;
-                state.update(dt, |s| {
-
-                    let mut main_light = s.light_book[0].borrow_mut();
-                    let old_position = main_light.position;
-                    main_light.position =
-                        cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(0.1))
-                        * old_position;
-                    s.light_buffer.update_light(&s.queue, &main_light);
-
-                    let pos = main_light.position;
-                    main_light.shadow.update(
-                        Some(pos),
-                        None,
-                        &s.queue,
-                        &mut s.shadow_uniform_buffer,
-                    );
-
-                    drop(main_light);
-
-                    let mut sub_light = s.light_book[2].borrow_mut();
-                    let old_position = sub_light.position;
-                    sub_light.position =
-                        cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(0.1))
-                        * old_position;
-                    s.light_buffer.update_light(&s.queue, &sub_light);
-
-                    let pos = sub_light.position;
-                    sub_light.shadow.update(
-                        Some(pos),
-                        None,
-                        &s.queue,
-                        &mut s.shadow_uniform_buffer,
-                    );
-
-                    /*
-                    s.shadowmap.position = cgmath::Point3::from_vec(main_light.position);
-                    s.shadowmap.direction = -main_light.position.normalize();
-                    s.shadowmap.update_view_proj(&s.queue);
-                    */
-
-                    Ok(())
-                }).unwrap();
+                // 1フレームの失敗でビューアは止めない
+                if let Err(e) = state.update(dt, |_| Ok(())) {
+                    log::error!("{:?}", e);
+                }
                 state.render();
             },
             Event::MainEventsCleared => {
                 window.request_redraw();
             },
+            Event::LoopDestroyed => {
+                if let Err(e) = state.save_camera_bookmarks() {
+                    log::warn!("{:?}", e);
+                }
+            },
             _ => (),
         }
     });
@@ -124,6 +139,56 @@ fn main() -> Result<()> {
     // Ok(())
 }
 
+// ユーザーが書き換える設定を置く場所。実行ファイルと同じ階層の config。
+// ビルドの成果物である OUT_DIR には置かない
+fn config_dir() -> std::path::PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("config")))
+        .unwrap_or_else(|| std::path::PathBuf::from("config"))
+}
+
+// path があれば load で読み込む。失敗はログに出すだけでビューアは止めない
+fn load_config<F>(path: &std::path::Path, load: F)
+where
+    F: FnOnce(&std::path::Path) -> Result<()>,
+{
+    if !path.exists() {
+        return;
+    }
+    if let Err(e) = load(path) {
+        log::error!("Cannot load {:?}: {:?}", path, e);
+    }
+}
+
+// from を y 軸周りに period 秒で一周させるクリップ
+fn orbit_clip(
+    name: String,
+    target: AnimationTarget,
+    from: cgmath::Vector3<f32>,
+    period: f32,
+) -> animation::Clip {
+    // slerp は 180 度未満のキーの間しか一意に決まらないので 90 度ごとに置く
+    const DIVISION: usize = 4;
+    let keys = (0..=DIVISION).map(|i| {
+        let rate = i as f32 / DIVISION as f32;
+        animation::Keyframe {
+            time: period * rate,
+            value: cgmath::Quaternion::from_axis_angle(
+                cgmath::Vector3::unit_y(),
+                cgmath::Deg(360.0 * rate),
+            ),
+            easing: animation::Easing::Linear,
+        }
+    }).collect();
+
+    animation::Clip::new(
+        name,
+        animation::LoopMode::Loop,
+        vec![(target, animation::Channel::Orbit(from, animation::Track::new(keys)))],
+    )
+}
+
 fn prepare_objects(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
@@ -133,14 +198,13 @@ fn prepare_objects(
     shadow_texture: &wgpu::Texture,
 ) -> Result<(Vec<Instance>, Vec<Light>, Vec<Instance>)>
 {
-    use shadowmap::DirUpdateWay;
+    use shadowmap::{DirUpdateWay, ShadowFilter, ShadowBias};
     use camera::Projection;
 
     let pos1 = (-5.0, 10.0, 5.0);
     let pos2 = (0.0, 2.1, 1.2);
 
     let shadow_1 = shadowmap::ShadowMap::new(
-        0,
         pos1.into(),
         (0.0, 0.0, 0.0).into(), // don't use
         0.5,
@@ -148,6 +212,8 @@ fn prepare_objects(
             anchor_pos: (0.0, 0.0, 0.0).into(),
         },
         Projection::new(sc_desc.width, sc_desc.height, cgmath::Deg(45.0), 0.1, 100.0),
+        ShadowFilter::Pcss { samples: 16, search_scale: 4.0 },
+        ShadowBias::default(),
         device,
         queue,
         sc_desc,
@@ -157,12 +223,13 @@ fn prepare_objects(
 
     let spot_light_dir = (0.0, -1.0, 0.0);
     let shadow_2 = shadowmap::ShadowMap::new(
-        1,
         pos2.into(),
         spot_light_dir.into(),
         0.0,
         DirUpdateWay::SpotLight,
         Projection::new(sc_desc.width, sc_desc.height, cgmath::Deg(120.0), 0.1, 100.0),
+        ShadowFilter::Poisson { samples: 12, spread: 2.0 },
+        ShadowBias::default(),
         device,
         queue,
         sc_desc,
@@ -172,7 +239,6 @@ fn prepare_objects(
 
     let pos3 = (-5.0, 10.0, -5.0);
     let shadow_3 = shadowmap::ShadowMap::new(
-        2,
         pos3.into(),
         (0.0, 0.0, 0.0).into(), // don't use
         0.5,
@@ -180,6 +246,8 @@ fn prepare_objects(
             anchor_pos: (0.0, 0.0, 0.0).into(),
         },
         Projection::new(sc_desc.width, sc_desc.height, cgmath::Deg(45.0), 0.1, 100.0),
+        ShadowFilter::Pcss { samples: 16, search_scale: 4.0 },
+        ShadowBias::default(),
         device,
         queue,
         sc_desc,
@@ -225,7 +293,7 @@ fn prepare_objects(
     )?;
     let bulb = Rc::new(bulb);
 
-    let bulb_i = Model::instantiate(
+    let mut bulb_i = Model::instantiate(
         bulb.clone(),
         "bulb".to_string(),
         (0.0, 2.08, 1.2).into(),
@@ -235,9 +303,36 @@ fn prepare_objects(
         ),
         0.042
     );
+    // 電球自体は影を落とさない
+    bulb_i.casts_shadow = false;
+
+    let mut instances = vec![house_i];
+
+    // スキンメッシュのモデルがあれば置いて、最初のクリップを再生する
+    let character_path = assets_dir.join("character.glb");
+    if character_path.exists() {
+        let character = Rc::new(Model::load_gltf(
+            2, device, queue, texture_layout,
+            character_path,
+        )?);
+        let mut character_i = Model::instantiate(
+            character.clone(),
+            "character".to_string(),
+            (2.0, 0.0, 2.0).into(),
+            cgmath::Quaternion::from_axis_angle(
+                cgmath::Vector3::unit_y(),
+                cgmath::Deg(0.0)
+            ),
+            1.0
+        );
+        if character.skeleton.as_ref().map_or(false, |s| !s.clips.is_empty()) {
+            character_i.skin.play(0, 1.0);
+        }
+        instances.push(character_i);
+    }
 
     Ok((
-        vec![house_i],
+        instances,
         lights,
         vec![bulb_i]
     ))
@@ -1,6 +1,7 @@
 #version 450
 
 layout(location = 0) in vec2 v_tex_coords;
+layout(location = 1) in vec3 v_position;
 layout(location = 0) out vec4 f_color;
 
 layout(set = 1, binding = 0)
@@ -10,6 +11,28 @@ uniform Uniforms {
     int u_light_num;
 };
 
+layout(set = 1, binding = 6) uniform textureCube t_environment;
+layout(set = 1, binding = 10) uniform sampler s_environment;
+layout(set = 1, binding = 11)
+uniform EnvironmentUniform {
+    float u_env_intensity;
+    float u_prefiltered_max_lod;
+    uint u_env_loaded;
+    float u_skybox_lod;
+};
+
+layout(set = 1, binding = 12)
+uniform FogUniform {
+    vec3 u_fog_color;
+    float u_fog_density;
+    float u_fog_start;
+    float u_fog_height_density;
+    float u_fog_height_falloff;
+    float u_fog_base_height;
+    uint u_fog_enabled;
+    uint u_fog_use_horizon;
+};
+
 layout(set = 0, binding = 0) uniform texture2D t_diffuse;
 layout(set = 0, binding = 1) uniform sampler s_diffuse;
 layout(set = 0, binding = 2)
@@ -20,10 +43,45 @@ uniform MaterialUniform {
     vec3 u_specular;
 };
 
+// shader.frag と合わせること
+const float FOG_HORIZON_LOD = 5.0;
+
+// 視点から position までの霧の量 (0..1)。shader.frag と合わせること
+float fog_amount(vec3 position) {
+    vec3 ray = position - u_view_position;
+    float dist = max(length(ray) - u_fog_start, 0.0);
+    // 低いほど濃い霧を視線に沿って積分したもの
+    float falloff = max(u_fog_height_falloff, 0.0001);
+    float eye_density = u_fog_height_density * exp(-falloff * (u_view_position.y - u_fog_base_height));
+    float dy = falloff * ray.y;
+    float height_factor = abs(dy) > 0.0001 ? (1.0 - exp(-dy)) / dy : 1.0;
+    float optical_depth = dist * (u_fog_density + eye_density * height_factor);
+    return 1.0 - exp(-optical_depth);
+}
+
+// 霧の色。指定があれば地平線あたりの空の色を使う
+vec3 fog_color(vec3 position) {
+    if (u_fog_use_horizon == 0 || u_env_loaded == 0) {
+        return u_fog_color;
+    }
+    vec3 dir = position - u_view_position;
+    dir = normalize(vec3(dir.x, 0.0, dir.z) + vec3(0.0, 0.0001, 0.0));
+    // 空の細かい模様を拾わないようにぼかしたミップを読む
+    return textureLod(samplerCube(t_environment, s_environment), dir, FOG_HORIZON_LOD).rgb * u_env_intensity;
+}
+
+vec3 apply_fog(vec3 color, vec3 position) {
+    if (u_fog_enabled == 0) {
+        return color;
+    }
+    return mix(color, fog_color(position), fog_amount(position));
+}
+
 void main() {
     if (use_texture == 1) {
         f_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
     } else {
         f_color = vec4(u_diffuse, 1.0);
     }
-}
\ No newline at end of file
+    f_color.rgb = apply_fog(f_color.rgb, v_position);
+}
@@ -3,6 +3,7 @@
 layout(location = 0) in vec3 a_position;
 layout(location = 1) in vec2 a_tex_coords;
 layout(location = 0) out vec2 v_tex_coords;
+layout(location = 1) out vec3 v_position;
 
 layout(set = 1, binding = 0)
 uniform Uniforms {
@@ -14,6 +15,9 @@ uniform Uniforms {
 struct Instance {
     mat4 transform;
     mat4 transform_norm;
+    uint flags;
+    uint joint_offset;
+    vec4 morph_weights;
 };
 
 layout(set = 2, binding = 0)
@@ -21,9 +25,18 @@ buffer Instances {
     Instance instances[];
 };
 
+// model.rs の INSTANCE_* と合わせること
+const uint INSTANCE_VISIBLE = 4;
+
 void main() {
     v_tex_coords = a_tex_coords;
+    if ((instances[gl_InstanceIndex].flags & INSTANCE_VISIBLE) == 0) {
+        gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+        return;
+    }
 
     mat4 instance_matrix = instances[gl_InstanceIndex].transform;
-    gl_Position = u_view_proj * instance_matrix * vec4(a_position, 1.0);
+    vec4 world_position = instance_matrix * vec4(a_position, 1.0);
+    v_position = world_position.xyz;
+    gl_Position = u_view_proj * world_position;
 }
\ No newline at end of file
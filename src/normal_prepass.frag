@@ -0,0 +1,13 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 1) in vec3 v_normal;
+layout(location = 2) in vec4 v_position;
+layout(location = 3) flat in uint v_flags;
+
+layout(location = 0) out vec4 f_normal;
+
+// SSAO のためにワールド座標の法線だけを書き出す。a = 0 の画素には何も無い
+void main() {
+    f_normal = vec4(normalize(v_normal), 1.0);
+}
@@ -0,0 +1,66 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_source;
+layout(set = 0, binding = 1) uniform sampler s_source;
+layout(set = 0, binding = 2)
+uniform PostUniform {
+    vec2 u_texel_size;
+    vec4 u_viewport;
+    float u_vignette_intensity;
+    float u_vignette_radius;
+    float u_vignette_smoothness;
+    float u_lut_strength;
+    float u_lut_size;
+};
+
+const float FXAA_SPAN_MAX = 8.0;
+const float FXAA_REDUCE_MUL = 1.0 / 8.0;
+const float FXAA_REDUCE_MIN = 1.0 / 128.0;
+
+vec4 fetch(vec2 uv) {
+    return texture(sampler2D(t_source, s_source), uv);
+}
+
+// 輝度はガンマ空間に近づけてから測る
+float luma(vec3 color) {
+    return dot(sqrt(color), vec3(0.299, 0.587, 0.114));
+}
+
+void main() {
+    vec2 uv = v_tex_coords;
+    vec4 center = fetch(uv);
+    float luma_nw = luma(fetch(uv + vec2(-1.0, -1.0) * u_texel_size).rgb);
+    float luma_ne = luma(fetch(uv + vec2(1.0, -1.0) * u_texel_size).rgb);
+    float luma_sw = luma(fetch(uv + vec2(-1.0, 1.0) * u_texel_size).rgb);
+    float luma_se = luma(fetch(uv + vec2(1.0, 1.0) * u_texel_size).rgb);
+    float luma_m = luma(center.rgb);
+
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // 輪郭に沿う向きを求める
+    vec2 dir = vec2(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se)
+    );
+    float dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+    float rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2(-FXAA_SPAN_MAX), vec2(FXAA_SPAN_MAX)) * u_texel_size;
+
+    vec3 rgb_a = 0.5 * (
+        fetch(uv + dir * (1.0 / 3.0 - 0.5)).rgb +
+        fetch(uv + dir * (2.0 / 3.0 - 0.5)).rgb
+    );
+    vec3 rgb_b = rgb_a * 0.5 + 0.25 * (
+        fetch(uv + dir * -0.5).rgb +
+        fetch(uv + dir * 0.5).rgb
+    );
+
+    // 広げすぎて別の面を拾ったら狭い方を使う
+    float luma_b = luma(rgb_b);
+    vec3 color = (luma_b < luma_min || luma_b > luma_max) ? rgb_a : rgb_b;
+    f_color = vec4(color, center.a);
+}
@@ -0,0 +1,41 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_source;
+layout(set = 0, binding = 1) uniform sampler s_source;
+layout(set = 0, binding = 2)
+uniform PostUniform {
+    vec2 u_texel_size;
+    vec4 u_viewport;
+    float u_vignette_intensity;
+    float u_vignette_radius;
+    float u_vignette_smoothness;
+    float u_lut_strength;
+    float u_lut_size;
+};
+
+layout(set = 1, binding = 0) uniform texture3D t_lut;
+layout(set = 1, binding = 1) uniform sampler s_lut;
+
+vec3 linear_to_srgb(vec3 c) {
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(vec3(0.0031308), c));
+}
+
+vec3 srgb_to_linear(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), step(vec3(0.04045), c));
+}
+
+void main() {
+    vec4 color = texture(sampler2D(t_source, s_source), v_tex_coords);
+
+    // .cube の LUT は表示用 (sRGB) の値を引く前提で作られている
+    vec3 srgb = linear_to_srgb(clamp(color.rgb, 0.0, 1.0));
+    // 端のテクセルの中心を指すように寄せる
+    vec3 uvw = srgb * ((u_lut_size - 1.0) / u_lut_size) + 0.5 / u_lut_size;
+    vec3 graded = texture(sampler3D(t_lut, s_lut), uvw).rgb;
+
+    color.rgb = srgb_to_linear(mix(srgb, graded, u_lut_strength));
+    f_color = color;
+}
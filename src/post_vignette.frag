@@ -0,0 +1,30 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_source;
+layout(set = 0, binding = 1) uniform sampler s_source;
+layout(set = 0, binding = 2)
+uniform PostUniform {
+    vec2 u_texel_size;
+    vec4 u_viewport; // 分割して描くときの、画像全体に対するこのタイルの位置 (xy) と大きさ (zw)
+    float u_vignette_intensity;
+    float u_vignette_radius;
+    float u_vignette_smoothness;
+    float u_lut_strength;
+    float u_lut_size;
+};
+
+void main() {
+    vec4 color = texture(sampler2D(t_source, s_source), v_tex_coords);
+
+    vec2 uv = u_viewport.xy + v_tex_coords * u_viewport.zw;
+    // 中心で 0、四隅で 1
+    float d = length(uv - 0.5) * 1.41421356;
+    // radius - smoothness から暗くなり始め、radius で一番暗くなる
+    float vignette = 1.0 - smoothstep(u_vignette_radius - u_vignette_smoothness, u_vignette_radius, d);
+    color.rgb *= mix(1.0, vignette, u_vignette_intensity);
+
+    f_color = color;
+}
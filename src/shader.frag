@@ -3,6 +3,7 @@
 layout(location = 0) in vec2 v_tex_coords;
 layout(location = 1) in vec3 v_normal;
 layout(location = 2) in vec4 v_position;
+layout(location = 3) flat in uint v_flags;
 // layout(location = 3) in vec4 s_gl_position;
 
 layout(location = 0) out vec4 f_color;
@@ -45,6 +46,10 @@ struct ShadowUniform {
     uint tex_width;
     uint tex_height;
     float darkness;
+    uint filter_mode; // 0: hard, 1: pcf, 2: poisson, 3: pcss
+    uint filter_samples;
+    float filter_radius;
+    float depth_bias;
 };
 
 layout(set = 1, binding = 2)
@@ -53,16 +58,148 @@ buffer ShadowUniforms {
 };
 layout(set = 1, binding = 3) uniform texture2DArray t_shadow;
 layout(set = 1, binding = 4) uniform samplerShadow s_shadow;
+layout(set = 1, binding = 5) uniform sampler s_shadow_raw;
 
-float fetch_shadow(int light_id, vec4 homogeneous_coords) {
+// 環境マップによる光。読み込むまでは u_env_loaded が 0
+layout(set = 1, binding = 6) uniform textureCube t_environment;
+layout(set = 1, binding = 7) uniform textureCube t_irradiance;
+layout(set = 1, binding = 8) uniform textureCube t_prefiltered;
+layout(set = 1, binding = 9) uniform texture2D t_brdf_lut;
+layout(set = 1, binding = 10) uniform sampler s_environment;
+layout(set = 1, binding = 11)
+uniform EnvironmentUniform {
+    float u_env_intensity;
+    float u_prefiltered_max_lod;
+    uint u_env_loaded;
+    float u_skybox_lod;
+};
+
+layout(set = 1, binding = 12)
+uniform FogUniform {
+    vec3 u_fog_color;
+    float u_fog_density;
+    float u_fog_start;
+    float u_fog_height_density;
+    float u_fog_height_falloff;
+    float u_fog_base_height;
+    uint u_fog_enabled;
+    uint u_fog_use_horizon;
+};
+
+// 積もる雪。上空から真下に見た深度で屋根の下かどうかを調べる
+layout(set = 1, binding = 13) uniform texture2D t_snow_sky;
+layout(set = 1, binding = 14)
+uniform SnowCoverUniform {
+    mat4 u_sky_view_proj;
+    vec3 u_snow_color;
+    float u_snow_coverage;
+    float u_snow_min_up;
+    float u_snow_blend;
+    float u_snow_depth_bias;
+    uint u_snow_enabled;
+};
+
+// SSAO の結果。無効なときは 1x1 の白
+layout(set = 3, binding = 0) uniform texture2D t_ao;
+layout(set = 3, binding = 1) uniform sampler s_ao;
+
+// model.rs の INSTANCE_* と合わせること
+const uint INSTANCE_RECEIVES_SHADOW = 2;
+
+// 材質に粗さが無いので一律にこの値で反射を読む
+const float IBL_ROUGHNESS = 0.3;
+// 誘電体の正面反射率
+const vec3 IBL_F0 = vec3(0.04);
+
+// 霧の色に使う環境マップのミップ (512 -> 16)
+const float FOG_HORIZON_LOD = 5.0;
+
+const uint FILTER_HARD = 0;
+const uint FILTER_PCF = 1;
+const uint FILTER_POISSON = 2;
+const uint FILTER_PCSS = 3;
+
+// ShadowUniform::MAX_SAMPLES と合わせること
+const vec2 POISSON_DISK[16] = vec2[](
+    vec2(-0.94201624, -0.39906216),
+    vec2( 0.94558609, -0.76890725),
+    vec2(-0.09418410, -0.92938870),
+    vec2( 0.34495938,  0.29387760),
+    vec2(-0.91588581,  0.45771432),
+    vec2(-0.81544232, -0.87912464),
+    vec2(-0.38277543,  0.27676845),
+    vec2( 0.97484398,  0.75648379),
+    vec2( 0.44323325, -0.97511554),
+    vec2( 0.53742981, -0.47373420),
+    vec2(-0.26496911, -0.41893023),
+    vec2( 0.79197514,  0.19090188),
+    vec2(-0.24188840,  0.99706507),
+    vec2(-0.81409955,  0.91437590),
+    vec2( 0.19984126,  0.78641367),
+    vec2( 0.14383161, -0.14100790)
+);
+
+float shadow_tap(int light_id, vec2 uv, float z_val) {
+    return texture(sampler2DArrayShadow(t_shadow, s_shadow), vec4(uv, light_id, z_val));
+}
+
+float shadow_pcf(int light_id, vec2 uv, float z_val, vec2 texel, uint kernel) {
+    float sum = 0.0;
+    float half_k = (float(kernel) - 1.0) * 0.5;
+    for (uint y = 0; y < kernel; y++) {
+        for (uint x = 0; x < kernel; x++) {
+            vec2 offset = (vec2(x, y) - half_k) * texel;
+            sum += shadow_tap(light_id, uv + offset, z_val);
+        }
+    }
+    return sum / float(kernel * kernel);
+}
+
+float shadow_poisson(int light_id, vec2 uv, float z_val, vec2 radius, uint samples) {
+    float sum = 0.0;
+    for (uint i = 0; i < samples; i++) {
+        sum += shadow_tap(light_id, uv + POISSON_DISK[i] * radius, z_val);
+    }
+    return sum / float(samples);
+}
+
+// 遮蔽物の平均深度を探し、その距離から半影の幅を決める
+float shadow_pcss(int light_id, vec2 uv, float z_val, vec2 texel, uint samples, float light_radius) {
+    vec2 search = texel * max(shadows[light_id].filter_radius * max(light_radius, 1.0), 1.0);
+
+    float blocker_sum = 0.0;
+    float blocker_num = 0.0;
+    for (uint i = 0; i < samples; i++) {
+        float d = texture(
+            sampler2DArray(t_shadow, s_shadow_raw),
+            vec3(uv + POISSON_DISK[i] * search, light_id)
+        ).r;
+        if (d < z_val) {
+            blocker_sum += d;
+            blocker_num += 1.0;
+        }
+    }
+    if (blocker_num == 0.0) {
+        return 1.0;
+    }
+
+    float blocker = blocker_sum / blocker_num;
+    float penumbra = (z_val - blocker) * light_radius / max(blocker, 0.0001);
+    // 深度は非線形な NDC のままなので近似。1テクセルから探索範囲までに収める
+    vec2 radius = clamp(texel * penumbra * float(shadows[light_id].tex_width), texel, search);
+    return shadow_poisson(light_id, uv, z_val, radius, samples);
+}
+
+float fetch_shadow(int light_id, vec4 homogeneous_coords, float light_radius) {
     if (homogeneous_coords.w <= 0.0) {
         return 1.0;
     }
 
-    float z_val = homogeneous_coords.z / homogeneous_coords.w;
+    float z_val = homogeneous_coords.z / homogeneous_coords.w - shadows[light_id].depth_bias;
 
     uint tex_width = shadows[light_id].tex_width;
     uint tex_height = shadows[light_id].tex_height;
+    vec2 texel = 1.0 / vec2(tex_width, tex_height);
 
     // compensate for the Y-flip difference between the NDC and texture coordinates
     const vec2 flip_correction = vec2(0.5, -0.5);
@@ -72,15 +209,78 @@ float fetch_shadow(int light_id, vec4 homogeneous_coords) {
         return 1.0;
     }
 
-    // compute texture coordinates for shadow lookup
-    vec4 light_local = vec4(
-        xy_val,
-        light_id,
-        z_val
-    );
     // do the lookup, using HW PCF and comparison
-    // return z_val > texture(sampler2DShadow(t_shadow, s_shadow), light_local) ? 0.5 : 1.0;
-    return max(texture(sampler2DArrayShadow(t_shadow, s_shadow), light_local), shadows[light_id].darkness);
+    float lit;
+    uint samples = shadows[light_id].filter_samples;
+    switch (shadows[light_id].filter_mode) {
+        case FILTER_PCF:
+            lit = shadow_pcf(light_id, xy_val, z_val, texel, samples);
+            break;
+        case FILTER_POISSON:
+            lit = shadow_poisson(light_id, xy_val, z_val, texel * shadows[light_id].filter_radius, samples);
+            break;
+        case FILTER_PCSS:
+            lit = shadow_pcss(light_id, xy_val, z_val, texel, samples, light_radius);
+            break;
+        default:
+            lit = shadow_tap(light_id, xy_val, z_val);
+            break;
+    }
+    return max(lit, shadows[light_id].darkness);
+}
+
+// 視点から position までの霧の量 (0..1)。no_shade.frag と合わせること
+float fog_amount(vec3 position) {
+    vec3 ray = position - u_view_position;
+    float dist = max(length(ray) - u_fog_start, 0.0);
+    // 低いほど濃い霧を視線に沿って積分したもの
+    float falloff = max(u_fog_height_falloff, 0.0001);
+    float eye_density = u_fog_height_density * exp(-falloff * (u_view_position.y - u_fog_base_height));
+    float dy = falloff * ray.y;
+    float height_factor = abs(dy) > 0.0001 ? (1.0 - exp(-dy)) / dy : 1.0;
+    float optical_depth = dist * (u_fog_density + eye_density * height_factor);
+    return 1.0 - exp(-optical_depth);
+}
+
+// 霧の色。指定があれば地平線あたりの空の色を使う
+vec3 fog_color(vec3 position) {
+    if (u_fog_use_horizon == 0 || u_env_loaded == 0) {
+        return u_fog_color;
+    }
+    vec3 dir = position - u_view_position;
+    dir = normalize(vec3(dir.x, 0.0, dir.z) + vec3(0.0, 0.0001, 0.0));
+    // 空の細かい模様を拾わないようにぼかしたミップを読む
+    return textureLod(samplerCube(t_environment, s_environment), dir, FOG_HORIZON_LOD).rgb * u_env_intensity;
+}
+
+// 雪に覆われる割合 (0..1)。材質によらず上向きの面ほど多い
+float snow_amount(vec3 normal, vec4 position) {
+    if (u_snow_enabled == 0 || u_snow_coverage <= 0.0) {
+        return 0.0;
+    }
+    // coverage が 1 のとき min_up まで、0 のときはどの面にも積もらない
+    float edge = mix(1.0 + u_snow_blend, u_snow_min_up, u_snow_coverage);
+    float amount = smoothstep(edge - u_snow_blend, edge, normal.y);
+    if (amount <= 0.0) {
+        return 0.0;
+    }
+
+    vec4 sky_position = u_sky_view_proj * position;
+    vec3 sky = sky_position.xyz / sky_position.w;
+    vec2 uv = sky.xy * vec2(0.5, -0.5) + 0.5;
+    // 焼いた範囲の外は遮るものが無いとみなす
+    if (uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0) {
+        return amount;
+    }
+    float open_sky = texture(sampler2DShadow(t_snow_sky, s_shadow), vec3(uv, sky.z - u_snow_depth_bias));
+    return amount * open_sky;
+}
+
+vec3 apply_fog(vec3 color, vec3 position) {
+    if (u_fog_enabled == 0) {
+        return color;
+    }
+    return mix(color, fog_color(position), fog_amount(position));
 }
 
 void main() {
@@ -91,8 +291,15 @@ void main() {
         object_color = vec4(u_diffuse, 1.0);
     }
 
+    // 雪は表面の色を置き換えるので、光の計算より先に混ぜる
+    float snow = snow_amount(normalize(v_normal), v_position);
+    object_color.rgb = mix(object_color.rgb, u_snow_color, snow);
+
     vec3 result = vec3(0.0, 0.0, 0.0);
 
+    vec2 ao_uv = gl_FragCoord.xy / vec2(textureSize(sampler2D(t_ao, s_ao), 0));
+    float ao = texture(sampler2D(t_ao, s_ao), ao_uv).r;
+
     float light_hit = 0.0;
 
     for (int i = 0; i < u_light_num; i++) {
@@ -112,7 +319,7 @@ void main() {
 
         l_radius = max(l_radius, 0.000001);
         vec3 ambient_color = l_color * l_radius / max(l_radius, distance(l_position, v_position.xyz));
-        ambient_color *= in_light;
+        ambient_color *= in_light * ao;
 
         vec3 normal = normalize(v_normal);
         vec3 light_dir = normalize(l_position - v_position.xyz);
@@ -127,10 +334,31 @@ void main() {
         vec3 specular_color = specular_strength * in_light * l_color;
 
         vec3 lig = l_intensity * (ambient_color + diffuse_color + specular_color) * object_color.xyz;
-        result += lig * fetch_shadow(i, shadows[i].shadow_view_proj * v_position);
+        if ((v_flags & INSTANCE_RECEIVES_SHADOW) != 0) {
+            lig *= fetch_shadow(i, shadows[i].shadow_view_proj * v_position, l_radius);
+        }
+        result += lig;
+    }
+
+    if (u_env_loaded != 0) {
+        vec3 normal = normalize(v_normal);
+        vec3 view_dir = normalize(u_view_position - v_position.xyz);
+        float n_dot_v = max(dot(normal, view_dir), 0.0);
+        vec3 irradiance = texture(samplerCube(t_irradiance, s_environment), normal).rgb;
+        vec3 r = reflect(-view_dir, normal);
+        vec3 prefiltered = textureLod(
+            samplerCube(t_prefiltered, s_environment),
+            r,
+            IBL_ROUGHNESS * u_prefiltered_max_lod
+        ).rgb;
+        vec2 brdf = texture(sampler2D(t_brdf_lut, s_environment), vec2(n_dot_v, 1.0 - IBL_ROUGHNESS)).rg;
+        vec3 specular = prefiltered * (IBL_F0 * brdf.x + brdf.y);
+        result += (irradiance * object_color.xyz + specular) * ao * u_env_intensity;
     }
 
     // result.rgb *= max(light_hit, fetch_shadow(shadow_view_proj * v_position));
 
+    result = apply_fog(result, v_position.xyz);
+
     f_color = vec4(result, object_color.a);
 }
\ No newline at end of file
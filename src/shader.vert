@@ -3,10 +3,22 @@
 layout(location = 0) in vec3 a_position;
 layout(location = 1) in vec2 a_tex_coords;
 layout(location = 2) in vec3 a_normal;
+layout(location = 3) in uvec4 a_joints;
+layout(location = 4) in vec4 a_weights;
+// モーフターゲットの差分 (model.rs の MorphVertex)
+layout(location = 5) in vec3 a_morph_position0;
+layout(location = 6) in vec3 a_morph_position1;
+layout(location = 7) in vec3 a_morph_position2;
+layout(location = 8) in vec3 a_morph_position3;
+layout(location = 9) in vec3 a_morph_normal0;
+layout(location = 10) in vec3 a_morph_normal1;
+layout(location = 11) in vec3 a_morph_normal2;
+layout(location = 12) in vec3 a_morph_normal3;
 
 layout(location = 0) out vec2 v_tex_coords;
 layout(location = 1) out vec3 v_normal;
 layout(location = 2) out vec4 v_position;
+layout(location = 3) flat out uint v_flags;
 // layout(location = 3) out vec4 s_gl_position;
 
 layout(set = 1, binding = 0)
@@ -19,6 +31,9 @@ uniform Uniforms {
 struct Instance {
     mat4 transform;
     mat4 transform_norm;
+    uint flags;
+    uint joint_offset;
+    vec4 morph_weights;
 };
 
 layout(set = 2, binding = 0)
@@ -26,16 +41,65 @@ buffer Instances {
     Instance instances[];
 };
 
+layout(set = 2, binding = 1)
+readonly buffer Joints {
+    mat4 joints[];
+};
+
+// スキンを持たない頂点は weights が全て 0 なので単位行列になる
+mat4 skin_matrix() {
+    float total = a_weights.x + a_weights.y + a_weights.z + a_weights.w;
+    if (total <= 0.0) {
+        return mat4(1.0);
+    }
+    uint base = instances[gl_InstanceIndex].joint_offset;
+    return a_weights.x * joints[base + a_joints.x]
+         + a_weights.y * joints[base + a_joints.y]
+         + a_weights.z * joints[base + a_joints.z]
+         + a_weights.w * joints[base + a_joints.w];
+}
+
+// MAX_MORPH_TARGETS 個のターゲットを重みで足し合わせる
+vec3 morph_position() {
+    vec4 w = instances[gl_InstanceIndex].morph_weights;
+    return a_position
+        + w.x * a_morph_position0
+        + w.y * a_morph_position1
+        + w.z * a_morph_position2
+        + w.w * a_morph_position3;
+}
+
+vec3 morph_normal() {
+    vec4 w = instances[gl_InstanceIndex].morph_weights;
+    return a_normal
+        + w.x * a_morph_normal0
+        + w.y * a_morph_normal1
+        + w.z * a_morph_normal2
+        + w.w * a_morph_normal3;
+}
+
+// model.rs の INSTANCE_* と合わせること
+const uint INSTANCE_VISIBLE = 4;
+
 void main() {
     v_tex_coords = a_tex_coords;
+    v_flags = instances[gl_InstanceIndex].flags;
+    if ((v_flags & INSTANCE_VISIBLE) == 0) {
+        // クリップ空間の外に追い出して描かせない
+        gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+        return;
+    }
 
     mat4 instance_matrix = instances[gl_InstanceIndex].transform;
     mat4 insnorm_matrix = instances[gl_InstanceIndex].transform_norm;
     // mat3 normal_matrix = mat3(transpose(inverse(instance_matrix)));
-    mat3 normal_matrix = mat3(insnorm_matrix);
-    v_normal = normal_matrix * a_normal;
+    mat4 skin = skin_matrix();
+    // 関節に拡大縮小があっても法線が面に垂直なままになるよう、インスタンスと同じく逆転置を使う
+    mat3 skin_norm = transpose(inverse(mat3(skin)));
+    mat3 normal_matrix = mat3(insnorm_matrix) * skin_norm;
+    v_normal = normal_matrix * morph_normal();
 
-    vec4 instance_space = instance_matrix * vec4(a_position, 1.0);
+    vec4 instance_space = instance_matrix * skin * vec4(morph_position(), 1.0);
     v_position = instance_space;
 
     gl_Position = u_view_proj * instance_space;
@@ -10,6 +10,39 @@ pub mod light;
 use light::*;
 pub mod shadowmap;
 // use shadowmap::*;
+pub mod debug_line;
+pub mod shadow_debug;
+use shadow_debug::ShadowDebug;
+pub mod gizmo;
+use gizmo::LightGizmo;
+pub mod animation;
+use animation::Animator;
+pub mod skin;
+pub mod gltf_model;
+pub mod bounds;
+use bounds::Aabb;
+pub mod bookmark;
+use bookmark::{CameraBookmarks, CameraPose};
+pub mod input_binding;
+use input_binding::{Action, Input, InputBindings};
+pub mod capture;
+use capture::{OffscreenTarget, ScreenshotFormat, ScreenshotSetting};
+pub mod hdr;
+use hdr::{SceneTarget, HDR_FORMAT};
+pub mod postprocess;
+use postprocess::{PostEffect, PostProcess, PostTargets};
+pub mod ssao;
+use ssao::{Ssao, SsaoTargets, SsaoView};
+pub mod environment;
+use environment::Environment;
+pub mod fog;
+use fog::Fog;
+pub mod snow;
+use snow::{Snow, SnowTarget};
+pub mod snow_cover;
+use snow_cover::SnowCover;
+pub mod sequence;
+use sequence::{CameraPath, SequenceSetting};
 
 #[allow(unused_imports)]
 use cgmath::prelude::*;
@@ -19,7 +52,7 @@ use winit::{
     window::Window,
 };
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -30,16 +63,21 @@ pub struct ShaderState {
     pub queue: wgpu::Queue,
     sc_desc: wgpu::SwapChainDescriptor,
     swap_chain: wgpu::SwapChain,
+    instance_setting: InstanceSetting,
 
-    depth_texture: Texture,
-    // shadow_texture: Texture,
+    // HDR の色と深度、後処理の中間テクスチャ。後処理を通してからスワップチェーンに描く
+    targets: PostTargets,
+    // 法線と深度の前処理パス、AO の結果
+    ssao_targets: SsaoTargets,
+    // 雪が読む targets.scene の深度
+    snow_target: SnowTarget,
+    shadow_texture: Texture,
 
     pub camera_setting: CameraSetting,
 
     pub shadow_uniform_buffer: shadowmap::ShadowUniformBuffer,
     uniform_setting: UniformSetting,
 
-    // instance_setting: InstanceSetting,
     pub model_instance_group_book: ModelInstanceGroupBook,
     pub light_buffer: LightBuffer, 
     // light_instance_setting: InstanceSetting,
@@ -47,8 +85,43 @@ pub struct ShaderState {
 
     // pub shadowmap: ShadowMap,
 
-    render_pipeline: wgpu::RenderPipeline,
-    light_render_pipeline: wgpu::RenderPipeline,
+    scene_pipeline_layout: wgpu::PipelineLayout,
+    // サンプル数ごとのパイプライン。1 は常に持っておく
+    scene_pipelines: HashMap<u32, ScenePipelines>,
+    // MSAA のサンプル数。1 なら MSAA しない
+    sample_count: u32,
+    // 作ろうとして失敗したサンプル数
+    unsupported_sample_counts: HashSet<u32>,
+
+    pub post_process: PostProcess,
+    pub ssao: Ssao,
+    // 背景と環境光に使う HDR 画像
+    pub environment: Environment,
+    // 背景は uniform のバインドグループだけで描く
+    skybox_pipeline_layout: wgpu::PipelineLayout,
+    pub fog: Fog,
+    pub snow: Snow,
+    pub snow_cover: SnowCover,
+    // 自動露出の追従に使う、直前の update の経過時間
+    frame_dt: f32,
+
+    pub shadow_debug: ShadowDebug,
+    pub light_gizmo: LightGizmo,
+
+    pub animator: Animator,
+
+    // frame_selected で対象にするインスタンス名
+    pub selection: Vec<String>,
+
+    pub camera_bookmarks: CameraBookmarks,
+    pub input_bindings: InputBindings,
+    modifiers: ModifiersState,
+
+    // 記録中はフレームごとのカメラ姿勢を camera_path に追加する
+    pub camera_path: CameraPath,
+    pub recording_path: bool,
+    pub sequence_setting: SequenceSetting,
+    pub screenshot_setting: ScreenshotSetting,
 
     pub instance_book: HashMap<String, Rc<RefCell<Instance>>>,
     pub light_book: Vec<Rc<RefCell<Light>>>,
@@ -102,7 +175,9 @@ impl ShaderState {
         let swap_chain = device.create_swap_chain(&surface, &sc_desc);
 
         let texture_setting = texture::TextureSetting::new(&device);
-        let depth_texture = texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
+        let sample_count = Self::DEFAULT_SAMPLE_COUNT;
+        let post_process = PostProcess::new(&device, sc_desc.format);
+        let targets = post_process.create_targets(&device, sc_desc.width, sc_desc.height, sample_count);
 
         let shadow_texture = texture::Texture::create_shadow_texture(&device, &sc_desc);
 
@@ -134,12 +209,23 @@ impl ShaderState {
             &instance_setting.layout,
         );
 
-        let mut lig_vec = lights.iter().collect::<Vec<_>>();
-        lig_vec.sort();
+        if lights.len() > Texture::MAXLIGHTS {
+            bail!("Too many lights: {} (max {})", lights.len(), Texture::MAXLIGHTS);
+        }
+        // id 順にスロット (= シャドウマップのレイヤ) を割り当てる
+        lights.sort();
+        for (slot, light) in lights.iter_mut().enumerate() {
+            light.shadow.set_layer(slot, &shadow_texture.texture);
+        }
+
+        let lig_vec = lights.iter().collect::<Vec<_>>();
         // let light_setting = LightSetting::new(&device, &lig_vec);
         let light_buffer = LightBuffer::new(&device, &lig_vec);
-        let shadow_uniforms = lig_vec.iter().map(|light| light.shadow.shadow_uniform).collect::<Vec<_>>();
-        let mut shadow_uniform_buffer = shadowmap::ShadowUniformBuffer::new(&device, &shadow_uniforms);
+        let mut shadow_uniform_buffer = shadowmap::ShadowUniformBuffer::new(&device, lig_vec.len());
+
+        let environment = Environment::new(&device, &queue);
+        let fog = Fog::new(&device, &queue);
+        let snow_cover = SnowCover::new(&device, &queue, &instance_setting.layout);
 
         let mut uniform_setting = uniform::UniformSetting::new(
             &device,
@@ -148,6 +234,9 @@ impl ShaderState {
             &light_buffer.buffer,
             &shadow_uniform_buffer.buffer,
             &shadow_texture,
+            &environment,
+            &fog,
+            &snow_cover,
             // &shadowmap,
         );
         uniform_setting.uniforms.update_view_proj(
@@ -168,6 +257,9 @@ impl ShaderState {
             );
         }
 
+        for instance in light_instances.iter_mut() {
+            instance.set_light(true);
+        }
         let mut ins_vec = light_instances.iter_mut().collect::<Vec<_>>();
         // 影は不要
         let light_instance_group_book = ModelInstanceGroupBook::new(
@@ -186,11 +278,11 @@ impl ShaderState {
                 (name, Rc::new(RefCell::new(instance)))
             }).collect::<HashMap<_, _>>();
 
-        let mut light_book = lights
+        // light_book の添字はスロットと一致させておく
+        let light_book = lights
             .into_iter()
             .map(|light| Rc::new(RefCell::new(light)))
             .collect::<Vec<_>>();
-        light_book.sort();
 
         // let main_light = light_book[0].borrow();
 
@@ -209,7 +301,20 @@ impl ShaderState {
 
         // drop(main_light);
 
-        let render_pipeline_layout =
+        let ssao = Ssao::new(
+            &device,
+            &queue,
+            &texture_setting.layout,
+            &uniform_setting.layout,
+            &instance_setting.layout,
+        )?;
+        let ssao_targets = ssao.create_targets(&device, sc_desc.width, sc_desc.height);
+
+        let snow = Snow::new(&device, &uniform_setting.layout, camera_setting.camera.position);
+        let snow_target = snow.create_target(&device, &targets.scene);
+
+        // 光源の球も同じバインドグループで描く
+        let scene_pipeline_layout =
             device.create_pipeline_layout(
                 &wgpu::PipelineLayoutDescriptor {
                     label: Some("Render Pipeline Layout"),
@@ -218,39 +323,44 @@ impl ShaderState {
                         &texture_setting.layout,
                         &uniform_setting.layout,
                         &instance_setting.layout,
+                        &ssao.layout,
                     ],
                     push_constant_ranges: &[],
                 }
             );
 
-        let render_pipeline = create_render_pipeline(
-            &device,
-            render_pipeline_layout,
-            &sc_desc,
-            wgpu::include_spirv!("./shader.vert.spv"),
-            wgpu::include_spirv!("./shader.frag.spv"),
-        )?;
-
-        let light_render_pipeline_layout =
+        let skybox_pipeline_layout =
             device.create_pipeline_layout(
                 &wgpu::PipelineLayoutDescriptor {
-                    label: Some("Light Render Pipeline Layout"),
-                    bind_group_layouts: &[
-                        &texture_setting.layout,
-                        &uniform_setting.layout,
-                        &instance_setting.layout,
-                    ],
+                    label: Some("Skybox Pipeline Layout"),
+                    bind_group_layouts: &[&uniform_setting.layout],
                     push_constant_ranges: &[],
                 }
             );
 
-        let light_render_pipeline = create_render_pipeline(
+        let mut scene_pipelines = HashMap::new();
+        for &count in [1, sample_count].iter() {
+            if !scene_pipelines.contains_key(&count) {
+                let pipelines = ScenePipelines::new(
+                    &device,
+                    &scene_pipeline_layout,
+                    &skybox_pipeline_layout,
+                    HDR_FORMAT,
+                    count,
+                )?;
+                scene_pipelines.insert(count, pipelines);
+            }
+        }
+
+        let shadow_debug = ShadowDebug::new(
             &device,
-            light_render_pipeline_layout,
             &sc_desc,
-            wgpu::include_spirv!("./no_shade.vert.spv"),
-            wgpu::include_spirv!("./no_shade.frag.spv"),
-        )?;
+            &shadow_texture,
+            &uniform_setting.layout,
+            HDR_FORMAT,
+            sample_count,
+        );
+        let light_gizmo = LightGizmo::new(&device, &sc_desc, HDR_FORMAT, &uniform_setting.layout, sample_count);
 
         Ok(Self {
             w_size,
@@ -259,24 +369,53 @@ impl ShaderState {
             queue, // command queue
             sc_desc,
             swap_chain,
+            instance_setting,
 
-            depth_texture,
-            // shadow_texture,
+            targets,
+            ssao_targets,
+            snow_target,
+            shadow_texture,
 
             camera_setting,
 
             shadow_uniform_buffer,
             uniform_setting,
 
-            // instance_setting,
             model_instance_group_book,
             light_buffer,
             light_instance_group_book,
 
             // shadowmap,
 
-            render_pipeline,
-            light_render_pipeline,
+            scene_pipeline_layout,
+            scene_pipelines,
+            sample_count,
+            unsupported_sample_counts: HashSet::new(),
+
+            post_process,
+            ssao,
+            environment,
+            skybox_pipeline_layout,
+            fog,
+            snow,
+            snow_cover,
+            frame_dt: 0.0,
+
+            shadow_debug,
+            light_gizmo,
+
+            animator: Animator::new(),
+
+            selection: Vec::new(),
+
+            camera_bookmarks: CameraBookmarks::new(),
+            input_bindings: InputBindings::new(),
+            modifiers: ModifiersState::empty(),
+
+            camera_path: CameraPath::new(),
+            recording_path: false,
+            sequence_setting: SequenceSetting::default(),
+            screenshot_setting: ScreenshotSetting::default(),
 
             instance_book,
             light_book,
@@ -290,19 +429,150 @@ impl ShaderState {
         self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
 
         self.camera_setting.projection.resize(new_size.width, new_size.height);
+        self.light_gizmo.resize(&self.queue, &self.sc_desc);
 
-        self.depth_texture = texture::Texture::create_depth_texture(
-            &self.device,
-            &self.sc_desc,
-            "depth_texture"
-        );
+        self.recreate_targets();
 
         // 簡単のため、影については画面サイズ変更の影響を受けないものとする。
         // self.shadow_texture = texture::Texture::create_shadow_texture(&self.device, &self.sc_desc);
     }
 
+    const DEFAULT_SAMPLE_COUNT: u32 = 4;
+
+    // 画面の大きさかサンプル数が変わったら作り直す
+    fn recreate_targets(&mut self) {
+        self.targets = self.post_process.create_targets(
+            &self.device,
+            self.sc_desc.width,
+            self.sc_desc.height,
+            self.sample_count,
+        );
+        self.ssao_targets = self.ssao.create_targets(&self.device, self.sc_desc.width, self.sc_desc.height);
+        self.snow_target = self.snow.create_target(&self.device, &self.targets.scene);
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    const SAMPLE_COUNTS: [u32; 4] = [1, 2, 4, 8];
+    // 露出のキー1回で変える EV
+    const EXPOSURE_STEP: f32 = 0.5;
+
+    // 1, 2, 4, 8 のどれか。wgpu 0.6 にはアダプタが扱えるサンプル数を問い合わせる API が無いので、
+    // パイプラインとターゲットを作ってみて、失敗 (検証エラーの panic) したら使えないものとして覚えておく
+    pub fn set_sample_count(&mut self, sample_count: u32) -> Result<()> {
+        if !Self::SAMPLE_COUNTS.contains(&sample_count) {
+            bail!("Unsupported MSAA sample count: {}", sample_count);
+        }
+        if self.unsupported_sample_counts.contains(&sample_count) {
+            bail!("MSAA sample count {} is not supported by this adapter", sample_count);
+        }
+        let (width, height) = (self.sc_desc.width, self.sc_desc.height);
+        let created = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| -> Result<_> {
+            let pipelines = if self.scene_pipelines.contains_key(&sample_count) {
+                None
+            } else {
+                Some(ScenePipelines::new(
+                    &self.device,
+                    &self.scene_pipeline_layout,
+                    &self.skybox_pipeline_layout,
+                    HDR_FORMAT,
+                    sample_count,
+                )?)
+            };
+            let targets = self.post_process.create_targets(&self.device, width, height, sample_count);
+            Ok((pipelines, targets))
+        }));
+        let (pipelines, targets) = match created {
+            Ok(Ok(created)) => created,
+            Ok(Err(e)) => {
+                self.unsupported_sample_counts.insert(sample_count);
+                return Err(e).with_context(|| format!("MSAA sample count {} is not supported", sample_count));
+            }
+            Err(_) => {
+                self.unsupported_sample_counts.insert(sample_count);
+                bail!("MSAA sample count {} is not supported by this adapter", sample_count);
+            }
+        };
+        if let Some(pipelines) = pipelines {
+            self.scene_pipelines.insert(sample_count, pipelines);
+        }
+        self.sample_count = sample_count;
+        self.targets = targets;
+        self.ssao_targets = self.ssao.create_targets(&self.device, width, height);
+        self.snow_target = self.snow.create_target(&self.device, &self.targets.scene);
+        self.shadow_debug.set_sample_count(&self.device, HDR_FORMAT, &self.uniform_setting.layout, sample_count);
+        self.light_gizmo.set_sample_count(&self.device, HDR_FORMAT, &self.uniform_setting.layout, sample_count);
+        Ok(())
+    }
+
+    // 1 → 2 → 4 → 8 → 1 と回す。アダプタが使えないサンプル数は飛ばす
+    pub fn cycle_sample_count(&mut self) -> Result<()> {
+        let counts = &Self::SAMPLE_COUNTS;
+        let current = counts.iter().position(|&c| c == self.sample_count).unwrap_or(0);
+        for i in 1..counts.len() {
+            let next = counts[(current + i) % counts.len()];
+            match self.set_sample_count(next) {
+                Ok(()) => return Ok(()),
+                Err(e) => log::warn!("Skipping MSAA x{}: {:?}", next, e),
+            }
+        }
+        bail!("No other MSAA sample count is supported")
+    }
+
+    fn toggle_post_effect(&mut self, effect: PostEffect) -> bool {
+        self.post_process.toggle(effect);
+        true
+    }
+
+    // 後処理の設定ファイルを読む。書式は PostProcess::load を参照
+    pub fn load_post_process<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.post_process.load(&self.device, &self.queue, path)
+    }
+
+    // 正距円筒図法の .hdr を背景と環境光に使う
+    pub fn load_environment<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.environment.load(&self.device, &self.queue, path)?;
+        self.uniform_setting.rebind(
+            &self.device,
+            &self.light_buffer.buffer,
+            &self.shadow_uniform_buffer.buffer,
+            &self.shadow_texture,
+            &self.environment,
+            &self.fog,
+            &self.snow_cover,
+        );
+        Ok(())
+    }
+
+    // 積もる雪の設定ファイルを読む。書式は SnowCover::load を参照
+    pub fn load_snow_cover<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.snow_cover.load(&self.queue, path)
+    }
+
+    // 雪の設定ファイルを読む。書式は Snow::load を参照
+    pub fn load_snow<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.snow.load(&self.device, path, self.camera_setting.camera.position)
+    }
+
+    // 霧の設定ファイルを読む。書式は Fog::load を参照
+    pub fn load_fog<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.fog.load(&self.queue, path)
+    }
+
+    // 背景と環境光の明るさ
+    pub fn set_environment_intensity(&mut self, intensity: f32) {
+        self.environment.intensity = intensity.max(0.0);
+        self.environment.write_uniform(&self.queue);
+    }
+
     pub fn input(&mut self, event: &WindowEvent) -> bool {
         match event {
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+                false
+            }
             WindowEvent::KeyboardInput {
                 input: KeyboardInput {
                     virtual_keycode: Some(key),
@@ -310,22 +580,25 @@ impl ShaderState {
                     ..
                 },
                 ..
-            } => self.camera_setting.camera_controller.process_keyboard(*key, *state),
+            } => self.process_input(Input::Key(*key), *state == ElementState::Pressed),
             WindowEvent::MouseWheel {
                 delta,
                 ..
             } => {
-                self.camera_setting.camera_controller.process_scroll(delta);
-                true
+                let actions = self.input_bindings.actions(Input::Wheel, self.modifiers);
+                if actions.contains(&Action::Zoom) {
+                    self.camera_setting.camera_controller.process_scroll(delta);
+                }
+                if actions.contains(&Action::AdjustSpeed) {
+                    self.camera_setting.camera_controller.process_speed_scroll(delta);
+                }
+                !actions.is_empty()
             }
             WindowEvent::MouseInput {
-                button: MouseButton::Left,
+                button,
                 state,
                 ..
-            } => {
-                self.camera_setting.mouse_pressed = *state == ElementState::Pressed;
-                true
-            }
+            } => self.process_input(Input::Mouse(*button), *state == ElementState::Pressed),
             WindowEvent::CursorMoved {
                 position,
                 ..
@@ -335,37 +608,736 @@ impl ShaderState {
                 let mouse_dy = position.y - y;
                 self.camera_setting.last_mouse_pos = *position;
                 if self.camera_setting.mouse_pressed {
+                    self.camera_setting.drag_distance += mouse_dx.abs() + mouse_dy.abs();
                     self.camera_setting.camera_controller
                         .process_mouse(mouse_dx, mouse_dy);
                 }
+                if self.camera_setting.pan_pressed {
+                    self.camera_setting.camera_controller
+                        .process_pan(mouse_dx, mouse_dy);
+                }
                 true
             }
             _ => false,
         }
     }
 
+    // キーやボタンを input_bindings で action に直して処理する
+    fn process_input(&mut self, input: Input, pressed: bool) -> bool {
+        let actions = if pressed {
+            self.input_bindings.actions(input, self.modifiers)
+        } else {
+            self.input_bindings.released_actions(input)
+        };
+        let mut handled = false;
+        for action in actions {
+            handled |= self.process_action(action, pressed);
+        }
+        handled
+    }
+
+    fn process_action(&mut self, action: Action, pressed: bool) -> bool {
+        if self.camera_setting.camera_controller.process_action(action, pressed) {
+            return true;
+        }
+        match action {
+            Action::Look => {
+                if pressed {
+                    self.camera_setting.drag_distance = 0.0;
+                } else if self.camera_setting.mouse_pressed
+                    && self.camera_setting.drag_distance < 3.0
+                    && self.camera_setting.camera_controller.mode == CameraMode::Orbit
+                {
+                    // ドラッグせずにクリックした面を回転中心にする
+                    let pos = self.camera_setting.last_mouse_pos;
+                    if let Ok(Some(pivot)) = self.pick_position(pos.x, pos.y) {
+                        let camera_setting = &mut self.camera_setting;
+                        camera_setting.camera_controller.set_pivot(pivot, &mut camera_setting.camera);
+                    }
+                }
+                self.camera_setting.mouse_pressed = pressed;
+                true
+            }
+            Action::Pan => {
+                self.camera_setting.pan_pressed = pressed;
+                true
+            }
+            // ここから下は押したときだけ
+            _ if !pressed => false,
+            Action::ToggleCameraMode => {
+                let camera_setting = &mut self.camera_setting;
+                camera_setting.camera_controller.toggle_mode(&camera_setting.camera);
+                true
+            }
+            Action::ToggleProjection => {
+                let camera_setting = &mut self.camera_setting;
+                camera_setting.camera_controller.toggle_projection(
+                    &mut camera_setting.camera,
+                    &mut camera_setting.projection,
+                );
+                true
+            }
+            Action::FrameAll => {
+                self.frame_all();
+                true
+            }
+            Action::FrameSelected => {
+                self.frame_selected();
+                true
+            }
+            Action::ViewFront => self.set_axis_view(AxisView::Front),
+            Action::ViewBack => self.set_axis_view(AxisView::Back),
+            Action::ViewLeft => self.set_axis_view(AxisView::Left),
+            Action::ViewRight => self.set_axis_view(AxisView::Right),
+            Action::ViewTop => self.set_axis_view(AxisView::Top),
+            Action::ViewBottom => self.set_axis_view(AxisView::Bottom),
+            Action::ToggleBloom => self.toggle_post_effect(PostEffect::Bloom),
+            Action::ToggleFxaa => self.toggle_post_effect(PostEffect::Fxaa),
+            Action::ToggleVignette => self.toggle_post_effect(PostEffect::Vignette),
+            Action::ToggleColorGrading => self.toggle_post_effect(PostEffect::ColorGrading),
+            Action::ToggleSsao => {
+                self.ssao.enabled = !self.ssao.enabled;
+                true
+            }
+            Action::ToggleSkybox => {
+                self.environment.show_skybox = !self.environment.show_skybox;
+                true
+            }
+            Action::ToggleSnow => {
+                self.snow.enabled = !self.snow.enabled;
+                true
+            }
+            Action::ToggleFog => {
+                self.fog.enabled = !self.fog.enabled;
+                self.fog.write_uniform(&self.queue);
+                true
+            }
+            Action::GoToBookmark(slot) => {
+                // 未登録の番号は何もしない
+                self.go_to_bookmark(&slot.to_string()).ok();
+                true
+            }
+            Action::SaveBookmark(slot) => {
+                self.bookmark_camera(&slot.to_string());
+                true
+            }
+            Action::ToggleShadowOverlay => {
+                self.shadow_debug.show_overlay = !self.shadow_debug.show_overlay;
+                true
+            }
+            Action::NextShadowLight => {
+                self.shadow_debug.next_light(self.light_book.len());
+                true
+            }
+            Action::ToggleShadowFrustums => {
+                self.shadow_debug.show_frustums = !self.shadow_debug.show_frustums;
+                true
+            }
+            Action::ToggleLightGizmo => {
+                self.light_gizmo.enabled = !self.light_gizmo.enabled;
+                true
+            }
+            Action::ToggleAutoExposure => {
+                self.post_process.tonemapper.toggle_auto_exposure();
+                true
+            }
+            Action::ExposureUp => {
+                self.post_process.tonemapper.adjust_exposure(Self::EXPOSURE_STEP);
+                true
+            }
+            Action::ExposureDown => {
+                self.post_process.tonemapper.adjust_exposure(-Self::EXPOSURE_STEP);
+                true
+            }
+            Action::CycleTonemap => {
+                let tonemapper = &mut self.post_process.tonemapper;
+                tonemapper.tonemap = tonemapper.tonemap.next();
+                true
+            }
+            Action::CycleMsaa => {
+                if let Err(e) = self.cycle_sample_count() {
+                    log::warn!("Cannot change the sample count: {:?}", e);
+                }
+                true
+            }
+            // 書き出しの失敗でビューアは止めない
+            Action::Screenshot => {
+                match self.save_screenshot() {
+                    Ok(path) => log::info!("Screenshot written to {:?}", path),
+                    Err(e) => log::error!("Cannot save the screenshot: {:?}", e),
+                }
+                true
+            }
+            Action::RenderTurntable => {
+                match self.render_turntable() {
+                    Ok(dir) => log::info!("Turntable written to {:?}", dir),
+                    Err(e) => log::error!("Cannot render the turntable: {:?}", e),
+                }
+                true
+            }
+            Action::ToggleCameraPathRecording => {
+                self.toggle_path_recording();
+                true
+            }
+            Action::RenderCameraPath => {
+                match self.render_camera_path() {
+                    Ok(dir) => log::info!("Camera path written to {:?}", dir),
+                    Err(e) => log::error!("Cannot render the camera path: {:?}", e),
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // 画面上の点 (物理ピクセル) に映っている面のワールド座標。何も無ければ None
+    pub fn pick_position(&self, x: f64, y: f64) -> Result<Option<cgmath::Point3<f32>>> {
+        let (width, height) = (self.sc_desc.width, self.sc_desc.height);
+        if x < 0.0 || y < 0.0 || x >= width as f64 || y >= height as f64 {
+            return Ok(None);
+        }
+
+        // マルチサンプルの深度はコピーできないので、1サンプルで描き直して読む
+        let pick_target;
+        let depth_texture = if self.sample_count > 1 {
+            pick_target = SceneTarget::new(&self.device, width, height, 1);
+            let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Pick Render Encoder"),
+            });
+            self.draw_scene(&mut encoder, &pick_target, None, None, Self::CLEAR_COLOR, false);
+            self.queue.submit(std::iter::once(encoder.finish()));
+            &pick_target.depth.texture
+        } else {
+            &self.targets.scene.depth.texture
+        };
+
+        // 1テクセルだけ読み出す。bytes_per_row は 256 の倍数でないといけない
+        let buffer = self.device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Pick Depth Buffer"),
+                size: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+                mapped_at_creation: false,
+            }
+        );
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Pick Depth Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: depth_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x: x as u32, y: y as u32, z: 0 },
+            },
+            wgpu::BufferCopyView {
+                buffer: &buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT,
+                    rows_per_image: 1,
+                },
+            },
+            wgpu::Extent3d { width: 1, height: 1, depth: 1 },
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapping)?;
+        let depth = {
+            let data = slice.get_mapped_range();
+            bytemuck::cast_slice::<u8, f32>(&data[..4])[0]
+        };
+        buffer.unmap();
+
+        // 深度がクリア値のままなら何も描かれていない
+        if depth >= 1.0 {
+            return Ok(None);
+        }
+
+        let ndc = cgmath::Vector4::new(
+            (2.0 * x / width as f64 - 1.0) as f32,
+            (1.0 - 2.0 * y / height as f64) as f32,
+            depth,
+            1.0,
+        );
+        let view_proj = self.camera_setting.projection.calc_matrix()
+            * self.camera_setting.camera.calc_matrix();
+        let inv = view_proj.invert().context("view_proj is not invertible")?;
+        let p = inv * ndc;
+        Ok(Some(cgmath::Point3::from_vec(p.truncate() / p.w)))
+    }
+
+    pub fn camera_pose(&self) -> CameraPose {
+        CameraPose::capture(&self.camera_setting.camera, &self.camera_setting.projection)
+    }
+
+    pub fn bookmark_camera(&mut self, name: &str) {
+        let pose = self.camera_pose();
+        self.camera_bookmarks.set(name, pose);
+    }
+
+    pub fn go_to_bookmark(&mut self, name: &str) -> Result<()> {
+        let pose = self.camera_pose();
+        self.camera_bookmarks.go_to(name, pose)
+    }
+
+    // ブックマークを読み込み、前回終了時の位置があれば戻す
+    pub fn load_camera_bookmarks<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.camera_bookmarks = CameraBookmarks::load(path)?;
+        if let Some(pose) = self.camera_bookmarks.get(CameraBookmarks::LAST).copied() {
+            self.apply_camera_pose(&pose);
+        }
+        Ok(())
+    }
+
+    // 終了時に呼ぶ。今の位置を LAST として一緒に保存する
+    pub fn save_camera_bookmarks(&mut self) -> Result<()> {
+        self.bookmark_camera(CameraBookmarks::LAST);
+        self.camera_bookmarks.save()
+    }
+
+    fn apply_camera_pose(&mut self, pose: &CameraPose) {
+        let camera_setting = &mut self.camera_setting;
+        let distance = camera_setting.camera_controller.pivot_distance(&camera_setting.camera);
+        pose.apply(&mut camera_setting.camera, &mut camera_setting.projection);
+        // Orbit モードで飛ばされないように pivot も付いてこさせる
+        camera_setting.camera_controller.pivot =
+            camera_setting.camera.position + camera_setting.camera.direction() * distance;
+    }
+
+    // アニメーションファイルを読み込む。存在しないインスタンスや光源を動かすトラックがあれば読み込まない
+    pub fn load_animation<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        use animation::AnimationTarget;
+
+        let path = path.as_ref();
+        let clips = animation::load_clips(path)?;
+        for clip in clips.iter() {
+            for (target, _) in clip.tracks.iter() {
+                match target {
+                    AnimationTarget::Instance(name) if !self.instance_book.contains_key(name) => {
+                        bail!("{:?}: clip {}: No such instance: {}", path, clip.name, name)
+                    }
+                    AnimationTarget::Light(id) => {
+                        self.light_slot(*id)
+                            .with_context(|| format!("{:?}: clip {}", path, clip.name))?;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        for clip in clips {
+            self.animator.add_clip(clip);
+        }
+        Ok(())
+    }
+
+    // アニメーションの現在値を各対象に書き込む。
+    // 対象が見つからないトラックは (後から消された場合など) 警告を出して外す
+    fn animate(&mut self, dt: std::time::Duration) {
+        use animation::{AnimationTarget, Sample};
+
+        let mut missing = Vec::new();
+        for (target, sample) in self.animator.advance(dt.as_secs_f32()) {
+            let result = match &target {
+                AnimationTarget::Instance(name) => self.modify_instance(name, |ins| match sample {
+                    Sample::Position(p) => ins.position = p,
+                    Sample::Rotation(r) => ins.rotation = r,
+                    Sample::Scale(s) => ins.scale = s,
+                    Sample::MorphWeight(i, w) => {
+                        if let Some(weight) = ins.morph_weights.get_mut(i) {
+                            *weight = w;
+                        }
+                    }
+                    _ => (),
+                }),
+                AnimationTarget::Light(id) => match sample {
+                    Sample::Position(p) => self.set_light_position(*id, p),
+                    Sample::LightColor(c) => self.set_light_color(*id, c),
+                    Sample::LightIntensity(i) => self.set_light_intensity(*id, i),
+                    _ => Ok(()),
+                },
+                AnimationTarget::Camera => {
+                    let camera = &mut self.camera_setting.camera;
+                    match sample {
+                        Sample::Position(p) => camera.position = cgmath::Point3::from_vec(p),
+                        Sample::CameraYaw(yaw) => camera.set_yaw(yaw),
+                        Sample::CameraPitch(pitch) => camera.set_pitch(pitch),
+                        _ => (),
+                    }
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                log::warn!("Animation track for {:?} removed: {:?}", target, e);
+                missing.push(target);
+            }
+        }
+        for target in missing.iter() {
+            self.animator.remove_target(target);
+        }
+    }
+
+    // スケルトンを持つインスタンスのジョイント行列を更新する
+    fn skin(&mut self, dt: std::time::Duration) -> Result<()> {
+        for r_instance in self.instance_book.values() {
+            let mut instance = r_instance.borrow_mut();
+            // 光源の球は model_instance_group_book に無いので骨を持たせない
+            if instance.is_light() || instance.skin.layers.is_empty() {
+                continue;
+            }
+            let model = instance.model().clone();
+            let skeleton = match model.skeleton.as_ref() {
+                Some(s) => s,
+                None => continue,
+            };
+            instance.skin.advance(dt.as_secs_f32());
+            let palette = skeleton.compute_palette(&instance.skin.layers);
+            self.model_instance_group_book.update_joints(&self.queue, &instance, &palette)?;
+        }
+
+        Ok(())
+    }
+
     pub fn update<F>(&mut self, dt: std::time::Duration, f: F) -> Result<()>
     where
         F: Fn(&mut Self) -> Result<()>
     {
-        self.camera_setting.camera_controller
-            .update_camera(&mut self.camera_setting.camera, dt);
+        self.frame_dt = dt.as_secs_f32();
+        self.animate(dt);
+        self.skin(dt)?;
+
+        // ブックマークへ移動中はコントローラの入力を無視する
+        if let Some(pose) = self.camera_bookmarks.advance(dt.as_secs_f32()) {
+            self.apply_camera_pose(&pose);
+        } else {
+            let camera_setting = &mut self.camera_setting;
+            camera_setting.camera_controller
+                .update_camera(&mut camera_setting.camera, &mut camera_setting.projection, dt);
+        }
+        if self.recording_path {
+            let pose = self.camera_pose();
+            self.camera_path.record(dt.as_secs_f32(), pose);
+        }
+        self.write_view_proj();
+        self.snow.update(&self.queue, dt.as_secs_f32(), self.camera_setting.camera.position);
+        self.snow_cover.update(&self.queue, dt.as_secs_f32(), self.camera_setting.camera.position);
+
+        f(self)?;
+
+        self.shadow_debug.update(&self.device, &self.queue, &self.light_book);
+        self.light_gizmo.update(&self.device, &self.queue, &self.light_book);
+
+        Ok(())
+    }
+
+    fn write_view_proj(&mut self) {
+        self.write_view_proj_cropped(cgmath::Matrix4::identity());
+    }
+
+    fn write_view_proj_cropped(&mut self, crop: cgmath::Matrix4<f32>) {
         self.uniform_setting.uniforms
-            .update_view_proj(
+            .update_view_proj_cropped(
                 &self.camera_setting.camera,
                 &self.camera_setting.projection,
+                crop,
             );
         self.queue.write_buffer(
             &self.uniform_setting.buffer,
             0,
             bytemuck::cast_slice(&[self.uniform_setting.uniforms])
         );
+    }
 
-        f(self)?;
+    // prepare_objects の外で光源を作るときに使う
+    pub fn create_shadow_map(
+        &self,
+        position: cgmath::Point3<f32>,
+        init_vec: cgmath::Vector3<f32>,
+        darkness: f32,
+        dir_update_way: shadowmap::DirUpdateWay,
+        projection: Projection,
+        filter: shadowmap::ShadowFilter,
+        bias: shadowmap::ShadowBias,
+    ) -> shadowmap::ShadowMap {
+        shadowmap::ShadowMap::new(
+            position,
+            init_vec,
+            darkness,
+            dir_update_way,
+            projection,
+            filter,
+            bias,
+            &self.device,
+            &self.queue,
+            &self.sc_desc,
+            &self.instance_setting.layout,
+            &self.shadow_texture.texture,
+        )
+    }
+
+    fn light_slot(&self, id: usize) -> Result<usize> {
+        self.light_book
+            .iter()
+            .position(|light| light.borrow().id == id)
+            .with_context(|| format!("No such light: {}", id))
+    }
+
+    pub fn light(&self, id: usize) -> Result<Rc<RefCell<Light>>> {
+        let slot = self.light_slot(id)?;
+        Ok(self.light_book[slot].clone())
+    }
+
+    // バッファを作り直した場合は全光源を書き直して bind group も作り直す
+    fn reserve_light_slots(&mut self, len: usize) {
+        let light_grown = self.light_buffer.reserve(&self.device, len);
+        let shadow_grown = self.shadow_uniform_buffer.reserve(&self.device, len);
+        if !light_grown && !shadow_grown {
+            return;
+        }
+
+        for r_light in self.light_book.iter() {
+            let light = r_light.borrow();
+            self.light_buffer.update_light(&self.queue, &light);
+            self.shadow_uniform_buffer.update_uniform(&self.queue, &light.shadow);
+        }
+        self.uniform_setting.rebind(
+            &self.device,
+            &self.light_buffer.buffer,
+            &self.shadow_uniform_buffer.buffer,
+            &self.shadow_texture,
+            &self.environment,
+            &self.fog,
+            &self.snow_cover,
+        );
+    }
+
+    pub fn add_light(&mut self, mut light: Light) -> Result<Rc<RefCell<Light>>> {
+        if self.light_slot(light.id).is_ok() {
+            bail!("Light id {} is already used", light.id);
+        }
+        let slot = self.light_book.len();
+        if slot >= Texture::MAXLIGHTS {
+            bail!("Too many lights (max {})", Texture::MAXLIGHTS);
+        }
+
+        self.reserve_light_slots(slot + 1);
+
+        light.shadow.set_layer(slot, &self.shadow_texture.texture);
+        let pos = light.shadow.position.to_vec();
+        let dir = light.shadow.direction;
+        light.shadow.update(
+            Some(pos),
+            Some(dir),
+            &self.queue,
+            &mut self.shadow_uniform_buffer,
+        );
+        self.light_buffer.update_light(&self.queue, &light);
+
+        let r_light = Rc::new(RefCell::new(light));
+        self.light_book.push(r_light.clone());
+        self.uniform_setting.set_light_num(&self.queue, self.light_book.len() as u32);
+        // 新しい光源の影を焼くため
+        self.model_instance_group_book.set_shadow_dirty(true);
+
+        Ok(r_light)
+    }
+
+    // 末尾の光源を空いたスロットに移して詰める
+    pub fn remove_light(&mut self, id: usize) -> Result<Rc<RefCell<Light>>> {
+        let slot = self.light_slot(id)?;
+        let removed = self.light_book.swap_remove(slot);
+
+        if let Some(r_moved) = self.light_book.get(slot) {
+            let mut moved = r_moved.borrow_mut();
+            moved.shadow.set_layer(slot, &self.shadow_texture.texture);
+            self.light_buffer.update_light(&self.queue, &moved);
+            self.shadow_uniform_buffer.update_uniform(&self.queue, &moved.shadow);
+        }
+        self.uniform_setting.set_light_num(&self.queue, self.light_book.len() as u32);
+
+        Ok(removed)
+    }
+
+    fn modify_light<F>(&mut self, id: usize, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Light),
+    {
+        let slot = self.light_slot(id)?;
+        let mut light = self.light_book[slot].borrow_mut();
+        f(&mut light);
+
+        let pos = light.position;
+        light.shadow.update(
+            Some(pos),
+            None,
+            &self.queue,
+            &mut self.shadow_uniform_buffer,
+        );
+        self.light_buffer.update_light(&self.queue, &light);
+
+        Ok(())
+    }
+
+    pub fn set_light_position(&mut self, id: usize, position: cgmath::Vector3<f32>) -> Result<()> {
+        self.modify_light(id, |light| light.position = position)
+    }
+
+    pub fn set_light_color(&mut self, id: usize, color: cgmath::Vector3<f32>) -> Result<()> {
+        self.modify_light(id, |light| light.color = color)
+    }
+
+    pub fn set_light_intensity(&mut self, id: usize, intensity: f32) -> Result<()> {
+        self.modify_light(id, |light| light.intensity = intensity)
+    }
+
+    pub fn set_light_radius(&mut self, id: usize, radius: f32) -> Result<()> {
+        self.modify_light(id, |light| light.radius = radius)
+    }
+
+    // スポットライトの向きは影の向きにも反映する
+    pub fn set_light_spot(
+        &mut self,
+        id: usize,
+        limitcos_inner: f32,
+        limitcos_outer: f32,
+        limitdir: cgmath::Vector3<f32>,
+    ) -> Result<()> {
+        self.modify_light(id, |light| {
+            light.is_spotlight = true;
+            light.limitcos_inner = limitcos_inner;
+            light.limitcos_outer = limitcos_outer;
+            light.limitdir = limitdir;
+        })?;
+
+        let slot = self.light_slot(id)?;
+        let mut light = self.light_book[slot].borrow_mut();
+        light.shadow.update(
+            None,
+            Some(limitdir),
+            &self.queue,
+            &mut self.shadow_uniform_buffer,
+        );
 
         Ok(())
     }
 
+    // インスタンスの変更をそれが属する方の InstanceGroupBook に反映する
+    pub fn update_instance(&self, instance: &Instance) -> Result<()> {
+        if instance.is_light() {
+            self.light_instance_group_book.update_instance(&self.queue, instance)
+        } else {
+            self.model_instance_group_book.update_instance(&self.queue, instance)
+        }
+    }
+
+    fn modify_instance<F>(&mut self, name: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Instance),
+    {
+        let r_instance = self.instance_book
+            .get(name)
+            .with_context(|| format!("No such instance: {}", name))?
+            .clone();
+        let mut instance = r_instance.borrow_mut();
+        f(&mut instance);
+        self.update_instance(&instance)
+    }
+
+    pub fn set_instance_visible(&mut self, name: &str, visible: bool) -> Result<()> {
+        self.modify_instance(name, |ins| ins.visible = visible)
+    }
+
+    pub fn set_instance_casts_shadow(&mut self, name: &str, casts_shadow: bool) -> Result<()> {
+        self.modify_instance(name, |ins| ins.casts_shadow = casts_shadow)
+    }
+
+    pub fn set_instance_receives_shadow(&mut self, name: &str, receives_shadow: bool) -> Result<()> {
+        self.modify_instance(name, |ins| ins.receives_shadow = receives_shadow)
+    }
+
+    pub fn set_instance_morph_weight(&mut self, name: &str, target: usize, weight: f32) -> Result<()> {
+        if target >= MAX_MORPH_TARGETS {
+            bail!("Morph target {} is out of range (max {})", target, MAX_MORPH_TARGETS);
+        }
+        self.modify_instance(name, |ins| ins.morph_weights[target] = weight)
+    }
+
+    fn set_axis_view(&mut self, view: AxisView) -> bool {
+        let camera_setting = &mut self.camera_setting;
+        camera_setting.camera_controller.set_axis_view(view, &mut camera_setting.camera);
+        true
+    }
+
+    // 見えているモデルのインスタンス全体の境界。光源の電球などは含めない
+    pub fn scene_aabb(&self) -> Aabb {
+        self.instance_book
+            .values()
+            .map(|r| r.borrow())
+            .filter(|ins| ins.visible && self.model_instance_group_book.contains(ins))
+            .fold(Aabb::empty(), |acc, ins| acc.union(&ins.world_aabb()))
+    }
+
+    pub fn frame_all(&mut self) {
+        let aabb = self.scene_aabb();
+        self.frame_aabb(&aabb);
+    }
+
+    // 選択が空のときは全体を写す
+    pub fn frame_selected(&mut self) {
+        let aabb = self.selection
+            .iter()
+            .filter_map(|name| self.instance_book.get(name))
+            .fold(Aabb::empty(), |acc, r| acc.union(&r.borrow().world_aabb()));
+        if aabb.is_empty() {
+            self.frame_all();
+        } else {
+            self.frame_aabb(&aabb);
+        }
+    }
+
+    // 今の向きのまま、aabb の外接球が画面に収まる位置までカメラを引く
+    pub fn frame_aabb(&mut self, aabb: &Aabb) {
+        if aabb.is_empty() {
+            return;
+        }
+        let sphere = aabb.bounding_sphere();
+        let radius = sphere.radius.max(0.01);
+
+        let camera_setting = &mut self.camera_setting;
+        let half_fov = camera_setting.projection.min_fov().0 * 0.5;
+        let distance = radius / half_fov.sin();
+        let dir = camera_setting.camera.direction();
+        camera_setting.camera.position = sphere.center - dir * distance;
+        camera_setting.camera_controller.pivot = sphere.center;
+        // 平行投影では距離ではなく映す幅で合わせる
+        let aspect = camera_setting.projection.aspect();
+        camera_setting.projection.set_ortho_height(2.0 * radius * (1.0 / aspect).max(1.0));
+
+        // 奥はシーン全体が入るようにする
+        let scene = self.scene_aabb().union(aabb).bounding_sphere();
+        let far = (scene.center - self.camera_setting.camera.position).magnitude() + scene.radius;
+        let znear = ((distance - radius) * 0.5).max(distance * 0.001);
+        let zfar = (far * 1.1).max(distance + radius);
+        self.camera_setting.projection.set_clip(znear, zfar);
+    }
+
+    // 全ての影を次のフレームで焼き直す
+    pub fn invalidate_shadows(&mut self) {
+        for r_light in self.light_book.iter() {
+            r_light.borrow_mut().shadow.invalidate();
+        }
+        self.model_instance_group_book.set_shadow_dirty(true);
+    }
+
+    const CLEAR_COLOR: wgpu::Color = wgpu::Color {
+        r: 0.1,
+        g: 0.1,
+        b: 0.1,
+        a: 1.0,
+    };
+
     pub fn render(&mut self) {
         let frame = self.swap_chain.get_current_frame()
             .expect("Timeout getting texture")
@@ -377,13 +1349,47 @@ impl ShaderState {
             }
         );
 
+        self.render_shadows(&mut encoder);
+        self.snow.simulate(&mut encoder);
+        self.draw_scene(
+            &mut encoder,
+            &self.targets.scene,
+            Some(&self.ssao_targets),
+            Some(&self.snow_target),
+            Self::CLEAR_COLOR,
+            true,
+        );
+        self.post_process.render(
+            &mut encoder,
+            &self.queue,
+            &self.targets,
+            &frame.view,
+            Some(self.frame_dt),
+            [0.0, 0.0, 1.0, 1.0],
+        );
+
+        self.shadow_debug.render_overlay(&mut encoder, &frame.view, &self.sc_desc);
+
+        // Submit Command. and its result will appear on frame.
+        // submit will accept anything that implements IntoIter
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    fn render_shadows(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        // 光源もインスタンスも動いていなければ前回の影をそのまま使う
+        let instances_moved = self.model_instance_group_book.is_shadow_dirty();
         for r_light in self.light_book.iter() {
-            let light = r_light.borrow_mut();
+            let mut light = r_light.borrow_mut();
+            if !instances_moved && !light.shadow.is_dirty() {
+                continue;
+            }
             light.shadow.render_to_texture(
-                &mut encoder,
+                encoder,
                 &self.model_instance_group_book,
             );
         }
+        self.snow_cover.render_sky(encoder, &self.model_instance_group_book, instances_moved);
+        self.model_instance_group_book.set_shadow_dirty(false);
 
         /*
         self.shadowmap.render_to_texture(
@@ -391,6 +1397,42 @@ impl ShaderState {
             &self.instance_setting,
         );
         */
+    }
+
+    // overlays が false ならデバッグ用の線やギズモは描かない。
+    // それらのパイプラインは今の sample_count 用しか無いので、違うサンプル数でも描かない。
+    // ssao_targets は target と同じ大きさのもの。None なら AO を掛けない。
+    // snow_target は target から作ったもの。None なら雪を描かない
+    fn draw_scene(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &SceneTarget,
+        ssao_targets: Option<&SsaoTargets>,
+        snow_target: Option<&SnowTarget>,
+        clear_color: wgpu::Color,
+        overlays: bool,
+    ) {
+        if let (true, Some(ssao_targets)) = (self.ssao.enabled, ssao_targets) {
+            {
+                let mut render_pass = ssao_targets.begin_prepass(encoder);
+                render_pass.set_pipeline(&self.ssao.prepass_pipeline);
+                render_pass.draw_model_instance_groups(
+                    &self.model_instance_group_book,
+                    &self.uniform_setting.bind_group,
+                );
+            }
+            let view = SsaoView {
+                view_proj: self.uniform_setting.uniforms.view_proj(),
+                eye: self.camera_setting.camera.position,
+                forward: self.camera_setting.camera.direction(),
+            };
+            self.ssao.render(encoder, &self.queue, ssao_targets, &view);
+        }
+
+        let sample_count = target.sample_count;
+        let pipelines = &self.scene_pipelines[&sample_count];
+        // MSAA のときはマルチサンプルのテクスチャに描いてから解決する
+        let (color_view, resolve_target) = target.attachment();
 
         // borrow encoder as &mut
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -398,15 +1440,10 @@ impl ShaderState {
             color_attachments: &[
                 wgpu::RenderPassColorAttachmentDescriptor {
                     // 書き出し先
-                    attachment: &frame.view,
-                    resolve_target: None,
+                    attachment: color_view,
+                    resolve_target,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.1,
-                            b: 0.1,
-                            a: 1.0,
-                        }),
+                        load: wgpu::LoadOp::Clear(clear_color),
                         store: true,
                     }
                 }
@@ -414,7 +1451,7 @@ impl ShaderState {
             // 深さについて
             depth_stencil_attachment: Some(
                 wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                    attachment: &self.depth_texture.view,
+                    attachment: &target.depth.view,
                     depth_ops: Some(wgpu::Operations {
                         load: wgpu::LoadOp::Clear(1.0),
                         store: true,
@@ -424,30 +1461,311 @@ impl ShaderState {
             ),
         });
 
-        render_pass.set_pipeline(&self.light_render_pipeline);
+        // 背景が透明でなければ環境マップで塗る
+        if self.environment.skybox_visible() && clear_color.a >= 1.0 {
+            render_pass.set_pipeline(&pipelines.skybox);
+            render_pass.set_bind_group(0, &self.uniform_setting.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        render_pass.set_bind_group(3, self.ssao.bind_group(ssao_targets), &[]);
+
+        render_pass.set_pipeline(&pipelines.light);
         render_pass.draw_model_instance_groups(
             &self.light_instance_group_book,
             &self.uniform_setting.bind_group,
         );
 
-        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_pipeline(&pipelines.render);
         render_pass.draw_model_instance_groups(
             &self.model_instance_group_book,
             &self.uniform_setting.bind_group,
         );
-        // borrow end
+
+        if overlays && sample_count == self.sample_count {
+            self.shadow_debug.draw_frustums(&mut render_pass, &self.uniform_setting.bind_group);
+            self.light_gizmo.draw(&mut render_pass, &self.uniform_setting.bind_group);
+        }
         drop(render_pass);
 
-        // Submit Command. and its result will appear on frame.
-        // submit will accept anything that implements IntoIter
+        // 雪は深度を読んで薄くするので、シーンを描き終えてから重ねる
+        if let Some(snow_target) = snow_target {
+            self.snow.render(
+                encoder,
+                &self.queue,
+                target,
+                snow_target,
+                &self.uniform_setting.bind_group,
+                self.camera_setting.camera.direction(),
+            );
+        }
+    }
+
+    // 今の状態を target に描いて読み出す。
+    // カメラの縦横比は size (分割前の画像の大きさ) に合わせ、tile の部分を切り出す
+    fn render_offscreen(
+        &mut self,
+        target: &OffscreenTarget,
+        size: (u32, u32),
+        tile: &capture::Tile,
+        clear_color: wgpu::Color,
+        adapt_dt: Option<f32>,
+    ) -> Result<image::RgbaImage> {
+        self.camera_setting.projection.resize(size.0, size.1);
+        self.write_view_proj_cropped(tile.crop);
+
+        let mut encoder = self.device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("Offscreen Render Encoder"),
+            }
+        );
+        self.render_shadows(&mut encoder);
+        self.snow.simulate(&mut encoder);
+        self.draw_scene(
+            &mut encoder,
+            &target.targets.scene,
+            Some(&target.ssao),
+            Some(&target.snow),
+            clear_color,
+            false,
+        );
+        self.post_process.render(
+            &mut encoder,
+            &self.queue,
+            &target.targets,
+            &target.view,
+            adapt_dt,
+            tile.viewport,
+        );
         self.queue.submit(std::iter::once(encoder.finish()));
+
+        let image = target.read(&self.device, &self.queue);
+
+        self.camera_setting.projection.resize(self.sc_desc.width, self.sc_desc.height);
+        self.write_view_proj();
+        image
+    }
+
+    // 今のカメラから見た画像を width x height で描き直す。
+    // TILE_SIZE を超える大きさは分割して描いてから繋ぎ合わせる
+    pub fn capture(&mut self, width: u32, height: u32, transparent: bool) -> Result<image::RgbaImage> {
+        if width == 0 || height == 0 {
+            bail!("Invalid screenshot size: {}x{}", width, height);
+        }
+        let clear_color = if transparent {
+            wgpu::Color { a: 0.0, ..Self::CLEAR_COLOR }
+        } else {
+            Self::CLEAR_COLOR
+        };
+
+        let tile_width = width.min(capture::TILE_SIZE);
+        let tile_height = height.min(capture::TILE_SIZE);
+        let target = OffscreenTarget::new(
+            &self.device,
+            &self.sc_desc,
+            &self.post_process,
+            &self.ssao,
+            &self.snow,
+            tile_width,
+            tile_height,
+            self.sample_count,
+        );
+        let tiles = capture::tiles(width, height, tile_width, tile_height);
+        if tiles.len() == 1 {
+            return self.render_offscreen(&target, (width, height), &tiles[0], clear_color, None);
+        }
+
+        let mut image = image::RgbaImage::new(width, height);
+        for tile in tiles.iter() {
+            // 露出はタイル間で揃えるため画面のものをそのまま使う。
+            // ブルームはタイルの中でしか広がらないので、継ぎ目で途切れることがある
+            let part = self.render_offscreen(&target, (width, height), tile, clear_color, None)?;
+            // はみ出した部分は捨てられる
+            image::imageops::replace(&mut image, &part, tile.x, tile.y);
+        }
+        Ok(image)
+    }
+
+    // screenshot_setting に従って保存し、書き出したパスを返す
+    pub fn save_screenshot(&mut self) -> Result<std::path::PathBuf> {
+        let setting = self.screenshot_setting.clone();
+        let (width, height) = setting.size.unwrap_or((self.sc_desc.width, self.sc_desc.height));
+        let image = self.capture(width, height, setting.transparent)?;
+
+        std::fs::create_dir_all(&setting.dir)
+            .with_context(|| format!("Cannot create {:?}", setting.dir))?;
+        let path = setting.dir.join(format!(
+            "screenshot_{}.{}",
+            capture::timestamp(),
+            setting.format.extension(),
+        ));
+        let res = match setting.format {
+            ScreenshotFormat::Png => image.save(&path),
+            ScreenshotFormat::Jpeg => image::DynamicImage::ImageRgba8(image).to_rgb().save(&path),
+        };
+        res.with_context(|| format!("Cannot write {:?}", path))?;
+        Ok(path)
+    }
+
+    // poses を1枚ずつ dir に frame_00000.png から書き出す。
+    // アニメーションは実時間ではなく 1 / fps ずつ進める
+    pub fn render_sequence<P: AsRef<std::path::Path>>(
+        &mut self,
+        poses: &[CameraPose],
+        dir: P,
+    ) -> Result<()> {
+        let setting = self.sequence_setting.clone();
+        // 連番は分割せずに描くのでテクスチャの上限に収める
+        let max = capture::MAX_TEXTURE_SIZE;
+        if setting.width == 0 || setting.height == 0 || setting.width > max || setting.height > max {
+            bail!("Invalid sequence size: {}x{} (max {})", setting.width, setting.height, max);
+        }
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).with_context(|| format!("Cannot create {:?}", dir))?;
+        let target = OffscreenTarget::new(
+            &self.device,
+            &self.sc_desc,
+            &self.post_process,
+            &self.ssao,
+            &self.snow,
+            setting.width,
+            setting.height,
+            self.sample_count,
+        );
+        let dt = std::time::Duration::from_secs_f32(1.0 / setting.fps);
+        let saved = self.camera_pose();
+
+        let mut result = Ok(());
+        for (i, pose) in poses.iter().enumerate() {
+            if i > 0 {
+                self.animate(dt);
+                result = self.skin(dt);
+                if result.is_err() {
+                    break;
+                }
+            }
+            // カメラのアニメーションより指定の姿勢を優先する
+            pose.apply(&mut self.camera_setting.camera, &mut self.camera_setting.projection);
+            if i > 0 {
+                // 雪もフレームの間隔で進める。粒は render_offscreen の simulate で動く
+                let eye = self.camera_setting.camera.position;
+                self.snow.update(&self.queue, dt.as_secs_f32(), eye);
+                self.snow_cover.update(&self.queue, dt.as_secs_f32(), eye);
+            }
+            let path = dir.join(format!("frame_{:05}.png", i));
+            result = self.render_offscreen(
+                &target,
+                (setting.width, setting.height),
+                &capture::Tile::full(),
+                Self::CLEAR_COLOR,
+                Some(dt.as_secs_f32()),
+            ).and_then(|image| {
+                image.save(&path).with_context(|| format!("Cannot write {:?}", path))
+            });
+            if result.is_err() {
+                break;
+            }
+        }
+
+        saved.apply(&mut self.camera_setting.camera, &mut self.camera_setting.projection);
+        self.write_view_proj();
+        result
+    }
+
+    // シーン全体の周りを一周する連番を書き出し、書き出したディレクトリを返す
+    pub fn render_turntable(&mut self) -> Result<std::path::PathBuf> {
+        let sphere = self.scene_aabb().bounding_sphere();
+        let poses = sequence::turntable(
+            &self.camera_pose(),
+            &sphere,
+            self.sequence_setting.turntable_degrees,
+            self.sequence_setting.turntable_frames,
+        );
+        let dir = self.sequence_setting.dir.join(format!("turntable_{}", capture::timestamp()));
+
+        // 回っている間ずっと球全体が描かれるよう描画範囲を広げる
+        let projection = &mut self.camera_setting.projection;
+        let (znear, zfar) = (projection.znear(), projection.zfar());
+        if let Some(pose) = poses.first() {
+            let distance = (pose.position - sphere.center).magnitude();
+            let near = ((distance - sphere.radius) * 0.5).max(distance * 0.001);
+            projection.set_clip(near.min(znear), ((distance + sphere.radius) * 1.1).max(zfar));
+        }
+        let result = self.render_sequence(&poses, &dir);
+        self.camera_setting.projection.set_clip(znear, zfar);
+        result.map(|_| dir)
+    }
+
+    // 記録したカメラの動きを連番で書き出す。経路も同じ場所に保存する
+    pub fn render_camera_path(&mut self) -> Result<std::path::PathBuf> {
+        if self.camera_path.is_empty() {
+            bail!("No camera path recorded");
+        }
+        let poses = self.camera_path.frames(self.sequence_setting.fps);
+        let dir = self.sequence_setting.dir.join(format!("camera_path_{}", capture::timestamp()));
+        std::fs::create_dir_all(&dir).with_context(|| format!("Cannot create {:?}", dir))?;
+        self.camera_path.save(dir.join("camera_path.txt"))?;
+        self.render_sequence(&poses, &dir)?;
+        Ok(dir)
+    }
+
+    // 保存しておいた経路を読み込む。render_camera_path で書き出せる
+    pub fn load_camera_path<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.camera_path = CameraPath::load(path)?;
+        self.recording_path = false;
+        Ok(())
+    }
+
+    // 記録を始めるときは前の経路を捨てる
+    pub fn toggle_path_recording(&mut self) {
+        self.recording_path = !self.recording_path;
+        if self.recording_path {
+            self.camera_path.clear();
+        }
+    }
+}
+
+// 背景と光源の球とモデルを描くパイプラインの組
+struct ScenePipelines {
+    render: wgpu::RenderPipeline,
+    light: wgpu::RenderPipeline,
+    skybox: wgpu::RenderPipeline,
+}
+
+impl ScenePipelines {
+    fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        skybox_layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Result<Self> {
+        let render = create_render_pipeline(
+            device,
+            layout,
+            format,
+            sample_count,
+            wgpu::include_spirv!("./shader.vert.spv"),
+            wgpu::include_spirv!("./shader.frag.spv"),
+        )?;
+        let light = create_render_pipeline(
+            device,
+            layout,
+            format,
+            sample_count,
+            wgpu::include_spirv!("./no_shade.vert.spv"),
+            wgpu::include_spirv!("./no_shade.frag.spv"),
+        )?;
+        let skybox = environment::create_skybox_pipeline(device, skybox_layout, format, sample_count);
+        Ok(Self { render, light, skybox })
     }
 }
 
 fn create_render_pipeline(
     device: &wgpu::Device,
-    render_pipeline_layout: wgpu::PipelineLayout,
-    sc_desc: &wgpu::SwapChainDescriptor,
+    render_pipeline_layout: &wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
     vert_src: wgpu::ShaderModuleSource,
     frag_src: wgpu::ShaderModuleSource,
 ) -> Result<wgpu::RenderPipeline> {
@@ -457,7 +1775,7 @@ fn create_render_pipeline(
     let res = device.create_render_pipeline(
         &wgpu::RenderPipelineDescriptor {
             label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
+            layout: Some(render_pipeline_layout),
             vertex_stage: wgpu::ProgrammableStageDescriptor {
                 module: &vs_module,
                 entry_point: "main",
@@ -480,7 +1798,7 @@ fn create_render_pipeline(
             ),
             color_states: &[
                 wgpu::ColorStateDescriptor {
-                    format: sc_desc.format,
+                    format,
                     color_blend: wgpu::BlendDescriptor::REPLACE,
                     // 透明度も塗り替え
                     alpha_blend: wgpu::BlendDescriptor::REPLACE,
@@ -501,10 +1819,11 @@ fn create_render_pipeline(
                 index_format: wgpu::IndexFormat::Uint32,
                 vertex_buffers: &[
                     // Vertex::desc()
-                    model::ModelVertex::desc()
+                    model::ModelVertex::desc(),
+                    model::MorphVertex::desc(),
                 ],
             },
-            sample_count: 1,
+            sample_count,
             sample_mask: !0,
             alpha_to_coverage_enabled: false,
         }
@@ -0,0 +1,647 @@
+use anyhow::*;
+use cgmath::*;
+use std::path::Path;
+
+// キーフレームアニメーション
+// 時間はすべて秒。ShaderState::update に渡される dt で進む
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    Linear,
+    // 次のキーまで値を保持する
+    Step,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Default for Easing {
+    fn default() -> Self {
+        Easing::Linear
+    }
+}
+
+impl Easing {
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.max(0.0).min(1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::Step => 0.0,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => t * (2.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "linear" => Easing::Linear,
+            "step" => Easing::Step,
+            "ease_in" => Easing::EaseIn,
+            "ease_out" => Easing::EaseOut,
+            "ease_in_out" => Easing::EaseInOut,
+            _ => bail!("Unknown easing: {}", s),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopMode {
+    Once,
+    Loop,
+    PingPong,
+}
+
+impl LoopMode {
+    fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "once" => LoopMode::Once,
+            "loop" => LoopMode::Loop,
+            "pingpong" => LoopMode::PingPong,
+            _ => bail!("Unknown loop mode: {}", s),
+        })
+    }
+
+    // 経過時間をクリップ内のローカル時間に直す
+    fn local_time(&self, time: f32, duration: f32) -> f32 {
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            LoopMode::Once => time.max(0.0).min(duration),
+            LoopMode::Loop => time.rem_euclid(duration),
+            LoopMode::PingPong => {
+                let t = time.rem_euclid(duration * 2.0);
+                if t > duration { duration * 2.0 - t } else { t }
+            }
+        }
+    }
+}
+
+pub trait Interpolate: Copy {
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for Vector3<f32> {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self.lerp(*other, t)
+    }
+}
+
+impl Interpolate for Quaternion<f32> {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        // 遠回りしないように符号を揃える
+        let other = if self.dot(*other) < 0.0 { -*other } else { *other };
+        self.slerp(other, t)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe<T> {
+    pub time: f32,
+    pub value: T,
+    // このキーから次のキーまでの補間の仕方
+    pub easing: Easing,
+}
+
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    keys: Vec<Keyframe<T>>,
+}
+
+impl<T: Interpolate> Track<T> {
+    pub fn new(mut keys: Vec<Keyframe<T>>) -> Self {
+        keys.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+        Self { keys }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map(|k| k.time).unwrap_or(0.0)
+    }
+
+    pub fn sample(&self, time: f32) -> Option<T> {
+        let first = self.keys.first()?;
+        if time <= first.time {
+            return Some(first.value);
+        }
+        // time を含む区間を探す
+        let next = self.keys.iter().position(|k| k.time > time);
+        let next = match next {
+            Some(i) => i,
+            None => return self.keys.last().map(|k| k.value),
+        };
+        let a = &self.keys[next - 1];
+        let b = &self.keys[next];
+        let span = b.time - a.time;
+        let t = if span > 0.0 { (time - a.time) / span } else { 1.0 };
+        Some(a.value.interpolate(&b.value, a.easing.apply(t)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnimationTarget {
+    Instance(String),
+    Light(usize),
+    Camera,
+}
+
+#[derive(Debug, Clone)]
+pub enum Channel {
+    Position(Track<Vector3<f32>>),
+    Rotation(Track<Quaternion<f32>>),
+    Scale(Track<f32>),
+    // (ターゲット番号, 重み)
+    MorphWeight(usize, Track<f32>),
+    LightColor(Track<Vector3<f32>>),
+    LightIntensity(Track<f32>),
+    // カメラの向きは度で持つ
+    CameraYaw(Track<f32>),
+    CameraPitch(Track<f32>),
+    // 原点周りに回した位置。Position と違い、キーの間も円周上を動く
+    Orbit(Vector3<f32>, Track<Quaternion<f32>>),
+}
+
+impl Channel {
+    fn duration(&self) -> f32 {
+        use Channel::*;
+        match self {
+            Position(t) | LightColor(t) => t.duration(),
+            Rotation(t) | Orbit(_, t) => t.duration(),
+            Scale(t) | MorphWeight(_, t) | LightIntensity(t) | CameraYaw(t) | CameraPitch(t) => t.duration(),
+        }
+    }
+
+    fn sample(&self, time: f32) -> Option<Sample> {
+        use Channel::*;
+        Some(match self {
+            Position(t) => Sample::Position(t.sample(time)?),
+            Rotation(t) => Sample::Rotation(t.sample(time)?),
+            Scale(t) => Sample::Scale(t.sample(time)?),
+            MorphWeight(i, t) => Sample::MorphWeight(*i, t.sample(time)?),
+            LightColor(t) => Sample::LightColor(t.sample(time)?),
+            LightIntensity(t) => Sample::LightIntensity(t.sample(time)?),
+            CameraYaw(t) => Sample::CameraYaw(Deg(t.sample(time)?)),
+            CameraPitch(t) => Sample::CameraPitch(Deg(t.sample(time)?)),
+            Orbit(from, t) => Sample::Position(t.sample(time)?.rotate_vector(*from)),
+        })
+    }
+}
+
+// ある時刻で評価した値。ShaderState がこれを各対象に書き込む
+#[derive(Debug, Clone, Copy)]
+pub enum Sample {
+    Position(Vector3<f32>),
+    Rotation(Quaternion<f32>),
+    Scale(f32),
+    MorphWeight(usize, f32),
+    LightColor(Vector3<f32>),
+    LightIntensity(f32),
+    CameraYaw(Deg<f32>),
+    CameraPitch(Deg<f32>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Clip {
+    pub name: String,
+    pub loop_mode: LoopMode,
+    pub tracks: Vec<(AnimationTarget, Channel)>,
+    duration: f32,
+}
+
+impl Clip {
+    pub fn new(name: String, loop_mode: LoopMode, tracks: Vec<(AnimationTarget, Channel)>) -> Self {
+        let duration = tracks.iter()
+            .map(|(_, ch)| ch.duration())
+            .fold(0.0, f32::max);
+        Self {
+            name,
+            loop_mode,
+            tracks,
+            duration,
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+}
+
+struct ClipState {
+    clip: Clip,
+    time: f32,
+    speed: f32,
+    playing: bool,
+}
+
+#[derive(Default)]
+pub struct Animator {
+    states: Vec<ClipState>,
+}
+
+impl Animator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_clip(&mut self, clip: Clip) {
+        self.states.push(ClipState {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            playing: true,
+        });
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        for clip in load_clips(path)? {
+            self.add_clip(clip);
+        }
+        Ok(())
+    }
+
+    fn state_mut(&mut self, name: &str) -> Result<&mut ClipState> {
+        self.states
+            .iter_mut()
+            .find(|s| s.clip.name == name)
+            .with_context(|| format!("No such clip: {}", name))
+    }
+
+    pub fn play(&mut self, name: &str) -> Result<()> {
+        self.state_mut(name)?.playing = true;
+        Ok(())
+    }
+
+    pub fn pause(&mut self, name: &str) -> Result<()> {
+        self.state_mut(name)?.playing = false;
+        Ok(())
+    }
+
+    pub fn seek(&mut self, name: &str, time: f32) -> Result<()> {
+        self.state_mut(name)?.time = time;
+        Ok(())
+    }
+
+    pub fn set_speed(&mut self, name: &str, speed: f32) -> Result<()> {
+        self.state_mut(name)?.speed = speed;
+        Ok(())
+    }
+
+    pub fn remove_clip(&mut self, name: &str) {
+        self.states.retain(|s| s.clip.name != name);
+    }
+
+    // target を動かすトラックを全クリップから外す。対象が消えたときに使う
+    pub fn remove_target(&mut self, target: &AnimationTarget) {
+        for state in self.states.iter_mut() {
+            state.clip.tracks.retain(|(t, _)| t != target);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    // 時間を進めて、各トラックの現在値を返す
+    pub fn advance(&mut self, dt: f32) -> Vec<(AnimationTarget, Sample)> {
+        let mut samples = Vec::new();
+        for state in self.states.iter_mut() {
+            if !state.playing {
+                continue;
+            }
+            state.time += dt * state.speed;
+            let local = state.clip.loop_mode.local_time(state.time, state.clip.duration);
+            for (target, channel) in state.clip.tracks.iter() {
+                if let Some(sample) = channel.sample(local) {
+                    samples.push((target.clone(), sample));
+                }
+            }
+        }
+        samples
+    }
+}
+
+// アニメーションファイルの書式 (1行1要素、# 以降はコメント)
+//
+//   clip <name> [once|loop|pingpong]
+//   track instance <name> <position|rotation|scale>
+//   track instance <name> morph <target>
+//   track light <id> <position|color|intensity>
+//   track camera <position|yaw|pitch>
+//   key <time> <values...> [easing]
+//
+// 値の個数は position/color が3つ、rotation が軸3つと角度(度)、それ以外は1つ。
+// easing は linear, step, ease_in, ease_out, ease_in_out のいずれか
+pub fn load_clips<P: AsRef<Path>>(path: P) -> Result<Vec<Clip>> {
+    let src = std::fs::read_to_string(path.as_ref())
+        .with_context(|| format!("Cannot read {:?}", path.as_ref()))?;
+    parse_clips(&src)
+}
+
+enum KeyKind {
+    Vec3,
+    Quat,
+    Scalar,
+}
+
+struct TrackBuilder {
+    target: AnimationTarget,
+    channel: String,
+    // morph のターゲット番号
+    index: usize,
+    kind: KeyKind,
+    keys: Vec<(f32, Vec<f32>, Easing)>,
+}
+
+impl TrackBuilder {
+    fn build(self) -> Result<(AnimationTarget, Channel)> {
+        let TrackBuilder { target, channel, index, keys, .. } = self;
+
+        let vec3 = || Track::new(keys.iter().map(|(time, v, easing)| Keyframe {
+            time: *time,
+            value: Vector3::new(v[0], v[1], v[2]),
+            easing: *easing,
+        }).collect());
+        let scalar = || Track::new(keys.iter().map(|(time, v, easing)| Keyframe {
+            time: *time,
+            value: v[0],
+            easing: *easing,
+        }).collect());
+        let quat = || Track::new(keys.iter().map(|(time, v, easing)| Keyframe {
+            time: *time,
+            value: Quaternion::from_axis_angle(
+                Vector3::new(v[0], v[1], v[2]).normalize(),
+                Deg(v[3]),
+            ),
+            easing: *easing,
+        }).collect());
+
+        let ch = match (&target, channel.as_str()) {
+            (AnimationTarget::Instance(_), "position")
+            | (AnimationTarget::Light(_), "position")
+            | (AnimationTarget::Camera, "position") => Channel::Position(vec3()),
+            (AnimationTarget::Instance(_), "rotation") => Channel::Rotation(quat()),
+            (AnimationTarget::Instance(_), "scale") => Channel::Scale(scalar()),
+            (AnimationTarget::Instance(_), "morph") => Channel::MorphWeight(index, scalar()),
+            (AnimationTarget::Light(_), "color") => Channel::LightColor(vec3()),
+            (AnimationTarget::Light(_), "intensity") => Channel::LightIntensity(scalar()),
+            (AnimationTarget::Camera, "yaw") => Channel::CameraYaw(scalar()),
+            (AnimationTarget::Camera, "pitch") => Channel::CameraPitch(scalar()),
+            (t, c) => bail!("Channel {} is not available for {:?}", c, t),
+        };
+        Ok((target, ch))
+    }
+}
+
+pub fn parse_clips(src: &str) -> Result<Vec<Clip>> {
+    let mut clips = Vec::new();
+    let mut current: Option<(String, LoopMode, Vec<(AnimationTarget, Channel)>)> = None;
+    let mut track: Option<TrackBuilder> = None;
+
+    for (n, line) in src.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let err = || format!("line {}: {}", n + 1, line);
+
+        match words[0] {
+            "clip" => {
+                if let Some(t) = track.take() {
+                    let (_, _, tracks) = current.as_mut().with_context(err)?;
+                    tracks.push(t.build().with_context(err)?);
+                }
+                if let Some((name, loop_mode, tracks)) = current.take() {
+                    clips.push(Clip::new(name, loop_mode, tracks));
+                }
+                let name = words.get(1).with_context(err)?.to_string();
+                let loop_mode = match words.get(2) {
+                    Some(w) => LoopMode::parse(w).with_context(err)?,
+                    None => LoopMode::Loop,
+                };
+                current = Some((name, loop_mode, Vec::new()));
+            }
+            "track" => {
+                let (_, _, tracks) = current.as_mut().with_context(err)?;
+                if let Some(t) = track.take() {
+                    tracks.push(t.build().with_context(err)?);
+                }
+                let (target, channel) = match words.get(1).copied() {
+                    Some("instance") => (
+                        AnimationTarget::Instance(words.get(2).with_context(err)?.to_string()),
+                        words.get(3).with_context(err)?,
+                    ),
+                    Some("light") => (
+                        AnimationTarget::Light(words.get(2).with_context(err)?.parse().with_context(err)?),
+                        words.get(3).with_context(err)?,
+                    ),
+                    Some("camera") => (
+                        AnimationTarget::Camera,
+                        words.get(2).with_context(err)?,
+                    ),
+                    _ => bail!("Unknown track target: {}", err()),
+                };
+                let index = if *channel == "morph" {
+                    let w = match target {
+                        AnimationTarget::Instance(_) => words.get(4),
+                        _ => words.get(3),
+                    };
+                    w.with_context(err)?.parse().with_context(err)?
+                } else {
+                    0
+                };
+                let kind = match *channel {
+                    "position" | "color" => KeyKind::Vec3,
+                    "rotation" => KeyKind::Quat,
+                    _ => KeyKind::Scalar,
+                };
+                track = Some(TrackBuilder {
+                    target,
+                    channel: channel.to_string(),
+                    index,
+                    kind,
+                    keys: Vec::new(),
+                });
+            }
+            "key" => {
+                let t = track.as_mut().with_context(err)?;
+                let value_num = match t.kind {
+                    KeyKind::Vec3 => 3,
+                    KeyKind::Quat => 4,
+                    KeyKind::Scalar => 1,
+                };
+                if words.len() < 2 + value_num {
+                    bail!("Too few values: {}", err());
+                }
+                let time = words[1].parse::<f32>().with_context(err)?;
+                let values = words[2..2 + value_num]
+                    .iter()
+                    .map(|w| w.parse::<f32>())
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .with_context(err)?;
+                // 長さ 0 の軸は正規化できない
+                if let KeyKind::Quat = t.kind {
+                    if Vector3::new(values[0], values[1], values[2]).magnitude2() == 0.0 {
+                        bail!("Rotation axis must not be zero: {}", err());
+                    }
+                }
+                let easing = match words.get(2 + value_num) {
+                    Some(w) => Easing::parse(w).with_context(err)?,
+                    None => Easing::Linear,
+                };
+                t.keys.push((time, values, easing));
+            }
+            _ => bail!("Unknown directive: {}", err()),
+        }
+    }
+
+    if let Some(t) = track.take() {
+        let (_, _, tracks) = current.as_mut().context("track before clip")?;
+        tracks.push(t.build()?);
+    }
+    if let Some((name, loop_mode, tracks)) = current.take() {
+        clips.push(Clip::new(name, loop_mode, tracks));
+    }
+
+    Ok(clips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(time: f32, value: f32, easing: Easing) -> Keyframe<f32> {
+        Keyframe { time, value, easing }
+    }
+
+    #[test]
+    fn orbit_stays_on_the_circle_between_keys() {
+        let keys = (0..=4)
+            .map(|i| Keyframe {
+                time: i as f32,
+                value: Quaternion::from_axis_angle(Vector3::unit_y(), Deg(90.0 * i as f32)),
+                easing: Easing::Linear,
+            })
+            .collect();
+        let channel = Channel::Orbit(Vector3::new(2.0, 1.0, 0.0), Track::new(keys));
+        for i in 0..=40 {
+            let time = i as f32 * 0.1;
+            let p = match channel.sample(time) {
+                Some(Sample::Position(p)) => p,
+                s => panic!("unexpected sample {:?}", s),
+            };
+            let angle = Rad::from(Deg(90.0 * time));
+            assert!((p.y - 1.0).abs() < 1e-4);
+            assert!((Vector2::new(p.x, p.z).magnitude() - 2.0).abs() < 1e-4, "{:?} at {}", p, time);
+            assert!((p.x - 2.0 * angle.0.cos()).abs() < 1e-4, "{:?} at {}", p, time);
+        }
+    }
+
+    #[test]
+    fn sample_interpolates_between_keys() {
+        let track = Track::new(vec![
+            key(1.0, 10.0, Easing::Linear),
+            key(0.0, 0.0, Easing::Linear),
+            key(2.0, 20.0, Easing::Step),
+        ]);
+        assert_eq!(track.duration(), 2.0);
+        assert_eq!(track.sample(-1.0), Some(0.0));
+        assert_eq!(track.sample(0.5), Some(5.0));
+        assert_eq!(track.sample(1.5), Some(15.0));
+        assert_eq!(track.sample(3.0), Some(20.0));
+    }
+
+    #[test]
+    fn sample_holds_value_with_step() {
+        let track = Track::new(vec![key(0.0, 1.0, Easing::Step), key(1.0, 2.0, Easing::Linear)]);
+        assert_eq!(track.sample(0.99), Some(1.0));
+        assert_eq!(track.sample(1.0), Some(2.0));
+    }
+
+    #[test]
+    fn sample_of_empty_track_is_none() {
+        assert_eq!(Track::<f32>::new(Vec::new()).sample(0.0), None);
+    }
+
+    #[test]
+    fn parse_clips_reads_tracks() {
+        let src = "
+            # コメント
+            clip walk pingpong
+            track instance house2 rotation
+            key 0 0 1 0 0
+            key 2 0 1 0 90 ease_in_out
+            track light 1 intensity
+            key 0 0.5
+            key 4 1.0 step
+            clip cam
+            track camera yaw
+            key 1 30
+        ";
+        let clips = parse_clips(src).unwrap();
+        assert_eq!(clips.len(), 2);
+
+        let walk = &clips[0];
+        assert_eq!(walk.name, "walk");
+        assert_eq!(walk.loop_mode, LoopMode::PingPong);
+        assert_eq!(walk.duration(), 4.0);
+        assert_eq!(walk.tracks.len(), 2);
+        assert_eq!(walk.tracks[0].0, AnimationTarget::Instance("house2".to_string()));
+        assert_eq!(walk.tracks[1].0, AnimationTarget::Light(1));
+
+        let cam = &clips[1];
+        assert_eq!(cam.loop_mode, LoopMode::Loop);
+        assert_eq!(cam.tracks[0].0, AnimationTarget::Camera);
+    }
+
+    #[test]
+    fn parse_clips_reads_morph_index() {
+        let src = "clip c\ntrack instance face morph 3\nkey 0 0.25";
+        let clips = parse_clips(src).unwrap();
+        match &clips[0].tracks[0].1 {
+            Channel::MorphWeight(i, t) => {
+                assert_eq!(*i, 3);
+                assert_eq!(t.sample(0.0), Some(0.25));
+            }
+            c => panic!("unexpected channel {:?}", c),
+        }
+    }
+
+    #[test]
+    fn parse_clips_rejects_malformed_input() {
+        let bad = [
+            "track camera yaw\nkey 0 1",
+            "clip c\nkey 0 1",
+            "clip c\ntrack instance a position\nkey 0 1 2",
+            "clip c\ntrack instance a position\nkey 0 1 2 x",
+            "clip c\ntrack instance a rotation\nkey 0 0 0 0 90",
+            "clip c\ntrack light x color",
+            "clip c\ntrack camera color\nkey 0 1 1 1",
+            "clip c\ntrack camera yaw\nkey 0 1 bounce",
+            "clip c wobble",
+            "keyframe 0 1",
+        ];
+        for src in bad.iter() {
+            assert!(parse_clips(src).is_err(), "accepted {:?}", src);
+        }
+    }
+
+    #[test]
+    fn remove_target_drops_only_its_tracks() {
+        let src = "clip c\ntrack instance a scale\nkey 0 1\ntrack instance b scale\nkey 0 2";
+        let mut animator = Animator::new();
+        for clip in parse_clips(src).unwrap() {
+            animator.add_clip(clip);
+        }
+        animator.remove_target(&AnimationTarget::Instance("a".to_string()));
+        let samples = animator.advance(0.0);
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].0, AnimationTarget::Instance("b".to_string()));
+    }
+}
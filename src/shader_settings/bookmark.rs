@@ -0,0 +1,287 @@
+use crate::shader_settings::camera::{Camera, Projection, ProjectionKind};
+use crate::shader_settings::animation::Easing;
+use anyhow::*;
+use cgmath::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// カメラの姿勢と投影をまとめたもの
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraPose {
+    pub position: Point3<f32>,
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
+    pub fovy: Rad<f32>,
+    pub kind: ProjectionKind,
+    pub ortho_height: f32,
+}
+
+impl CameraPose {
+    pub fn capture(camera: &Camera, projection: &Projection) -> Self {
+        Self {
+            position: camera.position,
+            yaw: camera.yaw(),
+            pitch: camera.pitch(),
+            fovy: projection.fovy(),
+            kind: projection.kind,
+            ortho_height: projection.ortho_height(),
+        }
+    }
+
+    pub fn apply(&self, camera: &mut Camera, projection: &mut Projection) {
+        camera.position = self.position;
+        camera.set_yaw(self.yaw);
+        camera.set_pitch(self.pitch);
+        projection.set_fovy(self.fovy);
+        projection.kind = self.kind;
+        projection.set_ortho_height(self.ortho_height);
+    }
+
+    // 向きは近い方を回る。投影の種類は途中で切り替える
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        let angle = |a: Rad<f32>, b: Rad<f32>| a + (b - a).normalize_signed() * t;
+        Self {
+            position: self.position + (other.position - self.position) * t,
+            yaw: angle(self.yaw, other.yaw),
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+            fovy: self.fovy + (other.fovy - self.fovy) * t,
+            kind: if t < 0.5 { self.kind } else { other.kind },
+            ortho_height: self.ortho_height + (other.ortho_height - self.ortho_height) * t,
+        }
+    }
+
+    fn to_line(&self, name: &str) -> String {
+        format!("pose {} {}", name, self.to_fields())
+    }
+
+    // x y z yaw pitch fovy kind ortho_height の順。角度は度で書く
+    pub fn to_fields(&self) -> String {
+        let kind = match self.kind {
+            ProjectionKind::Perspective => "perspective",
+            ProjectionKind::Orthographic => "orthographic",
+        };
+        format!(
+            "{} {} {} {} {} {} {} {}",
+            self.position.x,
+            self.position.y,
+            self.position.z,
+            Deg::from(self.yaw).0,
+            Deg::from(self.pitch).0,
+            Deg::from(self.fovy).0,
+            kind,
+            self.ortho_height,
+        )
+    }
+
+    // to_fields の逆。words は 8 語以上
+    pub fn parse_fields(words: &[&str]) -> Result<Self> {
+        if words.len() < 8 {
+            bail!("Too few fields: {}", words.join(" "));
+        }
+        let num = |i: usize| words[i].parse::<f32>()
+            .with_context(|| format!("Invalid number: {}", words[i]));
+        let kind = match words[6] {
+            "perspective" => ProjectionKind::Perspective,
+            "orthographic" => ProjectionKind::Orthographic,
+            w => bail!("Unknown projection: {}", w),
+        };
+        Ok(Self {
+            position: Point3::new(num(0)?, num(1)?, num(2)?),
+            yaw: Deg(num(3)?).into(),
+            pitch: Deg(num(4)?).into(),
+            fovy: Deg(num(5)?).into(),
+            kind,
+            ortho_height: num(7)?,
+        })
+    }
+}
+
+struct Transition {
+    from: CameraPose,
+    to: CameraPose,
+    time: f32,
+}
+
+// 名前付きのカメラ位置。最後の位置は LAST として一緒に保存する
+pub struct CameraBookmarks {
+    pub poses: BTreeMap<String, CameraPose>,
+    // 切り替えにかける秒数。0 なら即座に移る
+    pub transition_time: f32,
+    path: Option<PathBuf>,
+    transition: Option<Transition>,
+}
+
+impl CameraBookmarks {
+    pub const LAST: &'static str = "last";
+
+    pub fn new() -> Self {
+        Self {
+            poses: BTreeMap::new(),
+            transition_time: 0.5,
+            path: None,
+            transition: None,
+        }
+    }
+
+    // ファイルが無ければ空で始め、save でそこに書き出す
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut bookmarks = Self::new();
+        bookmarks.path = Some(path.as_ref().to_path_buf());
+        if path.as_ref().exists() {
+            let src = std::fs::read_to_string(path.as_ref())
+                .with_context(|| format!("Cannot read {:?}", path.as_ref()))?;
+            bookmarks.poses = parse_poses(&src)?;
+        }
+        Ok(bookmarks)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Cannot create {:?}", dir))?;
+        }
+        let mut src = String::from("# pose <name> <x> <y> <z> <yaw> <pitch> <fovy> <perspective|orthographic> <ortho_height>\n");
+        for (name, pose) in self.poses.iter() {
+            src.push_str(&pose.to_line(name));
+            src.push('\n');
+        }
+        std::fs::write(path, src).with_context(|| format!("Cannot write {:?}", path))
+    }
+
+    pub fn set(&mut self, name: &str, pose: CameraPose) {
+        self.poses.insert(name.to_string(), pose);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CameraPose> {
+        self.poses.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.poses.remove(name);
+    }
+
+    pub fn is_transitioning(&self) -> bool {
+        self.transition.is_some()
+    }
+
+    // 今の姿勢から name の姿勢へ移り始める
+    pub fn go_to(&mut self, name: &str, current: CameraPose) -> Result<()> {
+        let to = *self.poses.get(name).with_context(|| format!("No such bookmark: {}", name))?;
+        self.transition = Some(Transition {
+            from: current,
+            to,
+            time: 0.0,
+        });
+        Ok(())
+    }
+
+    // 移動中なら dt 進めた姿勢を返す。最後のフレームで Some を返してから終わる
+    pub fn advance(&mut self, dt: f32) -> Option<CameraPose> {
+        let transition = self.transition.as_mut()?;
+        transition.time += dt;
+        let t = if self.transition_time > 0.0 {
+            transition.time / self.transition_time
+        } else {
+            1.0
+        };
+        if t >= 1.0 {
+            let to = transition.to;
+            self.transition = None;
+            return Some(to);
+        }
+        Some(transition.from.lerp(&transition.to, Easing::EaseInOut.apply(t)))
+    }
+}
+
+// 1行1つ。# 以降はコメント
+fn parse_poses(src: &str) -> Result<BTreeMap<String, CameraPose>> {
+    let mut poses = BTreeMap::new();
+    for (n, line) in src.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let err = || format!("line {}: {}", n + 1, line);
+        if words[0] != "pose" || words.len() < 10 {
+            bail!("Invalid camera pose: {}", err());
+        }
+        let pose = CameraPose::parse_fields(&words[2..]).with_context(err)?;
+        poses.insert(words[1].to_string(), pose);
+    }
+    Ok(poses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pose(x: f32, kind: ProjectionKind) -> CameraPose {
+        CameraPose {
+            position: Point3::new(x, 2.0, -3.5),
+            yaw: Deg(-90.0).into(),
+            pitch: Deg(20.0).into(),
+            fovy: Deg(45.0).into(),
+            kind,
+            ortho_height: 10.0,
+        }
+    }
+
+    fn assert_pose_near(a: &CameraPose, b: &CameraPose) {
+        assert!((a.position - b.position).magnitude() < 1e-4, "{:?} != {:?}", a, b);
+        assert!((a.yaw - b.yaw).0.abs() < 1e-4, "{:?} != {:?}", a, b);
+        assert!((a.pitch - b.pitch).0.abs() < 1e-4, "{:?} != {:?}", a, b);
+        assert!((a.fovy - b.fovy).0.abs() < 1e-4, "{:?} != {:?}", a, b);
+        assert_eq!(a.kind, b.kind);
+        assert_eq!(a.ortho_height, b.ortho_height);
+    }
+
+    #[test]
+    fn poses_round_trip() {
+        let mut src = String::from("# comment\n\n");
+        src.push_str(&pose(1.0, ProjectionKind::Perspective).to_line("1"));
+        src.push('\n');
+        src.push_str(&pose(-4.25, ProjectionKind::Orthographic).to_line(CameraBookmarks::LAST));
+        src.push_str("  # trailing comment\n");
+
+        let poses = parse_poses(&src).unwrap();
+        assert_eq!(poses.len(), 2);
+        assert_pose_near(&poses["1"], &pose(1.0, ProjectionKind::Perspective));
+        assert_pose_near(&poses[CameraBookmarks::LAST], &pose(-4.25, ProjectionKind::Orthographic));
+    }
+
+    #[test]
+    fn parse_poses_rejects_malformed_lines() {
+        let bad = [
+            "pose 1 0 0 0 0 0 45 perspective",
+            "pose 1 0 0 0 0 0 45 fisheye 10",
+            "pose 1 0 0 x 0 0 45 perspective 10",
+            "camera 1 0 0 0 0 0 45 perspective 10",
+        ];
+        for src in bad.iter() {
+            assert!(parse_poses(src).is_err(), "accepted {:?}", src);
+        }
+    }
+
+    #[test]
+    fn parse_fields_needs_eight_words() {
+        assert!(CameraPose::parse_fields(&["0"; 7]).is_err());
+    }
+
+    #[test]
+    fn transition_ends_on_target() {
+        let mut bookmarks = CameraBookmarks::new();
+        let to = pose(5.0, ProjectionKind::Perspective);
+        bookmarks.set("a", to);
+        assert!(bookmarks.go_to("missing", to).is_err());
+
+        bookmarks.go_to("a", pose(0.0, ProjectionKind::Perspective)).unwrap();
+        let mid = bookmarks.advance(bookmarks.transition_time * 0.5).unwrap();
+        assert!((mid.position.x - 2.5).abs() < 1e-4);
+        assert_eq!(bookmarks.advance(bookmarks.transition_time), Some(to));
+        assert_eq!(bookmarks.advance(0.1), None);
+    }
+}
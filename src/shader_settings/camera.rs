@@ -3,6 +3,7 @@ use winit::event::*;
 use winit::dpi::PhysicalPosition;
 use std::time::Duration;
 use std::f32::consts::FRAC_PI_2;
+use crate::shader_settings::input_binding::Action;
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
@@ -37,6 +38,52 @@ impl Camera {
         }
     }
 
+    pub fn yaw(&self) -> Rad<f32> {
+        self.yaw
+    }
+
+    pub fn pitch(&self) -> Rad<f32> {
+        self.pitch
+    }
+
+    pub fn set_yaw<Y: Into<Rad<f32>>>(&mut self, yaw: Y) {
+        self.yaw = yaw.into();
+    }
+
+    // 上下の向きには上下限がある
+    pub fn set_pitch<P: Into<Rad<f32>>>(&mut self, pitch: P) {
+        let pitch = pitch.into();
+        self.pitch = if pitch < -Rad(FRAC_PI_2) {
+            -Rad(FRAC_PI_2)
+        } else if pitch > Rad(FRAC_PI_2) {
+            Rad(FRAC_PI_2)
+        } else {
+            pitch
+        };
+    }
+
+    // カメラの向いている方向
+    pub fn direction(&self) -> Vector3<f32> {
+        let (yaw_sin, yaw_cos) = self.yaw.0.sin_cos();
+        let (pitch_sin, pitch_cos) = self.pitch.0.sin_cos();
+        Vector3::new(
+            pitch_cos * yaw_cos,
+            pitch_sin,
+            pitch_cos * yaw_sin,
+        ).normalize()
+    }
+
+    // target の方を向くように yaw と pitch を決める
+    pub fn look_at(&mut self, target: Point3<f32>) {
+        let dir = target - self.position;
+        if dir.magnitude2() <= 0.0 {
+            return;
+        }
+        let dir = dir.normalize();
+        self.yaw = Rad(dir.z.atan2(dir.x));
+        self.set_pitch(Rad(dir.y.max(-1.0).min(1.0).asin()));
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
         Matrix4::look_at_dir(
             self.position, // eye
@@ -50,10 +97,20 @@ impl Camera {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProjectionKind {
+    Perspective,
+    // 奥行きで大きさが変わらない。寸法の確認用
+    Orthographic,
+}
+
 #[derive(Clone, Copy)]
 pub struct Projection {
+    pub kind: ProjectionKind,
     aspect: f32,
     fovy: Rad<f32>, // 視野角
+    // 平行投影で画面の縦に映る幅
+    ortho_height: f32,
     znear: f32,
     zfar: f32,
 }
@@ -67,38 +124,174 @@ impl Projection {
         zfar: f32,
     ) -> Self {
         Self {
+            kind: ProjectionKind::Perspective,
             aspect: width as f32 / height as f32,
             fovy: fovy.into(),
+            ortho_height: 10.0,
             znear,
             zfar,
         }
     }
 
+    pub fn new_orthographic(
+        width: u32,
+        height: u32,
+        ortho_height: f32,
+        znear: f32,
+        zfar: f32,
+    ) -> Self {
+        Self {
+            kind: ProjectionKind::Orthographic,
+            ortho_height,
+            ..Self::new(width, height, Deg(45.0), znear, zfar)
+        }
+    }
+
+    pub fn ortho_height(&self) -> f32 {
+        self.ortho_height
+    }
+
+    pub fn set_ortho_height(&mut self, ortho_height: f32) {
+        self.ortho_height = ortho_height.max(1e-3);
+    }
+
+    // 平行投影の拡大縮小。factor > 1 で広く映る
+    pub fn zoom(&mut self, factor: f32) {
+        self.set_ortho_height(self.ortho_height * factor);
+    }
+
+    // distance 先の面の見た目の大きさが変わらないように投影を切り替える
+    pub fn set_kind(&mut self, kind: ProjectionKind, distance: f32) {
+        if kind == ProjectionKind::Orthographic && self.kind == ProjectionKind::Perspective {
+            self.set_ortho_height(2.0 * distance * (self.fovy.0 * 0.5).tan());
+        }
+        self.kind = kind;
+    }
+
+    // 透視投影に戻すとき、見た目を保つために必要な注視点までの距離
+    pub fn perspective_distance(&self) -> f32 {
+        self.ortho_height * 0.5 / (self.fovy.0 * 0.5).tan()
+    }
+
     pub fn resize(&mut self, width: u32, height: u32) {
         self.aspect = width as f32 / height as f32;
     }
 
+    pub fn znear(&self) -> f32 {
+        self.znear
+    }
+
+    pub fn zfar(&self) -> f32 {
+        self.zfar
+    }
+
+    pub fn set_clip(&mut self, znear: f32, zfar: f32) {
+        self.znear = znear;
+        self.zfar = zfar;
+    }
+
+    pub fn aspect(&self) -> f32 {
+        self.aspect
+    }
+
+    pub fn fovy(&self) -> Rad<f32> {
+        self.fovy
+    }
+
+    pub fn set_fovy<F: Into<Rad<f32>>>(&mut self, fovy: F) {
+        self.fovy = fovy.into();
+    }
+
+    // 縦横のうち狭い方の視野角
+    pub fn min_fov(&self) -> Rad<f32> {
+        let half_h = (self.fovy.0 * 0.5).tan() * self.aspect;
+        let fovx = Rad(half_h.atan() * 2.0);
+        if fovx < self.fovy { fovx } else { self.fovy }
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
-        OPENGL_TO_WGPU_MATRIX * perspective(
-            self.fovy,
-            self.aspect,
-            self.znear,
-            self.zfar,
-        )
+        match self.kind {
+            ProjectionKind::Perspective => OPENGL_TO_WGPU_MATRIX * perspective(
+                self.fovy,
+                self.aspect,
+                self.znear,
+                self.zfar,
+            ),
+            ProjectionKind::Orthographic => {
+                let top = self.ortho_height * 0.5;
+                let right = top * self.aspect;
+                OPENGL_TO_WGPU_MATRIX * ortho(
+                    -right,
+                    right,
+                    -top,
+                    top,
+                    self.znear,
+                    self.zfar,
+                )
+            }
+        }
     }
 }
 
+// 軸に沿った定番の視点
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AxisView {
+    Front,
+    Back,
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+impl AxisView {
+    // 真上・真下は look_at_dir が退化しないように少しだけ傾ける
+    const VERTICAL: f32 = 89.9;
+
+    pub fn yaw_pitch(&self) -> (Deg<f32>, Deg<f32>) {
+        match self {
+            // 正面は -z 方向を見る
+            AxisView::Front => (Deg(-90.0), Deg(0.0)),
+            AxisView::Back => (Deg(90.0), Deg(0.0)),
+            AxisView::Left => (Deg(0.0), Deg(0.0)),
+            AxisView::Right => (Deg(180.0), Deg(0.0)),
+            AxisView::Top => (Deg(-90.0), Deg(-Self::VERTICAL)),
+            AxisView::Bottom => (Deg(-90.0), Deg(Self::VERTICAL)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraMode {
+    // WASD とマウスで自由に飛び回る
+    Fly,
+    // pivot の周りを回る
+    Orbit,
+}
+
 #[derive(Debug)]
 pub struct CameraController {
+    pub mode: CameraMode,
+    // Orbit モードの回転中心
+    pub pivot: Point3<f32>,
+    // 動きを滑らかにする時定数 (秒)。0 なら入力がそのまま反映される
+    pub smoothing: f32,
     amount_left: f32,
     amount_right: f32,
     amount_forward: f32,
     amount_backward: f32,
     amount_up: f32,
     amount_down: f32,
+    // 以下はフレーム間に溜まった入力 (ピクセル)。反映した分だけ減らす
     rotate_horizontal: f32,
     rotate_vertical: f32,
+    pan_horizontal: f32,
+    pan_vertical: f32,
     scroll: f32,
+    // (右, 上, 前) 方向の現在の速度
+    velocity: Vector3<f32>,
+    // Slow を押している間は移動が遅くなる
+    slow: bool,
     speed: f32,
     sensitivity: f32,
 }
@@ -106,6 +299,9 @@ pub struct CameraController {
 impl CameraController {
     pub fn new(speed: f32, sensitivity: f32) -> Self {
         Self {
+            mode: CameraMode::Fly,
+            pivot: Point3::new(0.0, 0.0, 0.0),
+            smoothing: 0.05,
             amount_left: 0.0,
             amount_right: 0.0,
             amount_forward: 0.0,
@@ -114,63 +310,239 @@ impl CameraController {
             amount_down: 0.0,
             rotate_horizontal: 0.0,
             rotate_vertical: 0.0,
+            pan_horizontal: 0.0,
+            pan_vertical: 0.0,
             scroll: 0.0,
+            velocity: Vector3::zero(),
+            slow: false,
             speed,
             sensitivity,
         }
     }
 
-    pub fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
-        use VirtualKeyCode as VKC;
-        let amount = if state == ElementState::Pressed { 1.0 } else { 0.0 };
-        match key {
-            VKC::W | VKC::Up => {
-                self.amount_forward = amount;
-                true
-            }
-            VKC::S | VKC::Down => {
-                self.amount_backward = amount;
-                true
-            }
-            VKC::A | VKC::Left => {
-                self.amount_left = amount;
-                true
-            }
-            VKC::D | VKC::Right => {
-                self.amount_right = amount;
-                true
-            }
-            VKC::Space => {
-                self.amount_up = amount;
-                true
+    // 押している間だけ効く action を受け取る。それ以外は false
+    pub fn process_action(&mut self, action: Action, pressed: bool) -> bool {
+        let amount = if pressed { 1.0 } else { 0.0 };
+        match action {
+            Action::MoveForward => self.amount_forward = amount,
+            Action::MoveBackward => self.amount_backward = amount,
+            Action::MoveLeft => self.amount_left = amount,
+            Action::MoveRight => self.amount_right = amount,
+            Action::MoveUp => self.amount_up = amount,
+            Action::MoveDown => self.amount_down = amount,
+            Action::Slow => self.slow = pressed,
+            _ => return false,
+        }
+        true
+    }
+
+    // 1フレームに何度イベントが来ても取りこぼさないように足し込む
+    pub fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        self.rotate_horizontal += mouse_dx as f32;
+        self.rotate_vertical += mouse_dy as f32;
+    }
+
+    pub fn process_pan(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        self.pan_horizontal += mouse_dx as f32;
+        self.pan_vertical += mouse_dy as f32;
+    }
+
+    // 今の見た目を変えずにモードを切り替える
+    pub fn set_mode(&mut self, mode: CameraMode, camera: &Camera) {
+        if self.mode == mode {
+            return;
+        }
+        if mode == CameraMode::Orbit {
+            // pivot までの距離を保ったまま、視線の先に pivot を置き直す
+            let distance = (self.pivot - camera.position).magnitude().max(Self::MIN_ORBIT_DISTANCE);
+            self.pivot = camera.position + camera.direction() * distance;
+        }
+        self.mode = mode;
+        self.stop();
+    }
+
+    pub fn toggle_mode(&mut self, camera: &Camera) {
+        let mode = match self.mode {
+            CameraMode::Fly => CameraMode::Orbit,
+            CameraMode::Orbit => CameraMode::Fly,
+        };
+        self.set_mode(mode, camera);
+    }
+
+    // 溜まった入力と慣性を捨てる
+    pub fn stop(&mut self) {
+        self.rotate_horizontal = 0.0;
+        self.rotate_vertical = 0.0;
+        self.pan_horizontal = 0.0;
+        self.pan_vertical = 0.0;
+        self.scroll = 0.0;
+        self.velocity = Vector3::zero();
+    }
+
+    // 視線方向にある pivot までの距離。後ろにある場合は最小値
+    pub fn pivot_distance(&self, camera: &Camera) -> f32 {
+        (self.pivot - camera.position).dot(camera.direction()).max(Self::MIN_ORBIT_DISTANCE)
+    }
+
+    // pivot を中心に軸方向の視点へ回り込む
+    pub fn set_axis_view(&mut self, view: AxisView, camera: &mut Camera) {
+        let distance = (self.pivot - camera.position).magnitude().max(Self::MIN_ORBIT_DISTANCE);
+        let (yaw, pitch) = view.yaw_pitch();
+        camera.set_yaw(yaw);
+        camera.set_pitch(pitch);
+        camera.position = self.pivot - camera.direction() * distance;
+        self.stop();
+    }
+
+    // 透視/平行投影を切り替える。pivot の見た目の大きさを保つ
+    pub fn toggle_projection(&mut self, camera: &mut Camera, projection: &mut Projection) {
+        match projection.kind {
+            ProjectionKind::Perspective => {
+                let distance = self.pivot_distance(camera);
+                projection.set_kind(ProjectionKind::Orthographic, distance);
             }
-            VKC::LShift => {
-                self.amount_down = amount;
-                true
+            ProjectionKind::Orthographic => {
+                let distance = projection.perspective_distance();
+                let dir = camera.direction();
+                let pivot = camera.position + dir * self.pivot_distance(camera);
+                camera.position = pivot - dir * distance;
+                projection.set_kind(ProjectionKind::Perspective, distance);
             }
-            _ => false,
         }
     }
 
-    pub fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
-        self.rotate_horizontal = mouse_dx as f32;
-        self.rotate_vertical = mouse_dy as f32;
+    // pivot を変えてそちらを向く。距離はそのまま
+    pub fn set_pivot(&mut self, pivot: Point3<f32>, camera: &mut Camera) {
+        self.pivot = pivot;
+        camera.look_at(pivot);
+        self.stop();
     }
 
-    pub fn process_scroll(&mut self, delta: &MouseScrollDelta) {
-        self.scroll = -match delta {
+    fn scroll_pixels(delta: &MouseScrollDelta) -> f32 {
+        -match delta {
             // assume a line is about 100 pixels
             MouseScrollDelta::LineDelta(_, scroll) => scroll * 100.0,
             MouseScrollDelta::PixelDelta(PhysicalPosition {
                 y: scroll,
                 ..
             }) => *scroll as f32, // scrollは借用状態
-        };
+        }
+    }
+
+    pub fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+        self.scroll += Self::scroll_pixels(delta);
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(Self::MIN_SPEED).min(Self::MAX_SPEED);
     }
 
-    pub fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+    // ホイール1段 (100px) で約 15% 速さを変える
+    pub fn process_speed_scroll(&mut self, delta: &MouseScrollDelta) {
+        let pixels = Self::scroll_pixels(delta);
+        self.set_speed(self.speed * 2.0f32.powf(-pixels / 500.0));
+    }
+
+    const MIN_ORBIT_DISTANCE: f32 = 0.1;
+    const SLOW_FACTOR: f32 = 0.25;
+    const MIN_SPEED: f32 = 0.01;
+    const MAX_SPEED: f32 = 1000.0;
+    // 入力1ピクセルあたりの量。dt には依らない
+    const LOOK_PER_PIXEL: f32 = 0.01;
+    const PAN_PER_PIXEL: f32 = 0.0015;
+    const DOLLY_PER_PIXEL: f32 = 0.0015;
+    const FLY_DOLLY_PER_PIXEL: f32 = 0.015;
+
+    // 今の移動速さ
+    fn move_speed(&self) -> f32 {
+        if self.slow { self.speed * Self::SLOW_FACTOR } else { self.speed }
+    }
+
+    // 指数的に目標へ近づく割合。フレームレートに依らず同じ時間で同じだけ近づく
+    fn smoothing_rate(&self, dt: f32) -> f32 {
+        if self.smoothing <= 0.0 {
+            1.0
+        } else {
+            1.0 - (-dt / self.smoothing).exp()
+        }
+    }
+
+    // 溜まった入力のうち rate の分だけ取り出す
+    fn take(pending: &mut f32, rate: f32) -> f32 {
+        let taken = *pending * rate;
+        *pending -= taken;
+        // 残りがごく僅かになったら打ち切る
+        if pending.abs() < 1e-3 {
+            *pending = 0.0;
+        }
+        taken
+    }
+
+    pub fn update_camera(&mut self, camera: &mut Camera, projection: &mut Projection, dt: Duration) {
         let dt = dt.as_secs_f32();
+        let rate = self.smoothing_rate(dt);
+
+        // 平行投影では近づいても大きさが変わらないので、ホイールは拡大縮小にする
+        let mut scroll = Self::take(&mut self.scroll, rate);
+        if projection.kind == ProjectionKind::Orthographic {
+            projection.zoom((1.0 + scroll * self.sensitivity * Self::DOLLY_PER_PIXEL).max(0.1));
+            scroll = 0.0;
+        }
+
+        // 押しているキーから目標の速度を決めて、そこへ滑らかに近づける
+        let target = Vector3::new(
+            self.amount_right - self.amount_left,
+            self.amount_up - self.amount_down,
+            self.amount_forward - self.amount_backward,
+        ) * self.move_speed();
+        self.velocity += (target - self.velocity) * rate;
+        if target == Vector3::zero() && self.velocity.magnitude2() < 1e-6 {
+            self.velocity = Vector3::zero();
+        }
+
+        let yaw = Self::take(&mut self.rotate_horizontal, rate);
+        let pitch = Self::take(&mut self.rotate_vertical, rate);
+        let look = self.sensitivity * Self::LOOK_PER_PIXEL;
+        camera.yaw += Rad(yaw * look);
+        camera.set_pitch(camera.pitch + Rad(-pitch * look));
+
+        match self.mode {
+            CameraMode::Fly => self.update_fly(camera, scroll, rate, dt),
+            CameraMode::Orbit => self.update_orbit(camera, scroll, rate, dt),
+        }
+    }
+
+    fn update_orbit(&mut self, camera: &mut Camera, scroll: f32, rate: f32, dt: f32) {
+        let mut distance = (self.pivot - camera.position).magnitude().max(Self::MIN_ORBIT_DISTANCE);
+
+        // ドリー。距離に比例させて近くでは細かく動く
+        distance *= (1.0 + scroll * self.sensitivity * Self::DOLLY_PER_PIXEL).max(0.1);
+        distance = distance.max(Self::MIN_ORBIT_DISTANCE);
+
+        // パン。pivot ごと画面に平行に動かす
+        let dir = camera.direction();
+        let right = dir.cross(Vector3::unit_y());
+        let right = if right.magnitude2() > 0.0 { right.normalize() } else { Vector3::unit_x() };
+        let up = right.cross(dir).normalize();
+        let pan_scale = distance * self.sensitivity * Self::PAN_PER_PIXEL;
+        let pan_h = Self::take(&mut self.pan_horizontal, rate);
+        let pan_v = Self::take(&mut self.pan_vertical, rate);
+        self.pivot += (-right * pan_h + up * pan_v) * pan_scale;
 
+        // キーボードでも pivot を動かせる
+        let forward = Vector3::new(dir.x, 0.0, dir.z);
+        let forward = if forward.magnitude2() > 0.0 { forward.normalize() } else { Vector3::zero() };
+        self.pivot += (right * self.velocity.x + forward * self.velocity.z) * dt;
+        self.pivot.y += self.velocity.y * dt;
+
+        camera.position = self.pivot - dir * distance;
+    }
+
+    fn update_fly(&mut self, camera: &mut Camera, scroll: f32, rate: f32, dt: f32) {
         // 前後左右
         let (yaw_sin, yaw_cos) = camera.yaw.0.sin_cos();
         // ここの数式は数学。どうしてこうなのか確認してない
@@ -180,38 +552,23 @@ impl CameraController {
         let right = Vector3::new(-yaw_sin, 0.0, yaw_cos).normalize();
 
         // ここはまぁわかるだろう
-        camera.position += forward * (self.amount_forward - self.amount_backward) * self.speed * dt;
-        camera.position += right * (self.amount_right - self.amount_left) * self.speed * dt;
+        camera.position += forward * self.velocity.z * dt;
+        camera.position += right * self.velocity.x * dt;
 
         // ズーム
         // カメラを移動させることで疑似的にズームしてる
         // ヨーだけではなくピッチでカメラの向いている方向に移動させる
-        let (pitch_sin, pitch_cos) = camera.pitch.0.sin_cos();
-        let scrollward = Vector3::new(
-            pitch_cos * yaw_cos,
-            pitch_sin,
-            pitch_cos * yaw_sin,
-        ).normalize();
-        camera.position += scrollward * self.scroll * self.speed * self.sensitivity * dt;
-        self.scroll = 0.0;
+        camera.position += camera.direction() * scroll * self.speed * self.sensitivity * Self::FLY_DOLLY_PER_PIXEL;
 
         // 上下
         // ロールしないのでy軸をずらすだけでいい
-        camera.position.y += (self.amount_up - self.amount_down) * self.speed * dt;
-
-        // 回転
-        camera.yaw += Rad(self.rotate_horizontal) * self.sensitivity * dt;
-        camera.pitch += Rad(-self.rotate_vertical) * self.sensitivity * dt;
+        camera.position.y += self.velocity.y * dt;
 
-        self.rotate_horizontal = 0.0;
-        self.rotate_vertical = 0.0;
-
-        // verticalな方向のカメラワーク(上下に動かす)には上下限がある。
-        if camera.pitch < -Rad(FRAC_PI_2) { // -π/2 より小さい
-            camera.pitch = -Rad(FRAC_PI_2);
-        } else if camera.pitch > Rad(FRAC_PI_2) {
-            camera.pitch = Rad(FRAC_PI_2);
-        }
+        // パンは fly でも使える
+        let pan_h = Self::take(&mut self.pan_horizontal, rate);
+        let pan_v = Self::take(&mut self.pan_vertical, rate);
+        let pan_scale = self.speed * self.sensitivity * Self::PAN_PER_PIXEL;
+        camera.position += (-right * pan_h + Vector3::unit_y() * pan_v) * pan_scale;
     }
 }
 
@@ -221,6 +578,10 @@ pub struct CameraSetting {
     pub camera_controller: CameraController,
     pub last_mouse_pos: PhysicalPosition<f64>,
     pub mouse_pressed: bool,
+    // 中・右ボタンでのパン
+    pub pan_pressed: bool,
+    // 左ボタンを押してからの移動量。クリックとドラッグを区別する
+    pub drag_distance: f64,
 }
 
 impl CameraSetting {
@@ -235,6 +596,8 @@ impl CameraSetting {
             camera_controller: CameraController::new(4.0, 0.4),
             last_mouse_pos: (0.0, 0.0).into(),
             mouse_pressed: false,
+            pan_pressed: false,
+            drag_distance: 0.0,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,271 @@
+use crate::shader_settings::postprocess::{PostProcess, PostTargets};
+use crate::shader_settings::ssao::{Ssao, SsaoTargets};
+use crate::shader_settings::snow::{Snow, SnowTarget};
+use anyhow::*;
+use cgmath::*;
+use std::path::PathBuf;
+
+// 1枚のテクスチャで描ける最大の辺の長さ
+pub const MAX_TEXTURE_SIZE: u32 = 8192;
+// スクリーンショットを分割して描くときの1枚の辺の長さ。
+// HDR や SSAO の中間テクスチャも同じ大きさで作るので、上限よりかなり小さくする
+pub const TILE_SIZE: u32 = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenshotFormat {
+    Png,
+    // 透明度は捨てる
+    Jpeg,
+}
+
+impl ScreenshotFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ScreenshotFormat::Png => "png",
+            ScreenshotFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScreenshotSetting {
+    // None ならウィンドウと同じ大きさ
+    pub size: Option<(u32, u32)>,
+    // 背景を透明にする。PNG のときだけ意味がある
+    pub transparent: bool,
+    pub format: ScreenshotFormat,
+    pub dir: PathBuf,
+}
+
+impl Default for ScreenshotSetting {
+    fn default() -> Self {
+        Self {
+            size: None,
+            transparent: false,
+            format: ScreenshotFormat::Png,
+            dir: PathBuf::from("screenshots"),
+        }
+    }
+}
+
+// 大きな画像のうち1枚のテクスチャに描く部分
+#[derive(Debug, Clone, Copy)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    // 射影後の座標をこのタイルの範囲に引き伸ばす行列
+    pub crop: Matrix4<f32>,
+    // 画像全体を 0..1 としたときのタイルの位置と大きさ。周辺減光などで使う
+    pub viewport: [f32; 4],
+}
+
+impl Tile {
+    // 分割しないときの1枚
+    pub fn full() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            crop: Matrix4::identity(),
+            viewport: [0.0, 0.0, 1.0, 1.0],
+        }
+    }
+}
+
+// width x height の画像を tile_width x tile_height ずつに区切る。端のタイルははみ出してよい
+pub fn tiles(width: u32, height: u32, tile_width: u32, tile_height: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    let (sx, sy) = (width as f32 / tile_width as f32, height as f32 / tile_height as f32);
+    for y in (0..height).step_by(tile_height as usize) {
+        for x in (0..width).step_by(tile_width as usize) {
+            // タイルの中心の NDC 座標。y は上向き
+            let cx = (x as f32 + tile_width as f32 * 0.5) / width as f32 * 2.0 - 1.0;
+            let cy = 1.0 - (y as f32 + tile_height as f32 * 0.5) / height as f32 * 2.0;
+            let crop = Matrix4::new(
+                sx, 0.0, 0.0, 0.0,
+                0.0, sy, 0.0, 0.0,
+                0.0, 0.0, 1.0, 0.0,
+                -sx * cx, -sy * cy, 0.0, 1.0,
+            );
+            let viewport = [
+                x as f32 / width as f32,
+                y as f32 / height as f32,
+                1.0 / sx,
+                1.0 / sy,
+            ];
+            tiles.push(Tile { x, y, crop, viewport });
+        }
+    }
+    tiles
+}
+
+// ウィンドウとは別に描画するためのテクスチャ。
+// targets.scene に HDR で描き、後処理を通して texture に落としてから読み出す
+pub struct OffscreenTarget {
+    pub width: u32,
+    pub height: u32,
+    format: wgpu::TextureFormat,
+    texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub targets: PostTargets,
+    pub ssao: SsaoTargets,
+    pub snow: SnowTarget,
+}
+
+impl OffscreenTarget {
+    // 色の形式はスワップチェーンに合わせる
+    pub fn new(
+        device: &wgpu::Device,
+        sc_desc: &wgpu::SwapChainDescriptor,
+        post_process: &PostProcess,
+        ssao: &Ssao,
+        snow: &Snow,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Texture"),
+            size: wgpu::Extent3d { width, height, depth: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: sc_desc.format,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let targets = post_process.create_targets(device, width, height, sample_count);
+        let ssao = ssao.create_targets(device, width, height);
+        let snow = snow.create_target(device, &targets.scene);
+
+        Self {
+            width,
+            height,
+            format: sc_desc.format,
+            texture,
+            view,
+            targets,
+            ssao,
+            snow,
+        }
+    }
+
+    // 描いた内容を RGBA8 で読み出す。送信済みのコマンドが終わるまで待つ
+    pub fn read(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<image::RgbaImage> {
+        let bytes_per_pixel = 4;
+        let unpadded = self.width * bytes_per_pixel;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded = (unpadded + align - 1) / align * align;
+
+        let buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Offscreen Read Buffer"),
+                size: (padded * self.height) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+                mapped_at_creation: false,
+            }
+        );
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Offscreen Read Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: padded,
+                    rows_per_image: self.height,
+                },
+            },
+            wgpu::Extent3d { width: self.width, height: self.height, depth: 1 },
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapping)?;
+
+        let bgra = matches!(
+            self.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        );
+        let mut pixels = Vec::with_capacity((unpadded * self.height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            // 行末の詰め物を取り除く
+            for row in data.chunks(padded as usize) {
+                for px in row[..unpadded as usize].chunks(bytes_per_pixel as usize) {
+                    if bgra {
+                        pixels.extend_from_slice(&[px[2], px[1], px[0], px[3]]);
+                    } else {
+                        pixels.extend_from_slice(px);
+                    }
+                }
+            }
+        }
+        buffer.unmap();
+
+        image::RgbaImage::from_raw(self.width, self.height, pixels)
+            .context("Offscreen image size mismatch")
+    }
+}
+
+// ファイル名用の時刻 (UNIX 時間のミリ秒)
+pub fn timestamp() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ndc(width: u32, height: u32, x: f32, y: f32) -> Vector4<f32> {
+        Vector4::new(x / width as f32 * 2.0 - 1.0, 1.0 - y / height as f32 * 2.0, 0.0, 1.0)
+    }
+
+    fn assert_near(a: Vector4<f32>, b: Vector4<f32>) {
+        assert!((a - b).magnitude() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn single_tile_is_identity() {
+        let tiles = tiles(640, 480, 640, 480);
+        assert_eq!(tiles.len(), 1);
+        assert_eq!((tiles[0].x, tiles[0].y), (0, 0));
+        assert_eq!(tiles[0].crop, Tile::full().crop);
+        assert_eq!(tiles[0].viewport, Tile::full().viewport);
+    }
+
+    #[test]
+    fn tiles_cover_the_image() {
+        let tiles = tiles(5000, 3000, TILE_SIZE, TILE_SIZE);
+        let origins = tiles.iter().map(|t| (t.x, t.y)).collect::<Vec<_>>();
+        assert_eq!(origins, vec![(0, 0), (2048, 0), (4096, 0), (0, 2048), (2048, 2048), (4096, 2048)]);
+
+        let last = tiles.last().unwrap();
+        assert_eq!(last.viewport, [4096.0 / 5000.0, 2048.0 / 3000.0, 2048.0 / 5000.0, 2048.0 / 3000.0]);
+    }
+
+    #[test]
+    fn crop_maps_tile_corners_to_ndc_corners() {
+        let (width, height) = (5000, 3000);
+        for tile in tiles(width, height, TILE_SIZE, TILE_SIZE).iter() {
+            let (x, y) = (tile.x as f32, tile.y as f32);
+            let size = TILE_SIZE as f32;
+            assert_near(tile.crop * ndc(width, height, x, y), Vector4::new(-1.0, 1.0, 0.0, 1.0));
+            assert_near(
+                tile.crop * ndc(width, height, x + size, y + size),
+                Vector4::new(1.0, -1.0, 0.0, 1.0),
+            );
+        }
+    }
+}
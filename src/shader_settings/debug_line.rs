@@ -0,0 +1,298 @@
+use crate::shader_settings::texture;
+use cgmath::*;
+
+// デバッグ表示用の線分
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct LineVertex {
+    position: [f32; 3],
+    color: [f32; 3],
+}
+
+unsafe impl bytemuck::Pod for LineVertex {}
+unsafe impl bytemuck::Zeroable for LineVertex {}
+
+impl LineVertex {
+    pub fn new(position: Vector3<f32>, color: Vector3<f32>) -> Self {
+        Self {
+            position: position.into(),
+            color: color.into(),
+        }
+    }
+
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<LineVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float3,
+                },
+            ],
+        }
+    }
+}
+
+// 線分を積んでおくためのヘルパ
+#[derive(Default)]
+pub struct LineList {
+    pub vertices: Vec<LineVertex>,
+}
+
+impl LineList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+
+    pub fn line(&mut self, from: Vector3<f32>, to: Vector3<f32>, color: Vector3<f32>) {
+        self.vertices.push(LineVertex::new(from, color));
+        self.vertices.push(LineVertex::new(to, color));
+    }
+
+    // axis に垂直な2軸を適当に選ぶ
+    fn basis(axis: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        let helper = if axis.y.abs() < 0.99 {
+            Vector3::unit_y()
+        } else {
+            Vector3::unit_x()
+        };
+        let u = axis.cross(helper).normalize();
+        let v = axis.cross(u).normalize();
+        (u, v)
+    }
+
+    pub fn circle(
+        &mut self,
+        center: Vector3<f32>,
+        axis: Vector3<f32>,
+        radius: f32,
+        color: Vector3<f32>,
+        segments: usize,
+    ) {
+        let (u, v) = Self::basis(axis.normalize());
+        let point = |i: usize| {
+            let t = i as f32 / segments as f32 * std::f32::consts::PI * 2.0;
+            center + (u * t.cos() + v * t.sin()) * radius
+        };
+        for i in 0..segments {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    // apex から dir 方向に開いた、半角の cos が limitcos の円錐
+    pub fn cone(
+        &mut self,
+        apex: Vector3<f32>,
+        dir: Vector3<f32>,
+        limitcos: f32,
+        length: f32,
+        color: Vector3<f32>,
+    ) {
+        let dir = dir.normalize();
+        let angle = limitcos.max(-1.0).min(1.0).acos();
+        let height = length * angle.cos();
+        let radius = length * angle.sin();
+        let center = apex + dir * height;
+
+        const SEGMENTS: usize = 24;
+        self.circle(center, dir, radius, color, SEGMENTS);
+
+        let (u, v) = Self::basis(dir);
+        for rim in [u, v, -u, -v].iter() {
+            self.line(apex, center + rim * radius, color);
+        }
+    }
+
+    pub fn arrow(&mut self, from: Vector3<f32>, dir: Vector3<f32>, length: f32, color: Vector3<f32>) {
+        let dir = dir.normalize();
+        let to = from + dir * length;
+        self.line(from, to, color);
+
+        let head = length * 0.2;
+        let (u, v) = Self::basis(dir);
+        for side in [u, v, -u, -v].iter() {
+            self.line(to, to - dir * head + side * head * 0.5, color);
+        }
+    }
+
+    // view_proj の逆行列から視錐台の8頂点を求めて12辺を引く
+    pub fn frustum(&mut self, view_proj: Matrix4<f32>, color: Vector3<f32>) {
+        let inv = match view_proj.invert() {
+            Some(m) => m,
+            None => return,
+        };
+
+        let mut corners = [Vector3::zero(); 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let x = if i & 1 == 0 { -1.0 } else { 1.0 };
+            let y = if i & 2 == 0 { -1.0 } else { 1.0 };
+            // wgpu の深度は [0, 1]
+            let z = if i & 4 == 0 { 0.0 } else { 1.0 };
+            let p = inv * Vector4::new(x, y, z, 1.0);
+            *corner = p.truncate() / p.w;
+        }
+
+        const EDGES: [(usize, usize); 12] = [
+            (0, 1), (2, 3), (4, 5), (6, 7),
+            (0, 2), (1, 3), (4, 6), (5, 7),
+            (0, 4), (1, 5), (2, 6), (3, 7),
+        ];
+        for &(a, b) in EDGES.iter() {
+            self.line(corners[a], corners[b], color);
+        }
+    }
+}
+
+pub struct LineRenderer {
+    pipeline: wgpu::RenderPipeline,
+    buffer: wgpu::Buffer,
+    capacity: usize,
+    len: u32,
+}
+
+impl LineRenderer {
+    const INITIAL_CAPACITY: usize = 256;
+
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+        sample_count: u32,
+    ) -> Self {
+        let pipeline = Self::create_pipeline(device, format, uniform_layout, sample_count);
+
+        let capacity = Self::INITIAL_CAPACITY;
+        let buffer = Self::create_buffer(device, capacity);
+
+        Self {
+            pipeline,
+            buffer,
+            capacity,
+            len: 0,
+        }
+    }
+
+    // 描き込む先のサンプル数が変わったら作り直す
+    pub fn set_sample_count(
+        &mut self,
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+        sample_count: u32,
+    ) {
+        self.pipeline = Self::create_pipeline(device, format, uniform_layout, sample_count);
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+        sample_count: u32,
+    ) -> wgpu::RenderPipeline {
+        let layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Debug Line Pipeline Layout"),
+                bind_group_layouts: &[uniform_layout],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let vs_module = device.create_shader_module(wgpu::include_spirv!("../debug_line.vert.spv"));
+        let fs_module = device.create_shader_module(wgpu::include_spirv!("../debug_line.frag.spv"));
+
+        device.create_render_pipeline(
+            &wgpu::RenderPipelineDescriptor {
+                label: Some("Debug Line Pipeline"),
+                layout: Some(&layout),
+                vertex_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &vs_module,
+                    entry_point: "main",
+                },
+                fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                    module: &fs_module,
+                    entry_point: "main",
+                }),
+                rasterization_state: Some(
+                    wgpu::RasterizationStateDescriptor {
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: wgpu::CullMode::None,
+                        depth_bias: 0,
+                        depth_bias_slope_scale: 0.0,
+                        depth_bias_clamp: 0.0,
+                        clamp_depth: false,
+                    }
+                ),
+                color_states: &[
+                    wgpu::ColorStateDescriptor {
+                        format,
+                        color_blend: wgpu::BlendDescriptor::REPLACE,
+                        alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                        write_mask: wgpu::ColorWrite::ALL,
+                    },
+                ],
+                primitive_topology: wgpu::PrimitiveTopology::LineList,
+                // 隠れた線は描かないが、線自体は深度を書かない
+                depth_stencil_state: Some(
+                    wgpu::DepthStencilStateDescriptor {
+                        format: texture::Texture::DEPTH_FORMAT,
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Less,
+                        stencil: wgpu::StencilStateDescriptor::default(),
+                    }
+                ),
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: wgpu::IndexFormat::Uint32,
+                    vertex_buffers: &[LineVertex::desc()],
+                },
+                sample_count,
+                sample_mask: !0,
+                alpha_to_coverage_enabled: false,
+            }
+        )
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Debug Line Buffer"),
+                size: (capacity * std::mem::size_of::<LineVertex>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        )
+    }
+
+    // 足りなければバッファを作り直す
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, lines: &LineList) {
+        let vertices = &lines.vertices;
+        if vertices.len() > self.capacity {
+            self.capacity = vertices.len().next_power_of_two();
+            self.buffer = Self::create_buffer(device, self.capacity);
+        }
+        if !vertices.is_empty() {
+            queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(vertices));
+        }
+        self.len = vertices.len() as u32;
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, uni_bg: &'a wgpu::BindGroup) {
+        if self.len == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, uni_bg, &[]);
+        render_pass.set_vertex_buffer(0, self.buffer.slice(..));
+        render_pass.draw(0..self.len, 0..1);
+    }
+}
@@ -0,0 +1,665 @@
+use crate::shader_settings::hdr::{self, HDR_FORMAT};
+use crate::shader_settings::texture;
+use anyhow::*;
+use std::num::NonZeroU32;
+use std::path::Path;
+use wgpu::util::DeviceExt;
+
+// 背景の一辺。ミップは 1x1 まで作る
+const ENVIRONMENT_SIZE: u32 = 512;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+// 粗さ 0, 0.25, 0.5, 0.75, 1 の5段
+const PREFILTERED_LEVELS: u32 = 5;
+const PREFILTER_SAMPLES: u32 = 512;
+const BRDF_LUT_SIZE: u32 = 256;
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+// 1枚のテクスチャに載せられる正距円筒図の最大幅
+const MAX_EQUIRECT_WIDTH: usize = 8192;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct EnvironmentUniform {
+    intensity: f32,
+    prefiltered_max_lod: f32,
+    loaded: u32,
+    skybox_lod: f32,
+}
+
+unsafe impl bytemuck::Pod for EnvironmentUniform {}
+unsafe impl bytemuck::Zeroable for EnvironmentUniform {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct FaceUniform {
+    face: u32,
+    lod: f32,
+}
+
+unsafe impl bytemuck::Pod for FaceUniform {}
+unsafe impl bytemuck::Zeroable for FaceUniform {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct FilterUniform {
+    face: u32,
+    mode: u32,
+    roughness: f32,
+    samples: u32,
+    env_size: f32,
+    lod: f32,
+    _padding: [f32; 2],
+}
+
+unsafe impl bytemuck::Pod for FilterUniform {}
+unsafe impl bytemuck::Zeroable for FilterUniform {}
+
+// 周囲の HDR 画像。背景に描き、放射照度と鏡面反射の前計算でモデルを照らす
+pub struct Environment {
+    // 背景と環境光の両方に掛ける
+    pub intensity: f32,
+    pub show_skybox: bool,
+    // 背景をぼかして描くときのミップ
+    pub skybox_lod: f32,
+    loaded: bool,
+    pub environment: texture::Texture,
+    pub irradiance: texture::Texture,
+    pub prefiltered: texture::Texture,
+    pub brdf_lut: texture::Texture,
+    // ミップを補間して読む
+    pub sampler: wgpu::Sampler,
+    pub uniform_buffer: wgpu::Buffer,
+    equirect_layout: wgpu::BindGroupLayout,
+    filter_layout: wgpu::BindGroupLayout,
+    equirect_pipeline: wgpu::RenderPipeline,
+    filter_pipeline: wgpu::RenderPipeline,
+}
+
+impl Environment {
+    // 読み込むまでは真っ黒で、環境光も背景も無い
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let environment = create_cube_texture(device, 1, 1, "environment_texture");
+        let irradiance = create_cube_texture(device, 1, 1, "irradiance_texture");
+        let prefiltered = create_cube_texture(device, 1, 1, "prefiltered_texture");
+        let brdf_lut = texture::Texture::create_render_texture(
+            device,
+            BRDF_LUT_SIZE,
+            BRDF_LUT_SIZE,
+            BRDF_LUT_FORMAT,
+            "brdf_lut_texture",
+        );
+
+        let sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                lod_min_clamp: 0.0,
+                lod_max_clamp: 100.0,
+                ..Default::default()
+            }
+        );
+
+        let uniform_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Environment Uniform Buffer"),
+                size: std::mem::size_of::<EnvironmentUniform>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+
+        let source_layout = |dimension, label| device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some(label),
+            }
+        );
+        let equirect_layout = source_layout(wgpu::TextureViewDimension::D2, "equirect_bind_group_layout");
+        let filter_layout = source_layout(wgpu::TextureViewDimension::Cube, "ibl_filter_bind_group_layout");
+
+        let equirect_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "Equirect To Cube Pipeline",
+            &[&equirect_layout],
+            wgpu::include_spirv!("../equirect_to_cube.frag.spv"),
+            HDR_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+        let filter_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "IBL Filter Pipeline",
+            &[&filter_layout],
+            wgpu::include_spirv!("../ibl_filter.frag.spv"),
+            HDR_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+
+        // BRDF の表は画像によらないので最初に一度だけ作る
+        let brdf_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "BRDF LUT Pipeline",
+            &[],
+            wgpu::include_spirv!("../brdf_lut.frag.spv"),
+            BRDF_LUT_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("BRDF LUT Encoder"),
+        });
+        {
+            let mut render_pass = begin_pass(&mut encoder, &brdf_lut.view);
+            render_pass.set_pipeline(&brdf_pipeline);
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let env = Self {
+            intensity: 1.0,
+            show_skybox: true,
+            skybox_lod: 0.0,
+            loaded: false,
+            environment,
+            irradiance,
+            prefiltered,
+            brdf_lut,
+            sampler,
+            uniform_buffer,
+            equirect_layout,
+            filter_layout,
+            equirect_pipeline,
+            filter_pipeline,
+        };
+        env.write_uniform(queue);
+        env
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    // 背景を描くかどうか
+    pub fn skybox_visible(&self) -> bool {
+        self.loaded && self.show_skybox
+    }
+
+    // intensity などを変えたら呼ぶ
+    pub fn write_uniform(&self, queue: &wgpu::Queue) {
+        let uniform = EnvironmentUniform {
+            intensity: self.intensity,
+            prefiltered_max_lod: (PREFILTERED_LEVELS - 1) as f32,
+            loaded: self.loaded as u32,
+            skybox_lod: self.skybox_lod,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    // 正距円筒図法の .hdr を読み込んでキューブマップと前計算を作り直す。
+    // テクスチャが変わるので、呼んだ後はシーンのバインドグループを作り直すこと
+    pub fn load<P: AsRef<Path>>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let (width, height, pixels) = load_equirect(path)?;
+        let equirect = create_equirect_texture(device, queue, width, height, pixels);
+
+        let env_levels = mip_levels(ENVIRONMENT_SIZE);
+        let environment = create_cube_texture(device, ENVIRONMENT_SIZE, env_levels, "environment_texture");
+        let irradiance = create_cube_texture(device, IRRADIANCE_SIZE, 1, "irradiance_texture");
+        let prefiltered = create_cube_texture(device, PREFILTERED_SIZE, PREFILTERED_LEVELS, "prefiltered_texture");
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+
+        // キューブマップの各ミップを正距円筒図から直接作る。
+        // 描き込み中のテクスチャを読まずに済み、ミップごとに画像の細かさを揃えられる
+        for level in 0..env_levels {
+            let size = (ENVIRONMENT_SIZE >> level).max(1);
+            let lod = (width as f32 / (4.0 * size as f32)).log2().max(0.0);
+            for face in 0..6 {
+                let uniform = FaceUniform { face, lod };
+                let bind_group = self.create_source_bind_group(
+                    device,
+                    &self.equirect_layout,
+                    &equirect,
+                    bytemuck::cast_slice(&[uniform]),
+                );
+                let view = face_view(&environment.texture, face, level);
+                let mut render_pass = begin_pass(&mut encoder, &view);
+                render_pass.set_pipeline(&self.equirect_pipeline);
+                render_pass.set_bind_group(0, &bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+        }
+
+        // 放射照度は 16x16 程度のミップを読めば十分に滑らか
+        let irradiance_lod = (ENVIRONMENT_SIZE as f32 / 16.0).log2();
+        let passes = std::iter::once((&irradiance, 0, 0, 0.0))
+            .chain((0..PREFILTERED_LEVELS).map(|level| {
+                let roughness = level as f32 / (PREFILTERED_LEVELS - 1) as f32;
+                (&prefiltered, level, 1, roughness)
+            }));
+        for (target, level, mode, roughness) in passes {
+            for face in 0..6 {
+                let uniform = FilterUniform {
+                    face,
+                    mode,
+                    roughness,
+                    samples: PREFILTER_SAMPLES,
+                    env_size: ENVIRONMENT_SIZE as f32,
+                    lod: irradiance_lod,
+                    _padding: [0.0; 2],
+                };
+                let bind_group = self.create_source_bind_group(
+                    device,
+                    &self.filter_layout,
+                    &environment,
+                    bytemuck::cast_slice(&[uniform]),
+                );
+                let view = face_view(&target.texture, face, level);
+                let mut render_pass = begin_pass(&mut encoder, &view);
+                render_pass.set_pipeline(&self.filter_pipeline);
+                render_pass.set_bind_group(0, &bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        self.environment = environment;
+        self.irradiance = irradiance;
+        self.prefiltered = prefiltered;
+        self.loaded = true;
+        self.write_uniform(queue);
+        Ok(())
+    }
+
+    pub fn clear(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.environment = create_cube_texture(device, 1, 1, "environment_texture");
+        self.irradiance = create_cube_texture(device, 1, 1, "irradiance_texture");
+        self.prefiltered = create_cube_texture(device, 1, 1, "prefiltered_texture");
+        self.loaded = false;
+        self.write_uniform(queue);
+    }
+
+    // 前計算のパスごとに面の番号などを変えるので、その都度ユニフォームを作る
+    fn create_source_bind_group(
+        &self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        source: &texture::Texture,
+        uniform: &[u8],
+    ) -> wgpu::BindGroup {
+        let buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Environment Pass Uniform Buffer"),
+                contents: uniform,
+                usage: wgpu::BufferUsage::UNIFORM,
+            }
+        );
+        device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Buffer(buffer.slice(..)),
+                    },
+                ],
+                label: Some("environment_pass_bind_group"),
+            }
+        )
+    }
+}
+
+fn begin_pass<'a>(
+    encoder: &'a mut wgpu::CommandEncoder,
+    view: &'a wgpu::TextureView,
+) -> wgpu::RenderPass<'a> {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        color_attachments: &[
+            wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                }
+            }
+        ],
+        depth_stencil_attachment: None,
+    })
+}
+
+fn mip_levels(size: u32) -> u32 {
+    32 - size.max(1).leading_zeros()
+}
+
+// 面を1枚ずつ描き込むための view
+fn face_view(texture: &wgpu::Texture, face: u32, level: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        label: Some("cube_face"),
+        format: None,
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        aspect: wgpu::TextureAspect::All,
+        base_mip_level: level,
+        level_count: NonZeroU32::new(1),
+        base_array_layer: face,
+        array_layer_count: NonZeroU32::new(1),
+    })
+}
+
+fn create_cube_texture(device: &wgpu::Device, size: u32, levels: u32, label: &str) -> texture::Texture {
+    let texture = device.create_texture(
+        &wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d { width: size, height: size, depth: 6 },
+            mip_level_count: levels,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        }
+    );
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        label: Some(label),
+        format: None,
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        aspect: wgpu::TextureAspect::All,
+        base_mip_level: 0,
+        level_count: None,
+        base_array_layer: 0,
+        array_layer_count: NonZeroU32::new(6),
+    });
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+    texture::Texture { texture, view, sampler }
+}
+
+// 正距円筒図の RGBA (f32)。.hdr は image、.exr は exr クレートで読む
+fn load_equirect(path: &Path) -> Result<(usize, usize, Vec<[f32; 4]>)> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "hdr" => {
+            let file = std::fs::File::open(path)
+                .with_context(|| format!("Cannot read {:?}", path))?;
+            let decoder = image::codecs::hdr::HdrDecoder::new(std::io::BufReader::new(file))
+                .with_context(|| format!("Invalid HDR image {:?}", path))?;
+            let meta = decoder.metadata();
+            let pixels = decoder
+                .read_image_hdr()
+                .with_context(|| format!("Invalid HDR image {:?}", path))?
+                .into_iter()
+                .map(|p| [p[0], p[1], p[2], 1.0])
+                .collect();
+            Ok((meta.width as usize, meta.height as usize, pixels))
+        }
+        "exr" => {
+            // 最初のレイヤーの RGBA を読む。A が無ければ 1 になる
+            let image = exr::prelude::read_first_rgba_layer_from_file(
+                path,
+                |resolution, _| (resolution.width(), vec![[0.0, 0.0, 0.0, 1.0]; resolution.width() * resolution.height()]),
+                |(width, pixels), position, (r, g, b, a): (f32, f32, f32, f32)| {
+                    pixels[position.y() * *width + position.x()] = [r, g, b, a];
+                },
+            )
+            .with_context(|| format!("Invalid OpenEXR image {:?}", path))?;
+            let size = image.layer_data.size;
+            let (_, pixels) = image.layer_data.channel_data.pixels;
+            Ok((size.width(), size.height(), pixels))
+        }
+        _ => bail!("Unsupported environment image: {:?}", path),
+    }
+}
+
+// ミップを全て付けてアップロードする。大きすぎる画像は縮めてから載せる
+fn create_equirect_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mut width: usize,
+    mut height: usize,
+    mut pixels: Vec<[f32; 4]>,
+) -> texture::Texture {
+    while width > MAX_EQUIRECT_WIDTH {
+        let (w, h, p) = downsample(width, height, &pixels);
+        width = w;
+        height = h;
+        pixels = p;
+    }
+
+    let levels = mip_levels(width.max(height) as u32);
+    let texture = device.create_texture(
+        &wgpu::TextureDescriptor {
+            label: Some("equirect_texture"),
+            size: wgpu::Extent3d { width: width as u32, height: height as u32, depth: 1 },
+            mip_level_count: levels,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        }
+    );
+
+    for level in 0..levels {
+        let data = pixels
+            .iter()
+            .flat_map(|p| p.iter().map(|&v| f32_to_f16(v)))
+            .collect::<Vec<u16>>();
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture: &texture,
+                mip_level: level,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(&data),
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 8 * width as u32,
+                rows_per_image: height as u32,
+            },
+            wgpu::Extent3d { width: width as u32, height: height as u32, depth: 1 },
+        );
+        if level + 1 < levels {
+            let (w, h, p) = downsample(width, height, &pixels);
+            width = w;
+            height = h;
+            pixels = p;
+        }
+    }
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+    texture::Texture { texture, view, sampler }
+}
+
+// 縦横半分 (最小 1) に 2x2 の平均で縮める
+fn downsample(width: usize, height: usize, pixels: &[[f32; 4]]) -> (usize, usize, Vec<[f32; 4]>) {
+    let (w, h) = ((width / 2).max(1), (height / 2).max(1));
+    let mut out = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            let mut sum = [0.0; 4];
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                let sx = (x * 2 + dx).min(width - 1);
+                let sy = (y * 2 + dy).min(height - 1);
+                let p = pixels[sy * width + sx];
+                for (s, v) in sum.iter_mut().zip(p.iter()) {
+                    *s += v * 0.25;
+                }
+            }
+            out.push(sum);
+        }
+    }
+    (w, h, out)
+}
+
+// Rgba16Float に載せるための変換。丸めは切り捨て
+fn f32_to_f16(v: f32) -> u16 {
+    if v.is_nan() {
+        return 0x7e00;
+    }
+    // 表せる最大値で止める
+    let bits = v.max(-65504.0).min(65504.0).to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if exp <= 0 {
+        // 非正規化数。小さすぎれば 0
+        if exp < -10 {
+            return sign;
+        }
+        let m = (mantissa | 0x80_0000) >> (14 - exp) as u32;
+        return sign | m as u16;
+    }
+    sign | ((exp as u16) << 10) | (mantissa >> 13) as u16
+}
+
+// シーンの最初に描く背景。layout の set 0 にはシーンの uniform のバインドグループを置く。
+// 深度は見ずに書き込みもしないので、後から描くモデルが必ず手前に来る
+pub fn create_skybox_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    let vs_module = device.create_shader_module(wgpu::include_spirv!("../fullscreen.vert.spv"));
+    let fs_module = device.create_shader_module(wgpu::include_spirv!("../skybox.frag.spv"));
+
+    device.create_render_pipeline(
+        &wgpu::RenderPipelineDescriptor {
+            label: Some("Skybox Pipeline"),
+            layout: Some(layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &vs_module,
+                entry_point: "main",
+            },
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: &fs_module,
+                entry_point: "main",
+            }),
+            rasterization_state: Some(
+                wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::None,
+                    depth_bias: 0,
+                    depth_bias_slope_scale: 0.0,
+                    depth_bias_clamp: 0.0,
+                    clamp_depth: false,
+                }
+            ),
+            color_states: &[
+                wgpu::ColorStateDescriptor {
+                    format,
+                    color_blend: wgpu::BlendDescriptor::REPLACE,
+                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                },
+            ],
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            depth_stencil_state: Some(
+                wgpu::DepthStencilStateDescriptor {
+                    format: texture::Texture::DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare: wgpu::CompareFunction::Always,
+                    stencil: wgpu::StencilStateDescriptor::default(),
+                }
+            ),
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count,
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f32_to_f16_normal_values() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(0.5), 0x3800);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-10)), 0x3c01);
+    }
+
+    #[test]
+    fn f32_to_f16_truncates() {
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_f16(2.0 - 2f32.powi(-12)), 0x3fff);
+    }
+
+    #[test]
+    fn f32_to_f16_clamps_out_of_range() {
+        assert_eq!(f32_to_f16(1.0e6), 0x7bff);
+        assert_eq!(f32_to_f16(-1.0e6), 0xfbff);
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7bff);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfbff);
+        assert_eq!(f32_to_f16(f32::NAN), 0x7e00);
+    }
+
+    #[test]
+    fn f32_to_f16_subnormals() {
+        // 正規化数の最小値と、その下の非正規化数
+        assert_eq!(f32_to_f16(2f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_f16(2f32.powi(-15)), 0x0200);
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(-2f32.powi(-24)), 0x8001);
+        assert_eq!(f32_to_f16(1.0e-10), 0x0000);
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::*;
+use std::path::Path;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct FogUniform {
+    color: [f32; 3],
+    density: f32,
+    start: f32,
+    height_density: f32,
+    height_falloff: f32,
+    base_height: f32,
+    enabled: u32,
+    use_horizon: u32,
+    _padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for FogUniform {}
+unsafe impl bytemuck::Zeroable for FogUniform {}
+
+// 距離による霧と、低いところほど濃い高さの霧。
+// 値を変えたら write_uniform で GPU に送る
+pub struct Fog {
+    pub enabled: bool,
+    // 線形の色
+    pub color: [f32; 3],
+    // 距離の霧の濃さ (1/m)
+    pub density: f32,
+    // これより近くには霧を掛けない
+    pub start: f32,
+    // base_height での高さの霧の濃さ
+    pub height_density: f32,
+    // 高さ 1 上がるごとの減衰の強さ
+    pub height_falloff: f32,
+    pub base_height: f32,
+    // 環境マップがあれば、霧の色を地平線あたりの空の色にする
+    pub use_horizon: bool,
+    pub buffer: wgpu::Buffer,
+}
+
+impl Fog {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Fog Uniform Buffer"),
+                size: std::mem::size_of::<FogUniform>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let fog = Self {
+            enabled: false,
+            color: [0.75, 0.8, 0.88],
+            density: 0.02,
+            start: 5.0,
+            height_density: 0.05,
+            height_falloff: 0.5,
+            base_height: 0.0,
+            use_horizon: false,
+            buffer,
+        };
+        fog.write_uniform(queue);
+        fog
+    }
+
+    pub fn write_uniform(&self, queue: &wgpu::Queue) {
+        let uniform = FogUniform {
+            color: self.color,
+            density: self.density.max(0.0),
+            start: self.start.max(0.0),
+            height_density: self.height_density.max(0.0),
+            height_falloff: self.height_falloff.max(0.0),
+            base_height: self.base_height,
+            enabled: self.enabled as u32,
+            use_horizon: self.use_horizon as u32,
+            _padding: [0; 2],
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    // 設定ファイルの書式 (1行1つ、# 以降はコメント)
+    //
+    //   enable / disable
+    //   color <r> <g> <b>
+    //   distance <density> <start>
+    //   height <density> <falloff> <base_height>
+    //   horizon on|off                    霧の色を空の地平線の色にする
+    pub fn load<P: AsRef<Path>>(&mut self, queue: &wgpu::Queue, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read {:?}", path))?;
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let err = || format!("line {}: {}", n + 1, line);
+            let floats = |count: usize| -> Result<Vec<f32>> {
+                if words.len() != count + 1 {
+                    bail!("Expected {} values: {}", count, err());
+                }
+                words[1..]
+                    .iter()
+                    .map(|w| w.parse::<f32>().with_context(err))
+                    .collect()
+            };
+            match words[0] {
+                "enable" | "disable" => self.enabled = words[0] == "enable",
+                "color" => {
+                    let v = floats(3)?;
+                    self.color = [v[0], v[1], v[2]];
+                }
+                "distance" => {
+                    let v = floats(2)?;
+                    self.density = v[0];
+                    self.start = v[1];
+                }
+                "height" => {
+                    let v = floats(3)?;
+                    self.height_density = v[0];
+                    self.height_falloff = v[1];
+                    self.base_height = v[2];
+                }
+                "horizon" => {
+                    self.use_horizon = match words.get(1) {
+                        Some(&"on") => true,
+                        Some(&"off") => false,
+                        _ => bail!("Expected on or off: {}", err()),
+                    };
+                }
+                _ => bail!("Unknown directive: {}", err()),
+            }
+        }
+        self.write_uniform(queue);
+        Ok(())
+    }
+}
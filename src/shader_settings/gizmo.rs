@@ -0,0 +1,307 @@
+use crate::shader_settings::texture;
+use crate::shader_settings::light::Light;
+use crate::shader_settings::debug_line::{LineList, LineRenderer};
+use cgmath::*;
+use wgpu::util::DeviceExt;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct BillboardRaw {
+    position: [f32; 3],
+    color: [f32; 3],
+}
+
+unsafe impl bytemuck::Pod for BillboardRaw {}
+unsafe impl bytemuck::Zeroable for BillboardRaw {}
+
+impl BillboardRaw {
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<BillboardRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float3,
+                },
+            ],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct GizmoUniform {
+    scale: [f32; 2],
+}
+
+unsafe impl bytemuck::Pod for GizmoUniform {}
+unsafe impl bytemuck::Zeroable for GizmoUniform {}
+
+// light_book の各光源の位置・向きを可視化する
+pub struct LightGizmo {
+    pub enabled: bool,
+    // 円錐や矢印の長さ
+    pub length: f32,
+    // ビルボードの画面上の半径 (画面の高さに対する割合)
+    pub billboard_size: f32,
+    aspect: f32,
+    uniform_buffer: wgpu::Buffer,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    billboard_pipeline: wgpu::RenderPipeline,
+    billboard_buffer: wgpu::Buffer,
+    billboard_num: u32,
+    lines: LineList,
+    line_renderer: LineRenderer,
+}
+
+impl LightGizmo {
+    pub fn new(
+        device: &wgpu::Device,
+        sc_desc: &wgpu::SwapChainDescriptor,
+        // シーンを描き込むテクスチャの形式
+        format: wgpu::TextureFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+        sample_count: u32,
+    ) -> Self {
+        let billboard_size = 0.02;
+        let aspect = sc_desc.width as f32 / sc_desc.height as f32;
+        let uniform = GizmoUniform {
+            scale: [billboard_size / aspect, billboard_size],
+        };
+
+        let uniform_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Gizmo Uniform Buffer"),
+                contents: bytemuck::cast_slice(&[uniform]),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            }
+        );
+
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("gizmo_bind_group_layout"),
+            }
+        );
+
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                    },
+                ],
+                label: Some("gizmo_bind_group"),
+            }
+        );
+
+        let billboard_pipeline = Self::create_billboard_pipeline(
+            device,
+            format,
+            uniform_layout,
+            &layout,
+            sample_count,
+        );
+
+        let billboard_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Gizmo Billboard Buffer"),
+                size: (texture::Texture::MAXLIGHTS * std::mem::size_of::<BillboardRaw>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+
+        let line_renderer = LineRenderer::new(device, format, uniform_layout, sample_count);
+
+        Self {
+            enabled: false,
+            length: 1.0,
+            billboard_size,
+            aspect,
+            uniform_buffer,
+            layout,
+            bind_group,
+            billboard_pipeline,
+            billboard_buffer,
+            billboard_num: 0,
+            lines: LineList::new(),
+            line_renderer,
+        }
+    }
+
+    // 描き込む先のサンプル数が変わったら作り直す
+    pub fn set_sample_count(
+        &mut self,
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+        sample_count: u32,
+    ) {
+        self.billboard_pipeline = Self::create_billboard_pipeline(
+            device,
+            format,
+            uniform_layout,
+            &self.layout,
+            sample_count,
+        );
+        self.line_renderer.set_sample_count(device, format, uniform_layout, sample_count);
+    }
+
+    fn create_billboard_pipeline(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+        gizmo_layout: &wgpu::BindGroupLayout,
+        sample_count: u32,
+    ) -> wgpu::RenderPipeline {
+        let pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Gizmo Billboard Pipeline Layout"),
+                bind_group_layouts: &[uniform_layout, gizmo_layout],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let vs_module = device.create_shader_module(wgpu::include_spirv!("../gizmo_billboard.vert.spv"));
+        let fs_module = device.create_shader_module(wgpu::include_spirv!("../gizmo_billboard.frag.spv"));
+
+        device.create_render_pipeline(
+            &wgpu::RenderPipelineDescriptor {
+                label: Some("Gizmo Billboard Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &vs_module,
+                    entry_point: "main",
+                },
+                fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                    module: &fs_module,
+                    entry_point: "main",
+                }),
+                rasterization_state: Some(
+                    wgpu::RasterizationStateDescriptor {
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: wgpu::CullMode::None,
+                        depth_bias: 0,
+                        depth_bias_slope_scale: 0.0,
+                        depth_bias_clamp: 0.0,
+                        clamp_depth: false,
+                    }
+                ),
+                color_states: &[
+                    wgpu::ColorStateDescriptor {
+                        format,
+                        color_blend: wgpu::BlendDescriptor::REPLACE,
+                        alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                        write_mask: wgpu::ColorWrite::ALL,
+                    },
+                ],
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                depth_stencil_state: Some(
+                    wgpu::DepthStencilStateDescriptor {
+                        format: texture::Texture::DEPTH_FORMAT,
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Less,
+                        stencil: wgpu::StencilStateDescriptor::default(),
+                    }
+                ),
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: wgpu::IndexFormat::Uint32,
+                    vertex_buffers: &[BillboardRaw::desc()],
+                },
+                sample_count,
+                sample_mask: !0,
+                alpha_to_coverage_enabled: false,
+            }
+        
+    }
+
+    pub fn resize(&mut self, queue: &wgpu::Queue, sc_desc: &wgpu::SwapChainDescriptor) {
+        self.aspect = sc_desc.width as f32 / sc_desc.height as f32;
+        self.write_uniform(queue);
+    }
+
+    fn write_uniform(&self, queue: &wgpu::Queue) {
+        let uniform = GizmoUniform {
+            scale: [self.billboard_size / self.aspect, self.billboard_size],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        lights: &[Rc<RefCell<Light>>],
+    ) {
+        self.lines.clear();
+        self.billboard_num = 0;
+        if !self.enabled {
+            return;
+        }
+
+        let mut billboards = Vec::with_capacity(lights.len());
+        for r_light in lights.iter() {
+            let light = r_light.borrow();
+            billboards.push(BillboardRaw {
+                position: light.position.into(),
+                color: light.color.into(),
+            });
+
+            if light.is_spotlight {
+                // 内側は光源色、外側は暗めにして区別する
+                self.lines.cone(light.position, light.limitdir, light.limitcos_inner, self.length, light.color);
+                self.lines.cone(light.position, light.limitdir, light.limitcos_outer, self.length, light.color * 0.5);
+            } else if light.shadow.direction.magnitude2() > 0.0 {
+                self.lines.arrow(light.position, light.shadow.direction, self.length, light.color);
+            }
+        }
+        billboards.truncate(texture::Texture::MAXLIGHTS);
+
+        if !billboards.is_empty() {
+            queue.write_buffer(&self.billboard_buffer, 0, bytemuck::cast_slice(&billboards));
+        }
+        self.billboard_num = billboards.len() as u32;
+        self.line_renderer.upload(device, queue, &self.lines);
+    }
+
+    // メインのレンダーパスの中で呼ぶ
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, uni_bg: &'a wgpu::BindGroup) {
+        if !self.enabled {
+            return;
+        }
+
+        self.line_renderer.draw(render_pass, uni_bg);
+
+        if self.billboard_num > 0 {
+            render_pass.set_pipeline(&self.billboard_pipeline);
+            render_pass.set_bind_group(0, uni_bg, &[]);
+            render_pass.set_bind_group(1, &self.bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.billboard_buffer.slice(..));
+            render_pass.draw(0..6, 0..self.billboard_num);
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use crate::shader_settings::texture;
+use crate::shader_settings::model::{Model, Mesh, Material, ModelVertex, MorphVertex, MAX_MORPH_TARGETS};
+use crate::shader_settings::skin::{Trs, Skeleton, SkeletonNode, NodeTracks, SkinClip};
+use crate::shader_settings::animation::{Easing, Keyframe, Track};
+use crate::shader_settings::bounds::Aabb;
+use anyhow::*;
+use std::path::*;
+
+use wgpu::util::DeviceExt;
+use cgmath::*;
+
+// glTF (.gltf / .glb) の読み込み
+// スケルトンはモデルごとに1つ (最初の skin) だけ扱う
+
+fn node_trs(node: &gltf::Node) -> Trs {
+    let (t, r, s) = node.transform().decomposed();
+    Trs {
+        translation: t.into(),
+        // glTF は xyzw の順
+        rotation: Quaternion::new(r[3], r[0], r[1], r[2]),
+        scale: s.into(),
+    }
+}
+
+fn load_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    data: &gltf::image::Data,
+    label: &str,
+) -> Result<texture::Texture> {
+    use gltf::image::Format;
+
+    let img = match data.format {
+        Format::R8G8B8A8 => image::RgbaImage::from_raw(data.width, data.height, data.pixels.clone())
+            .map(image::DynamicImage::ImageRgba8),
+        Format::R8G8B8 => image::RgbImage::from_raw(data.width, data.height, data.pixels.clone())
+            .map(image::DynamicImage::ImageRgb8),
+        f => bail!("Unsupported image format: {:?}", f),
+    }.context("Invalid image size")?;
+
+    texture::Texture::from_image(device, queue, &img, Some(label))
+}
+
+fn easing(interpolation: gltf::animation::Interpolation) -> Easing {
+    match interpolation {
+        gltf::animation::Interpolation::Step => Easing::Step,
+        // CUBICSPLINE は接線を捨てて線形で近似する
+        _ => Easing::Linear,
+    }
+}
+
+// CUBICSPLINE の出力は (in接線, 値, out接線) の組になっている
+fn keyframes<T: Copy>(
+    times: &[f32],
+    values: Vec<T>,
+    interpolation: gltf::animation::Interpolation,
+) -> Vec<Keyframe<T>> {
+    let stride = if interpolation == gltf::animation::Interpolation::CubicSpline { 3 } else { 1 };
+    let offset = stride / 2;
+    times
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &time)| values.get(i * stride + offset).map(|&value| Keyframe {
+            time,
+            value,
+            easing: easing(interpolation),
+        }))
+        .collect()
+}
+
+fn load_clips(document: &gltf::Document, buffers: &[gltf::buffer::Data]) -> Vec<SkinClip> {
+    use gltf::animation::util::ReadOutputs;
+
+    let mut clips = Vec::new();
+    for (i, anim) in document.animations().enumerate() {
+        let name = anim.name()
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| format!("animation{}", i));
+        let mut channels: Vec<(usize, NodeTracks)> = Vec::new();
+        let mut duration = 0.0f32;
+
+        for channel in anim.channels() {
+            let reader = channel.reader(|b| Some(&buffers[b.index()]));
+            let times = match reader.read_inputs() {
+                Some(t) => t.collect::<Vec<_>>(),
+                None => continue,
+            };
+            duration = times.iter().cloned().fold(duration, f32::max);
+            let interpolation = channel.sampler().interpolation();
+
+            let node = channel.target().node().index();
+            let index = match channels.iter().position(|(n, _)| *n == node) {
+                Some(i) => i,
+                None => {
+                    channels.push((node, NodeTracks::default()));
+                    channels.len() - 1
+                }
+            };
+            let tracks = &mut channels[index].1;
+
+            match reader.read_outputs() {
+                Some(ReadOutputs::Translations(v)) => {
+                    let values = v.map(Vector3::from).collect();
+                    tracks.translation = Some(Track::new(keyframes(&times, values, interpolation)));
+                }
+                Some(ReadOutputs::Rotations(r)) => {
+                    let values = r.into_f32().map(|q| Quaternion::new(q[3], q[0], q[1], q[2])).collect();
+                    tracks.rotation = Some(Track::new(keyframes(&times, values, interpolation)));
+                }
+                Some(ReadOutputs::Scales(v)) => {
+                    let values = v.map(Vector3::from).collect();
+                    tracks.scale = Some(Track::new(keyframes(&times, values, interpolation)));
+                }
+                _ => (),
+            }
+        }
+
+        clips.push(SkinClip { name, duration, channels });
+    }
+
+    clips
+}
+
+fn load_skeleton(document: &gltf::Document, buffers: &[gltf::buffer::Data]) -> Option<Skeleton> {
+    let skin = document.skins().next()?;
+
+    // ノード番号をそのままスケルトンのノード番号にする
+    let mut nodes = document.nodes()
+        .map(|n| SkeletonNode { parent: None, rest: node_trs(&n) })
+        .collect::<Vec<_>>();
+    for node in document.nodes() {
+        for child in node.children() {
+            nodes[child.index()].parent = Some(node.index());
+        }
+    }
+
+    let joints = skin.joints().map(|j| j.index()).collect::<Vec<_>>();
+    let reader = skin.reader(|b| Some(&buffers[b.index()]));
+    let inverse_binds = match reader.read_inverse_bind_matrices() {
+        Some(m) => m.map(Matrix4::from).collect(),
+        None => vec![Matrix4::identity(); joints.len()],
+    };
+
+    Some(Skeleton {
+        nodes,
+        joints,
+        inverse_binds,
+        clips: load_clips(document, buffers),
+    })
+}
+
+impl Model {
+    pub fn load_gltf<P: AsRef<Path>>(
+        id: usize,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: P,
+    ) -> Result<Self> {
+        let (document, buffers, images) = gltf::import(path.as_ref())
+            .with_context(|| format!("Failed to load {:?}", path.as_ref()))?;
+
+        let mut materials = Vec::new();
+        for (i, mat) in document.materials().enumerate() {
+            let name = mat.name()
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| format!("material{}", i));
+            let pbr = mat.pbr_metallic_roughness();
+            let texture = match pbr.base_color_texture() {
+                Some(info) => {
+                    let data = &images[info.texture().source().index()];
+                    Some(load_texture(device, queue, data, &name)?)
+                }
+                None => None,
+            };
+            let color = pbr.base_color_factor();
+            let color = Vector3::new(color[0], color[1], color[2]);
+            materials.push(Material::new(
+                device,
+                queue,
+                layout,
+                name,
+                texture,
+                color * 0.1,
+                color,
+                Vector3::new(0.5, 0.5, 0.5),
+            )?);
+        }
+        // マテリアル指定の無いプリミティブ用
+        let default_material = materials.len();
+        materials.push(Material::new(
+            device,
+            queue,
+            layout,
+            "default".to_string(),
+            None,
+            Vector3::new(0.1, 0.1, 0.1),
+            Vector3::new(0.8, 0.8, 0.8),
+            Vector3::new(0.5, 0.5, 0.5),
+        )?);
+
+        let skeleton = load_skeleton(&document, &buffers);
+
+        // スキンを持たないメッシュはノードの変換を頂点に焼き込む
+        let mut stack = document.default_scene()
+            .or_else(|| document.scenes().next())
+            .context("glTF has no scene")?
+            .nodes()
+            .map(|n| (n, Matrix4::<f32>::identity()))
+            .collect::<Vec<_>>();
+
+        let mut meshes = Vec::new();
+        let mut morph_weights = [0.0; MAX_MORPH_TARGETS];
+        while let Some((node, parent)) = stack.pop() {
+            let global = parent * node_trs(&node).to_matrix();
+            for child in node.children() {
+                stack.push((child, global));
+            }
+
+            let mesh = match node.mesh() {
+                Some(m) => m,
+                None => continue,
+            };
+            // 重みはインスタンスに1組しか無いので、全メッシュで共有する。ノードの指定を優先する
+            if let Some(weights) = node.weights().or_else(|| mesh.weights()) {
+                for (dst, src) in morph_weights.iter_mut().zip(weights) {
+                    *dst = *src;
+                }
+            }
+            // glTF の仕様上、スキンメッシュはノードの変換を無視する
+            let skinned = node.skin().is_some() && skeleton.is_some();
+            let transform = if skinned { Matrix4::identity() } else { global };
+            let normal_transform = Matrix3::from_cols(
+                transform.x.truncate(),
+                transform.y.truncate(),
+                transform.z.truncate(),
+            ).invert().unwrap_or(Matrix3::identity()).transpose();
+
+            for (p, primitive) in mesh.primitives().enumerate() {
+                let reader = primitive.reader(|b| Some(&buffers[b.index()]));
+                let positions = reader.read_positions()
+                    .context("Primitive has no positions")?
+                    .collect::<Vec<_>>();
+                let len = positions.len();
+                let normals = reader.read_normals()
+                    .map(|n| n.collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0.0, 1.0, 0.0]; len]);
+                // glTF の UV は左上原点なので OBJ と違って反転しない
+                let tex_coords = reader.read_tex_coords(0)
+                    .map(|t| t.into_f32().collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0.0, 0.0]; len]);
+                let (joints, weights) = if skinned {
+                    (
+                        reader.read_joints(0).map(|j| j.into_u16().collect::<Vec<_>>()),
+                        reader.read_weights(0).map(|w| w.into_f32().collect::<Vec<_>>()),
+                    )
+                } else {
+                    (None, None)
+                };
+
+                let mut vertices = Vec::with_capacity(len);
+                let mut aabb = Aabb::empty();
+                for i in 0..len {
+                    let position = transform.transform_point(Point3::from(positions[i]));
+                    aabb.extend(position);
+                    let normal = normal_transform * Vector3::from(normals[i]);
+                    let joint = joints.as_ref()
+                        .map(|j| {
+                            let j = j[i];
+                            [j[0] as u32, j[1] as u32, j[2] as u32, j[3] as u32]
+                        })
+                        .unwrap_or([0; 4]);
+                    let weight = weights.as_ref().map(|w| w[i]).unwrap_or([0.0; 4]);
+                    vertices.push(ModelVertex::new(
+                        position.into(),
+                        tex_coords[i],
+                        normal.normalize().into(),
+                        joint,
+                        weight,
+                    ));
+                }
+
+                // 上限を超えるターゲットは捨てる
+                let mut morphs = vec![bytemuck::Zeroable::zeroed(); len];
+                let mut morph_target_num = 0;
+                for (t, (d_positions, d_normals, _)) in reader.read_morph_targets()
+                    .take(MAX_MORPH_TARGETS)
+                    .enumerate()
+                {
+                    let morphs: &mut Vec<MorphVertex> = &mut morphs;
+                    if let Some(d) = d_positions {
+                        for (m, d) in morphs.iter_mut().zip(d) {
+                            m.position_deltas[t] = transform.transform_vector(Vector3::from(d)).into();
+                        }
+                    }
+                    if let Some(d) = d_normals {
+                        for (m, d) in morphs.iter_mut().zip(d) {
+                            m.normal_deltas[t] = (normal_transform * Vector3::from(d)).into();
+                        }
+                    }
+                    morph_target_num = t + 1;
+                }
+
+                let indices = match reader.read_indices() {
+                    Some(i) => i.into_u32().collect::<Vec<_>>(),
+                    None => (0..len as u32).collect(),
+                };
+
+                let name = format!("{}_{}", mesh.name().unwrap_or("mesh"), p);
+                let morph_buffer = Mesh::create_morph_buffer(device, &name, &morphs);
+                let vertex_buffer = device.create_buffer_init(
+                    &wgpu::util::BufferInitDescriptor {
+                        label: Some(&format!("{:?} Vertex Buffer", path.as_ref())),
+                        contents: bytemuck::cast_slice(&vertices),
+                        usage: wgpu::BufferUsage::VERTEX,
+                    }
+                );
+                let index_buffer = device.create_buffer_init(
+                    &wgpu::util::BufferInitDescriptor {
+                        label: Some(&format!("{:?} Index Buffer", path.as_ref())),
+                        contents: bytemuck::cast_slice(&indices),
+                        usage: wgpu::BufferUsage::INDEX,
+                    }
+                );
+
+                meshes.push(
+                    Mesh {
+                        name,
+                        vertex_buffer,
+                        index_buffer,
+                        num_elements: indices.len() as u32,
+                        material: primitive.material().index().unwrap_or(default_material),
+                        morph_buffer,
+                        morph_target_num,
+                        aabb,
+                    }
+                );
+            }
+        }
+
+        let mut model = Self::from_parts(id, meshes, materials, skeleton);
+        model.morph_weights = morph_weights;
+        Ok(model)
+    }
+}
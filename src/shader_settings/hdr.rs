@@ -0,0 +1,536 @@
+use crate::shader_settings::texture;
+use wgpu::util::DeviceExt;
+use anyhow::*;
+
+// シーンはこの形式に描き、トーンマップでスワップチェーンの形式に落とす
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tonemap {
+    Aces,
+    Reinhard,
+    // Hable (Uncharted 2)
+    Filmic,
+}
+
+impl Tonemap {
+    const ALL: &'static [(&'static str, Tonemap)] = &[
+        ("aces", Tonemap::Aces),
+        ("reinhard", Tonemap::Reinhard),
+        ("filmic", Tonemap::Filmic),
+    ];
+
+    pub fn parse(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, tonemap)| *tonemap)
+            .with_context(|| format!("Unknown tonemap operator: {}", s))
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            Tonemap::Aces => Tonemap::Reinhard,
+            Tonemap::Reinhard => Tonemap::Filmic,
+            Tonemap::Filmic => Tonemap::Aces,
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            Tonemap::Aces => 0,
+            Tonemap::Reinhard => 1,
+            Tonemap::Filmic => 2,
+        }
+    }
+}
+
+// 値はどちらも EV (2 の何乗倍するか)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exposure {
+    Manual(f32),
+    // ヒストグラムから求めた露出に補正を掛ける
+    Auto(f32),
+}
+
+// HDR の色 (MSAA ならその解決先) と深度をまとめたもの
+pub struct SceneTarget {
+    pub width: u32,
+    pub height: u32,
+    pub sample_count: u32,
+    pub color: texture::Texture,
+    msaa: Option<texture::Texture>,
+    pub depth: texture::Texture,
+}
+
+impl SceneTarget {
+    pub fn new(device: &wgpu::Device, width: u32, height: u32, sample_count: u32) -> Self {
+        let desc = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            format: HDR_FORMAT,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+
+        let color = texture::Texture::create_render_texture(device, width, height, HDR_FORMAT, "hdr_texture");
+
+        let msaa = if sample_count > 1 {
+            Some(texture::Texture::create_msaa_texture(device, &desc, sample_count, "hdr_msaa_texture"))
+        } else {
+            None
+        };
+        let depth = texture::Texture::create_depth_texture(device, &desc, sample_count, "depth_texture");
+
+        Self {
+            width,
+            height,
+            sample_count,
+            color,
+            msaa,
+            depth,
+        }
+    }
+
+    // 描き込む先と解決先
+    pub fn attachment(&self) -> (&wgpu::TextureView, Option<&wgpu::TextureView>) {
+        match self.msaa.as_ref() {
+            Some(msaa) => (&msaa.view, Some(&self.color.view)),
+            None => (&self.color.view, None),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct TonemapUniform {
+    operator: u32,
+    exposure: f32,
+    auto_exposure: u32,
+    white: f32,
+}
+
+unsafe impl bytemuck::Pod for TonemapUniform {}
+unsafe impl bytemuck::Zeroable for TonemapUniform {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct HistogramUniform {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    time_coeff: f32,
+    key: f32,
+}
+
+unsafe impl bytemuck::Pod for HistogramUniform {}
+unsafe impl bytemuck::Zeroable for HistogramUniform {}
+
+const HISTOGRAM_BINS: usize = 256;
+// luminance_histogram.comp の local_size
+const HISTOGRAM_GROUP_SIZE: u32 = 16;
+
+// SceneTarget ごとのバインドグループ
+pub struct TonemapBindings {
+    width: u32,
+    height: u32,
+    tonemap: wgpu::BindGroup,
+    histogram: wgpu::BindGroup,
+}
+
+// HDR の色をスワップチェーンの形式へ落とす全画面パス
+pub struct Tonemapper {
+    pub tonemap: Tonemap,
+    pub exposure: Exposure,
+    // Reinhard と Filmic で白になる明るさ
+    pub white: f32,
+    // 自動露出がこの秒数程度で明るさの変化に追いつく
+    pub adaptation_time: f32,
+    // ヒストグラムで扱う輝度の範囲 (log2)
+    pub min_log_luminance: f32,
+    pub max_log_luminance: f32,
+    // 平均の明るさをこの値に合わせる
+    pub key: f32,
+    uniform_buffer: wgpu::Buffer,
+    histogram_uniform_buffer: wgpu::Buffer,
+    histogram_buffer: wgpu::Buffer,
+    exposure_buffer: wgpu::Buffer,
+    layout: wgpu::BindGroupLayout,
+    histogram_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+    histogram_pipeline: wgpu::ComputePipeline,
+    average_pipeline: wgpu::ComputePipeline,
+}
+
+impl Tonemapper {
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let uniform_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Tonemap Uniform Buffer"),
+                size: std::mem::size_of::<TonemapUniform>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let histogram_uniform_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Histogram Uniform Buffer"),
+                size: std::mem::size_of::<HistogramUniform>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let histogram_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Histogram Buffer"),
+                contents: bytemuck::cast_slice(&[0u32; HISTOGRAM_BINS]),
+                usage: wgpu::BufferUsage::STORAGE,
+            }
+        );
+        let key = 0.18;
+        // (平均輝度, 露出)。最初は露出 1 から始める
+        let exposure_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Exposure Buffer"),
+                contents: bytemuck::cast_slice(&[key, 1.0f32]),
+                usage: wgpu::BufferUsage::STORAGE,
+            }
+        );
+
+        // HDR の色、サンプラ、ユニフォームは両方のレイアウトで同じ番号にする
+        let texture_entries = |visibility: wgpu::ShaderStage| vec![
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility,
+                ty: wgpu::BindingType::SampledTexture {
+                    multisampled: false,
+                    component_type: wgpu::TextureComponentType::Float,
+                    dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility,
+                ty: wgpu::BindingType::Sampler {
+                    comparison: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+
+        let mut entries = texture_entries(wgpu::ShaderStage::FRAGMENT);
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::StorageBuffer {
+                dynamic: false,
+                readonly: true,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &entries,
+                label: Some("tonemap_bind_group_layout"),
+            }
+        );
+
+        let mut entries = texture_entries(wgpu::ShaderStage::COMPUTE);
+        // ヒストグラムと (平均輝度, 露出)
+        for &binding in [3, 4].iter() {
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStage::COMPUTE,
+                ty: wgpu::BindingType::StorageBuffer {
+                    dynamic: false,
+                    readonly: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            });
+        }
+        let histogram_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &entries,
+                label: Some("histogram_bind_group_layout"),
+            }
+        );
+
+        let pipeline = create_fullscreen_pipeline(
+            device,
+            "Tonemap Pipeline",
+            &[&layout],
+            wgpu::include_spirv!("../tonemap.frag.spv"),
+            format,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+
+        let compute_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Histogram Pipeline Layout"),
+                bind_group_layouts: &[&histogram_layout],
+                push_constant_ranges: &[],
+            }
+        );
+        let histogram_module = device.create_shader_module(wgpu::include_spirv!("../luminance_histogram.comp.spv"));
+        let histogram_pipeline = device.create_compute_pipeline(
+            &wgpu::ComputePipelineDescriptor {
+                label: Some("Histogram Pipeline"),
+                layout: Some(&compute_layout),
+                compute_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &histogram_module,
+                    entry_point: "main",
+                },
+            }
+        );
+        let average_module = device.create_shader_module(wgpu::include_spirv!("../luminance_average.comp.spv"));
+        let average_pipeline = device.create_compute_pipeline(
+            &wgpu::ComputePipelineDescriptor {
+                label: Some("Luminance Average Pipeline"),
+                layout: Some(&compute_layout),
+                compute_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &average_module,
+                    entry_point: "main",
+                },
+            }
+        );
+
+        Self {
+            tonemap: Tonemap::Aces,
+            exposure: Exposure::Auto(0.0),
+            white: 4.0,
+            adaptation_time: 1.0,
+            min_log_luminance: -10.0,
+            max_log_luminance: 4.0,
+            key,
+            uniform_buffer,
+            histogram_uniform_buffer,
+            histogram_buffer,
+            exposure_buffer,
+            layout,
+            histogram_layout,
+            pipeline,
+            histogram_pipeline,
+            average_pipeline,
+        }
+    }
+
+    // 描き込む先を作り直したら呼び直す
+    pub fn bind(&self, device: &wgpu::Device, target: &SceneTarget) -> TonemapBindings {
+        let tonemap = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&target.color.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&target.color.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Buffer(self.uniform_buffer.slice(..)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Buffer(self.exposure_buffer.slice(..)),
+                    },
+                ],
+                label: Some("tonemap_bind_group"),
+            }
+        );
+        let histogram = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &self.histogram_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&target.color.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&target.color.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Buffer(self.histogram_uniform_buffer.slice(..)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Buffer(self.histogram_buffer.slice(..)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::Buffer(self.exposure_buffer.slice(..)),
+                    },
+                ],
+                label: Some("histogram_bind_group"),
+            }
+        );
+
+        TonemapBindings {
+            width: target.width,
+            height: target.height,
+            tonemap,
+            histogram,
+        }
+    }
+
+    // 手動でも自動でも補正の EV を足す
+    pub fn adjust_exposure(&mut self, ev: f32) {
+        self.exposure = match self.exposure {
+            Exposure::Manual(e) => Exposure::Manual(e + ev),
+            Exposure::Auto(e) => Exposure::Auto(e + ev),
+        };
+    }
+
+    pub fn toggle_auto_exposure(&mut self) {
+        self.exposure = match self.exposure {
+            Exposure::Manual(ev) => Exposure::Auto(ev),
+            Exposure::Auto(ev) => Exposure::Manual(ev),
+        };
+    }
+
+    // adapt_dt が Some なら自動露出をその秒数だけ今の画像に近づける。
+    // None なら前回の露出をそのまま使う (スクリーンショットなど)
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        bindings: &TonemapBindings,
+        output: &wgpu::TextureView,
+        adapt_dt: Option<f32>,
+    ) {
+        let (ev, auto_exposure) = match self.exposure {
+            Exposure::Manual(ev) => (ev, false),
+            Exposure::Auto(ev) => (ev, true),
+        };
+        let uniform = TonemapUniform {
+            operator: self.tonemap.id(),
+            exposure: 2.0f32.powf(ev),
+            auto_exposure: auto_exposure as u32,
+            white: self.white,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+        if let (true, Some(dt)) = (auto_exposure, adapt_dt) {
+            let time_coeff = if self.adaptation_time > 0.0 {
+                1.0 - (-dt / self.adaptation_time).exp()
+            } else {
+                1.0
+            };
+            let histogram_uniform = HistogramUniform {
+                min_log_luminance: self.min_log_luminance,
+                log_luminance_range: (self.max_log_luminance - self.min_log_luminance).max(0.001),
+                time_coeff,
+                key: self.key,
+            };
+            queue.write_buffer(&self.histogram_uniform_buffer, 0, bytemuck::cast_slice(&[histogram_uniform]));
+
+            let mut compute_pass = encoder.begin_compute_pass();
+            compute_pass.set_bind_group(0, &bindings.histogram, &[]);
+            compute_pass.set_pipeline(&self.histogram_pipeline);
+            compute_pass.dispatch(
+                (bindings.width + HISTOGRAM_GROUP_SIZE - 1) / HISTOGRAM_GROUP_SIZE,
+                (bindings.height + HISTOGRAM_GROUP_SIZE - 1) / HISTOGRAM_GROUP_SIZE,
+                1,
+            );
+            compute_pass.set_pipeline(&self.average_pipeline);
+            compute_pass.dispatch(1, 1, 1);
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: output,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    }
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &bindings.tonemap, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+// fullscreen.vert と組み合わせる全画面パス用のパイプライン
+pub fn create_fullscreen_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+    frag_src: wgpu::ShaderModuleSource,
+    format: wgpu::TextureFormat,
+    color_blend: wgpu::BlendDescriptor,
+    alpha_blend: wgpu::BlendDescriptor,
+) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(
+        &wgpu::PipelineLayoutDescriptor {
+            label: Some(label),
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        }
+    );
+
+    let vs_module = device.create_shader_module(wgpu::include_spirv!("../fullscreen.vert.spv"));
+    let fs_module = device.create_shader_module(frag_src);
+
+    device.create_render_pipeline(
+        &wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+            layout: Some(&layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &vs_module,
+                entry_point: "main",
+            },
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: &fs_module,
+                entry_point: "main",
+            }),
+            rasterization_state: Some(
+                wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::None,
+                    depth_bias: 0,
+                    depth_bias_slope_scale: 0.0,
+                    depth_bias_clamp: 0.0,
+                    clamp_depth: false,
+                }
+            ),
+            color_states: &[
+                wgpu::ColorStateDescriptor {
+                    format,
+                    color_blend,
+                    alpha_blend,
+                    write_mask: wgpu::ColorWrite::ALL,
+                },
+            ],
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        }
+    )
+}
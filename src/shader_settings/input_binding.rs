@@ -0,0 +1,487 @@
+use anyhow::*;
+use winit::event::{VirtualKeyCode, MouseButton, ModifiersState};
+use std::path::Path;
+
+// 入力とカメラ操作の対応付け
+// キーやボタンを直接見ずに Action を介すことで、配置を設定ファイルで変えられる
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    // 押している間だけ効くもの
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    // 移動を遅くする
+    Slow,
+    // ドラッグで視点を回す
+    Look,
+    // ドラッグで平行移動
+    Pan,
+    // ホイール
+    Zoom,
+    // ホイールで移動の速さを変える
+    AdjustSpeed,
+    // 押した瞬間に一度だけ効くもの
+    ToggleCameraMode,
+    ToggleProjection,
+    FrameAll,
+    FrameSelected,
+    ViewFront,
+    ViewBack,
+    ViewLeft,
+    ViewRight,
+    ViewTop,
+    ViewBottom,
+    // 後処理の効果を切り替える
+    ToggleBloom,
+    ToggleFxaa,
+    ToggleVignette,
+    ToggleColorGrading,
+    ToggleSsao,
+    ToggleSkybox,
+    ToggleFog,
+    ToggleSnow,
+    // 1..=9 の番号のブックマークへ移る / 今の位置を登録する
+    GoToBookmark(u8),
+    SaveBookmark(u8),
+    // 影と光源のデバッグ表示
+    ToggleShadowOverlay,
+    NextShadowLight,
+    ToggleShadowFrustums,
+    ToggleLightGizmo,
+    // 露出とアンチエイリアス
+    ToggleAutoExposure,
+    ExposureUp,
+    ExposureDown,
+    CycleTonemap,
+    CycleMsaa,
+    // 画像や連番の書き出し
+    Screenshot,
+    RenderTurntable,
+    ToggleCameraPathRecording,
+    RenderCameraPath,
+}
+
+impl Action {
+    const ALL: &'static [(&'static str, Action)] = &[
+        ("move_forward", Action::MoveForward),
+        ("move_backward", Action::MoveBackward),
+        ("move_left", Action::MoveLeft),
+        ("move_right", Action::MoveRight),
+        ("move_up", Action::MoveUp),
+        ("move_down", Action::MoveDown),
+        ("slow", Action::Slow),
+        ("look", Action::Look),
+        ("pan", Action::Pan),
+        ("zoom", Action::Zoom),
+        ("adjust_speed", Action::AdjustSpeed),
+        ("toggle_camera_mode", Action::ToggleCameraMode),
+        ("toggle_projection", Action::ToggleProjection),
+        ("frame_all", Action::FrameAll),
+        ("frame_selected", Action::FrameSelected),
+        ("view_front", Action::ViewFront),
+        ("view_back", Action::ViewBack),
+        ("view_left", Action::ViewLeft),
+        ("view_right", Action::ViewRight),
+        ("view_top", Action::ViewTop),
+        ("view_bottom", Action::ViewBottom),
+        ("toggle_bloom", Action::ToggleBloom),
+        ("toggle_fxaa", Action::ToggleFxaa),
+        ("toggle_vignette", Action::ToggleVignette),
+        ("toggle_color_grading", Action::ToggleColorGrading),
+        ("toggle_ssao", Action::ToggleSsao),
+        ("toggle_skybox", Action::ToggleSkybox),
+        ("toggle_fog", Action::ToggleFog),
+        ("toggle_snow", Action::ToggleSnow),
+        ("bookmark_1", Action::GoToBookmark(1)),
+        ("bookmark_2", Action::GoToBookmark(2)),
+        ("bookmark_3", Action::GoToBookmark(3)),
+        ("bookmark_4", Action::GoToBookmark(4)),
+        ("bookmark_5", Action::GoToBookmark(5)),
+        ("bookmark_6", Action::GoToBookmark(6)),
+        ("bookmark_7", Action::GoToBookmark(7)),
+        ("bookmark_8", Action::GoToBookmark(8)),
+        ("bookmark_9", Action::GoToBookmark(9)),
+        ("save_bookmark_1", Action::SaveBookmark(1)),
+        ("save_bookmark_2", Action::SaveBookmark(2)),
+        ("save_bookmark_3", Action::SaveBookmark(3)),
+        ("save_bookmark_4", Action::SaveBookmark(4)),
+        ("save_bookmark_5", Action::SaveBookmark(5)),
+        ("save_bookmark_6", Action::SaveBookmark(6)),
+        ("save_bookmark_7", Action::SaveBookmark(7)),
+        ("save_bookmark_8", Action::SaveBookmark(8)),
+        ("save_bookmark_9", Action::SaveBookmark(9)),
+        ("toggle_shadow_overlay", Action::ToggleShadowOverlay),
+        ("next_shadow_light", Action::NextShadowLight),
+        ("toggle_shadow_frustums", Action::ToggleShadowFrustums),
+        ("toggle_light_gizmo", Action::ToggleLightGizmo),
+        ("toggle_auto_exposure", Action::ToggleAutoExposure),
+        ("exposure_up", Action::ExposureUp),
+        ("exposure_down", Action::ExposureDown),
+        ("cycle_tonemap", Action::CycleTonemap),
+        ("cycle_msaa", Action::CycleMsaa),
+        ("screenshot", Action::Screenshot),
+        ("render_turntable", Action::RenderTurntable),
+        ("toggle_camera_path_recording", Action::ToggleCameraPathRecording),
+        ("render_camera_path", Action::RenderCameraPath),
+    ];
+
+    fn parse(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, action)| *action)
+            .with_context(|| format!("Unknown action: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    Wheel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Binding {
+    pub input: Input,
+    // 一緒に押されている必要がある修飾キー
+    pub modifiers: ModifiersState,
+}
+
+impl Binding {
+    pub fn key(key: VirtualKeyCode) -> Self {
+        Self { input: Input::Key(key), modifiers: ModifiersState::empty() }
+    }
+
+    pub fn mouse(button: MouseButton) -> Self {
+        Self { input: Input::Mouse(button), modifiers: ModifiersState::empty() }
+    }
+
+    pub fn with_modifiers(self, modifiers: ModifiersState) -> Self {
+        Self { modifiers, ..self }
+    }
+
+    // "ctrl+shift+W" や "mouse_left" の形
+    fn parse(s: &str) -> Result<Self> {
+        let mut words = s.split('+').collect::<Vec<_>>();
+        let input = words.pop().context("Empty binding")?;
+        let mut modifiers = ModifiersState::empty();
+        for w in words {
+            modifiers |= match w.to_lowercase().as_str() {
+                "ctrl" => ModifiersState::CTRL,
+                "shift" => ModifiersState::SHIFT,
+                "alt" => ModifiersState::ALT,
+                "logo" => ModifiersState::LOGO,
+                _ => bail!("Unknown modifier: {}", w),
+            };
+        }
+        let input = match input {
+            "mouse_left" => Input::Mouse(MouseButton::Left),
+            "mouse_right" => Input::Mouse(MouseButton::Right),
+            "mouse_middle" => Input::Mouse(MouseButton::Middle),
+            "wheel" => Input::Wheel,
+            k => Input::Key(parse_key(k)?),
+        };
+        Ok(Self { input, modifiers })
+    }
+}
+
+pub struct InputBindings {
+    bindings: Vec<(Action, Binding)>,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        use VirtualKeyCode as VKC;
+        let key = |action, key| (action, Binding::key(key));
+        let mut bindings = vec![
+            key(Action::MoveForward, VKC::W),
+            key(Action::MoveForward, VKC::Up),
+            key(Action::MoveBackward, VKC::S),
+            key(Action::MoveBackward, VKC::Down),
+            key(Action::MoveLeft, VKC::A),
+            key(Action::MoveLeft, VKC::Left),
+            key(Action::MoveRight, VKC::D),
+            key(Action::MoveRight, VKC::Right),
+            key(Action::MoveUp, VKC::Space),
+            key(Action::MoveDown, VKC::LShift),
+            key(Action::Slow, VKC::LControl),
+            key(Action::Slow, VKC::RControl),
+            (Action::Look, Binding::mouse(MouseButton::Left)),
+            (Action::Pan, Binding::mouse(MouseButton::Middle)),
+            (Action::Pan, Binding::mouse(MouseButton::Right)),
+            (Action::Zoom, Binding { input: Input::Wheel, modifiers: ModifiersState::empty() }),
+            (Action::AdjustSpeed, Binding { input: Input::Wheel, modifiers: ModifiersState::ALT }),
+            key(Action::ToggleCameraMode, VKC::Tab),
+            key(Action::ToggleProjection, VKC::Numpad5),
+            key(Action::FrameAll, VKC::Home),
+            key(Action::FrameSelected, VKC::F),
+            key(Action::ViewFront, VKC::Numpad1),
+            key(Action::ViewBack, VKC::Numpad9),
+            key(Action::ViewLeft, VKC::Numpad4),
+            key(Action::ViewRight, VKC::Numpad3),
+            key(Action::ViewTop, VKC::Numpad7),
+            key(Action::ViewBottom, VKC::Numpad2),
+            key(Action::ToggleBloom, VKC::B),
+            key(Action::ToggleFxaa, VKC::X),
+            key(Action::ToggleVignette, VKC::V),
+            key(Action::ToggleColorGrading, VKC::G),
+            key(Action::ToggleSsao, VKC::O),
+            key(Action::ToggleSkybox, VKC::K),
+            key(Action::ToggleFog, VKC::H),
+            key(Action::ToggleSnow, VKC::N),
+            key(Action::ToggleShadowOverlay, VKC::F3),
+            key(Action::NextShadowLight, VKC::F4),
+            key(Action::ToggleShadowFrustums, VKC::F5),
+            key(Action::ToggleLightGizmo, VKC::F6),
+            key(Action::ToggleAutoExposure, VKC::F2),
+            key(Action::ExposureUp, VKC::Equals),
+            key(Action::ExposureUp, VKC::Add),
+            key(Action::ExposureDown, VKC::Minus),
+            key(Action::ExposureDown, VKC::Subtract),
+            key(Action::CycleMsaa, VKC::F7),
+            key(Action::CycleTonemap, VKC::F8),
+            key(Action::ToggleCameraPathRecording, VKC::F9),
+            key(Action::RenderTurntable, VKC::F10),
+            key(Action::RenderCameraPath, VKC::F11),
+            key(Action::Screenshot, VKC::F12),
+        ];
+        // 数字キーでブックマークへ移動、Ctrl と一緒に押すと今の位置を登録する
+        let digits = [
+            VKC::Key1, VKC::Key2, VKC::Key3, VKC::Key4, VKC::Key5,
+            VKC::Key6, VKC::Key7, VKC::Key8, VKC::Key9,
+        ];
+        for (i, &digit) in digits.iter().enumerate() {
+            let slot = i as u8 + 1;
+            bindings.push(key(Action::GoToBookmark(slot), digit));
+            bindings.push((Action::SaveBookmark(slot), Binding::key(digit).with_modifiers(ModifiersState::CTRL)));
+        }
+        Self { bindings }
+    }
+}
+
+impl InputBindings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 設定ファイルの書式 (1行1つ、# 以降はコメント)
+    //
+    //   bind <action> <input>     既定の割り当てに追加する
+    //   unbind <action>           その action の割り当てを全て消す
+    //
+    // input は W, Up, Numpad1 などのキー名か mouse_left, mouse_right, mouse_middle, wheel。
+    // 前に ctrl+, shift+, alt+, logo+ を付けると修飾キーが必要になる
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let src = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Cannot read {:?}", path.as_ref()))?;
+        let mut bindings = Self::default();
+        bindings.parse(&src)?;
+        Ok(bindings)
+    }
+
+    fn parse(&mut self, src: &str) -> Result<()> {
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let err = || format!("line {}: {}", n + 1, line);
+            match words[0] {
+                "bind" => {
+                    let action = Action::parse(words.get(1).with_context(err)?).with_context(err)?;
+                    let binding = Binding::parse(words.get(2).with_context(err)?).with_context(err)?;
+                    self.bind(action, binding);
+                }
+                "unbind" => {
+                    let action = Action::parse(words.get(1).with_context(err)?).with_context(err)?;
+                    self.unbind(action);
+                }
+                _ => bail!("Unknown directive: {}", err()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        if !self.bindings.contains(&(action, binding)) {
+            self.bindings.push((action, binding));
+        }
+    }
+
+    pub fn unbind(&mut self, action: Action) {
+        self.bindings.retain(|(a, _)| *a != action);
+    }
+
+    pub fn bindings(&self, action: Action) -> impl Iterator<Item = &Binding> {
+        self.bindings.iter().filter(move |(a, _)| *a == action).map(|(_, b)| b)
+    }
+
+    // input に割り当てられた action。修飾キーが足りているもののうち、
+    // 一番多く修飾キーを要求するものだけを返す (ctrl+W があれば W より優先)
+    pub fn actions(&self, input: Input, modifiers: ModifiersState) -> Vec<Action> {
+        let matched = self.bindings
+            .iter()
+            .filter(|(_, b)| b.input == input && modifiers.contains(b.modifiers))
+            .collect::<Vec<_>>();
+        let most = matched.iter().map(|(_, b)| b.modifiers.bits().count_ones()).max();
+        matched
+            .into_iter()
+            .filter(|(_, b)| Some(b.modifiers.bits().count_ones()) == most)
+            .map(|(a, _)| *a)
+            .collect()
+    }
+
+    // 離したときは修飾キーに関係なく全て止める。押しっぱなしを防ぐため
+    pub fn released_actions(&self, input: Input) -> Vec<Action> {
+        self.bindings
+            .iter()
+            .filter(|(_, b)| b.input == input)
+            .map(|(a, _)| *a)
+            .collect()
+    }
+}
+
+fn parse_key(s: &str) -> Result<VirtualKeyCode> {
+    use VirtualKeyCode as VKC;
+    const KEYS: &[(&str, VirtualKeyCode)] = &[
+        ("A", VKC::A), ("B", VKC::B), ("C", VKC::C), ("D", VKC::D), ("E", VKC::E),
+        ("F", VKC::F), ("G", VKC::G), ("H", VKC::H), ("I", VKC::I), ("J", VKC::J),
+        ("K", VKC::K), ("L", VKC::L), ("M", VKC::M), ("N", VKC::N), ("O", VKC::O),
+        ("P", VKC::P), ("Q", VKC::Q), ("R", VKC::R), ("S", VKC::S), ("T", VKC::T),
+        ("U", VKC::U), ("V", VKC::V), ("W", VKC::W), ("X", VKC::X), ("Y", VKC::Y),
+        ("Z", VKC::Z),
+        ("Key0", VKC::Key0), ("Key1", VKC::Key1), ("Key2", VKC::Key2), ("Key3", VKC::Key3),
+        ("Key4", VKC::Key4), ("Key5", VKC::Key5), ("Key6", VKC::Key6), ("Key7", VKC::Key7),
+        ("Key8", VKC::Key8), ("Key9", VKC::Key9),
+        ("Numpad0", VKC::Numpad0), ("Numpad1", VKC::Numpad1), ("Numpad2", VKC::Numpad2),
+        ("Numpad3", VKC::Numpad3), ("Numpad4", VKC::Numpad4), ("Numpad5", VKC::Numpad5),
+        ("Numpad6", VKC::Numpad6), ("Numpad7", VKC::Numpad7), ("Numpad8", VKC::Numpad8),
+        ("Numpad9", VKC::Numpad9),
+        ("F1", VKC::F1), ("F2", VKC::F2), ("F3", VKC::F3), ("F4", VKC::F4),
+        ("F5", VKC::F5), ("F6", VKC::F6), ("F7", VKC::F7), ("F8", VKC::F8),
+        ("F9", VKC::F9), ("F10", VKC::F10), ("F11", VKC::F11), ("F12", VKC::F12),
+        ("Up", VKC::Up), ("Down", VKC::Down), ("Left", VKC::Left), ("Right", VKC::Right),
+        ("Space", VKC::Space), ("Tab", VKC::Tab), ("Return", VKC::Return), ("Back", VKC::Back),
+        ("Home", VKC::Home), ("End", VKC::End), ("PageUp", VKC::PageUp), ("PageDown", VKC::PageDown),
+        ("Insert", VKC::Insert), ("Delete", VKC::Delete),
+        ("LShift", VKC::LShift), ("RShift", VKC::RShift),
+        ("LControl", VKC::LControl), ("RControl", VKC::RControl),
+        ("LAlt", VKC::LAlt), ("RAlt", VKC::RAlt),
+        ("Comma", VKC::Comma), ("Period", VKC::Period), ("Slash", VKC::Slash),
+        ("Semicolon", VKC::Semicolon), ("Apostrophe", VKC::Apostrophe),
+        ("LBracket", VKC::LBracket), ("RBracket", VKC::RBracket),
+        ("Minus", VKC::Minus), ("Equals", VKC::Equals),
+        ("Add", VKC::Add), ("Subtract", VKC::Subtract),
+        ("Multiply", VKC::Multiply), ("Divide", VKC::Divide),
+    ];
+    KEYS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(s))
+        .map(|(_, key)| *key)
+        .with_context(|| format!("Unknown key: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use VirtualKeyCode as VKC;
+
+    #[test]
+    fn binding_parses_modifiers_and_inputs() {
+        let b = Binding::parse("ctrl+Shift+w").unwrap();
+        assert_eq!(b, Binding::key(VKC::W).with_modifiers(ModifiersState::CTRL | ModifiersState::SHIFT));
+        assert_eq!(Binding::parse("mouse_middle").unwrap(), Binding::mouse(MouseButton::Middle));
+        assert_eq!(
+            Binding::parse("alt+wheel").unwrap(),
+            Binding { input: Input::Wheel, modifiers: ModifiersState::ALT },
+        );
+        assert_eq!(Binding::parse("Key3").unwrap(), Binding::key(VKC::Key3));
+    }
+
+    #[test]
+    fn binding_rejects_unknown_names() {
+        for s in ["hyper+W", "ctrl+", "F13", "mouse_side"].iter() {
+            assert!(Binding::parse(s).is_err(), "accepted {:?}", s);
+        }
+    }
+
+    #[test]
+    fn parse_binds_and_unbinds() {
+        let mut bindings = InputBindings::default();
+        bindings.parse("
+            # コメント
+            unbind toggle_bloom
+            bind toggle_bloom ctrl+B
+            bind bookmark_2 Numpad8
+            unbind save_bookmark_2
+        ").unwrap();
+
+        assert_eq!(bindings.actions(Input::Key(VKC::B), ModifiersState::empty()), vec![]);
+        assert_eq!(bindings.actions(Input::Key(VKC::B), ModifiersState::CTRL), vec![Action::ToggleBloom]);
+        assert_eq!(bindings.actions(Input::Key(VKC::Numpad8), ModifiersState::empty()), vec![Action::GoToBookmark(2)]);
+        assert_eq!(bindings.actions(Input::Key(VKC::Key2), ModifiersState::CTRL), vec![Action::GoToBookmark(2)]);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_lines() {
+        let bad = [
+            "bind toggle_bloom",
+            "bind no_such_action B",
+            "bind toggle_bloom NoSuchKey",
+            "unbind",
+            "bind_all B",
+        ];
+        for src in bad.iter() {
+            assert!(InputBindings::default().parse(src).is_err(), "accepted {:?}", src);
+        }
+    }
+
+    #[test]
+    fn actions_prefer_more_modifiers() {
+        let bindings = InputBindings::default();
+        let key1 = Input::Key(VKC::Key1);
+        assert_eq!(bindings.actions(key1, ModifiersState::empty()), vec![Action::GoToBookmark(1)]);
+        assert_eq!(bindings.actions(key1, ModifiersState::CTRL), vec![Action::SaveBookmark(1)]);
+        // 余分な修飾キーがあっても一番近いものが選ばれる
+        assert_eq!(
+            bindings.actions(key1, ModifiersState::CTRL | ModifiersState::SHIFT),
+            vec![Action::SaveBookmark(1)],
+        );
+        assert_eq!(
+            bindings.actions(Input::Wheel, ModifiersState::ALT),
+            vec![Action::AdjustSpeed],
+        );
+        assert_eq!(bindings.actions(Input::Key(VKC::F3), ModifiersState::empty()), vec![Action::ToggleShadowOverlay]);
+        assert_eq!(bindings.actions(Input::Key(VKC::Equals), ModifiersState::empty()), vec![Action::ExposureUp]);
+        assert_eq!(bindings.actions(Input::Key(VKC::Subtract), ModifiersState::empty()), vec![Action::ExposureDown]);
+    }
+
+    #[test]
+    fn function_keys_can_be_rebound() {
+        let mut bindings = InputBindings::default();
+        bindings.parse("unbind screenshot\nbind screenshot shift+F2\nbind cycle_msaa F12").unwrap();
+        assert_eq!(bindings.actions(Input::Key(VKC::F12), ModifiersState::empty()), vec![Action::CycleMsaa]);
+        assert_eq!(bindings.actions(Input::Key(VKC::F2), ModifiersState::SHIFT), vec![Action::Screenshot]);
+        assert_eq!(bindings.actions(Input::Key(VKC::F2), ModifiersState::empty()), vec![Action::ToggleAutoExposure]);
+    }
+
+    #[test]
+    fn released_actions_ignore_modifiers() {
+        let bindings = InputBindings::default();
+        let released = bindings.released_actions(Input::Key(VKC::Key9));
+        assert!(released.contains(&Action::GoToBookmark(9)));
+        assert!(released.contains(&Action::SaveBookmark(9)));
+    }
+
+    #[test]
+    fn every_action_name_parses() {
+        for (name, action) in Action::ALL.iter() {
+            assert_eq!(Action::parse(name).unwrap(), *action);
+        }
+    }
+}
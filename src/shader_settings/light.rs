@@ -59,6 +59,11 @@ impl Light {
         }
     }
 
+    // ライトバッファ上の位置。シャドウマップのレイヤと共通
+    pub fn slot(&self) -> usize {
+        self.shadow.layer()
+    }
+
     pub fn to_raw(&self) -> LightRaw {
         LightRaw {
             position: self.position,
@@ -124,11 +129,16 @@ unsafe impl bytemuck::Pod for LightRaw {}
 
 pub struct LightBuffer {
     pub buffer: wgpu::Buffer,
+    capacity: usize,
 }
 
+// 光源はスロット (= シャドウマップのレイヤ) の順に詰めて並べる。
+// shader.frag は 0..u_light_num を走査するので途中に空きがあってはいけない
 impl LightBuffer {
     pub fn new(device: &wgpu::Device, lights: &[&Light]) -> Self {
-        let light_raws = lights.iter().map(|light| light.to_raw()).collect::<Vec<_>>();
+        let capacity = lights.len().max(1).next_power_of_two();
+        let mut light_raws = lights.iter().map(|light| light.to_raw()).collect::<Vec<_>>();
+        light_raws.resize(capacity, bytemuck::Zeroable::zeroed());
 
         let buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -140,11 +150,30 @@ impl LightBuffer {
 
         Self {
             buffer,
+            capacity,
+        }
+    }
+
+    // len 個入らなければ作り直す。作り直した場合は中身が空なので true を返す
+    pub fn reserve(&mut self, device: &wgpu::Device, len: usize) -> bool {
+        if len <= self.capacity {
+            return false;
         }
+        self.capacity = len.next_power_of_two();
+        self.buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Lights Buffer"),
+                size: (self.capacity * std::mem::size_of::<LightRaw>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        true
     }
 
+    // ユーザの付けた id ではなく、割り当てられたスロットに書き込む
     pub fn update_light(&mut self, queue: &wgpu::Queue, light: &Light) {
-        let offset = light.id * std::mem::size_of::<LightRaw>();
+        let offset = light.slot() * std::mem::size_of::<LightRaw>();
         let offset = offset as u64;
         queue.write_buffer(
             &self.buffer,
@@ -1,4 +1,6 @@
 use crate::shader_settings::texture;
+use crate::shader_settings::skin;
+use crate::shader_settings::bounds::{Aabb, BoundingSphere};
 use anyhow::*;
 use std::path::*;
 use std::ops::Range;
@@ -16,15 +18,38 @@ pub struct ModelVertex {
     position: [f32; 3],
     tex_coords: [f32; 2],
     normal: [f32; 3],
+    // スキニング用。OBJ 等スキンを持たない頂点は weights が全て 0
+    joints: [u32; 4],
+    weights: [f32; 4],
 }
 
 unsafe impl bytemuck::Pod for ModelVertex {}
 unsafe impl bytemuck::Zeroable for ModelVertex {}
 
+impl ModelVertex {
+    pub fn new(
+        position: [f32; 3],
+        tex_coords: [f32; 2],
+        normal: [f32; 3],
+        joints: [u32; 4],
+        weights: [f32; 4],
+    ) -> Self {
+        Self {
+            position,
+            tex_coords,
+            normal,
+            joints,
+            weights,
+        }
+    }
+}
+
 impl Vertex for ModelVertex {
     fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
         const OFFSET_2: wgpu::BufferAddress = std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress;
         const OFFSET_3: wgpu::BufferAddress = std::mem::size_of::<[f32; 2]>() as wgpu::BufferAddress + OFFSET_2;
+        const OFFSET_4: wgpu::BufferAddress = std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress + OFFSET_3;
+        const OFFSET_5: wgpu::BufferAddress = std::mem::size_of::<[u32; 4]>() as wgpu::BufferAddress + OFFSET_4;
         wgpu::VertexBufferDescriptor {
             stride: std::mem::size_of::<ModelVertex>() as wgpu::BufferAddress,
             step_mode: wgpu::InputStepMode::Vertex,
@@ -44,6 +69,84 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float3,
                 },
+                wgpu::VertexAttributeDescriptor {
+                    offset: OFFSET_4,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Uint4,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: OFFSET_5,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float4,
+                },
+            ],
+        }
+    }
+}
+
+// モーフターゲット (ブレンドシェイプ)
+// 頂点属性として渡すので数に上限がある。shader.vert, bake.vert と合わせること
+pub const MAX_MORPH_TARGETS: usize = 4;
+
+// 各ターゲットの基本形状からの差分。ModelVertex とは別の頂点バッファに入れる
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MorphVertex {
+    pub position_deltas: [[f32; 3]; MAX_MORPH_TARGETS],
+    pub normal_deltas: [[f32; 3]; MAX_MORPH_TARGETS],
+}
+
+unsafe impl bytemuck::Pod for MorphVertex {}
+unsafe impl bytemuck::Zeroable for MorphVertex {}
+
+impl Vertex for MorphVertex {
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        // 位置の差分が location 5..8、法線の差分が 9..12
+        const F3: wgpu::BufferAddress = std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress;
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<MorphVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    offset: F3 * 0,
+                    shader_location: 5,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: F3 * 1,
+                    shader_location: 6,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: F3 * 2,
+                    shader_location: 7,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: F3 * 3,
+                    shader_location: 8,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: F3 * 4,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: F3 * 5,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: F3 * 6,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: F3 * 7,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float3,
+                },
             ],
         }
     }
@@ -53,11 +156,43 @@ pub struct Model {
     pub id: usize,
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    pub skeleton: Option<skin::Skeleton>,
+    // モデル空間での境界。スキンメッシュはバインドポーズでの値
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
+    // インスタンスを作ったときのモーフの重み。glTF ではメッシュの weights
+    pub morph_weights: [f32; MAX_MORPH_TARGETS],
 }
 
 use std::cmp::{PartialEq, Eq};
 use std::hash::{Hash, Hasher};
 
+impl Model {
+    // 境界はメッシュから求める
+    pub fn from_parts(
+        id: usize,
+        meshes: Vec<Mesh>,
+        materials: Vec<Material>,
+        skeleton: Option<skin::Skeleton>,
+    ) -> Self {
+        let aabb = meshes.iter().fold(Aabb::empty(), |acc, m| acc.union(&m.aabb));
+        let bounding_sphere = aabb.bounding_sphere();
+        Self {
+            id,
+            meshes,
+            materials,
+            skeleton,
+            aabb,
+            bounding_sphere,
+            morph_weights: [0.0; MAX_MORPH_TARGETS],
+        }
+    }
+
+    pub fn morph_target_num(&self) -> usize {
+        self.meshes.iter().map(|m| m.morph_target_num).max().unwrap_or(0)
+    }
+}
+
 impl PartialEq for Model {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id
@@ -79,6 +214,74 @@ pub struct Material {
     pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+    // テクスチャが無い場合は代替のテクスチャを充てる
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        name: String,
+        diffuse_texture: Option<texture::Texture>,
+        ambient_color: cgmath::Vector3<f32>,
+        diffuse_color: cgmath::Vector3<f32>,
+        specular_color: cgmath::Vector3<f32>,
+    ) -> Result<Self> {
+        let material_uniform = MaterialUniform {
+            use_texture: if diffuse_texture.is_some() { 1 } else { 0 },
+            _p1: (0.0, 0.0, 0.0).into(),
+            ambient_color,
+            _p2: 0,
+            diffuse_color,
+            _p3: 0,
+            specular_color,
+            _p4: 0,
+        };
+        let matuni_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Material Uniform Buffer"),
+                contents: bytemuck::cast_slice(&[material_uniform]),
+                usage: wgpu::BufferUsage::UNIFORM,
+            }
+        );
+        let diffuse_texture = if let Some(t) = diffuse_texture {
+            t
+        } else {
+            texture::Texture::load(
+                device,
+                queue,
+                "./assets/default_texture.png",
+            )?
+        };
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Buffer(matuni_buffer.slice(..))
+                    },
+                ],
+                label: None,
+            }
+        );
+
+        Ok(Self {
+            name,
+            diffuse_texture,
+            matuni_buffer,
+            bind_group,
+        })
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct MaterialUniform {
@@ -104,6 +307,26 @@ pub struct Mesh {
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize, // インデックスくさい -> そうだった
+    // ターゲットが無いメッシュでも 0 埋めのバッファを持つ
+    pub morph_buffer: wgpu::Buffer,
+    pub morph_target_num: usize,
+    pub aabb: Aabb,
+}
+
+impl Mesh {
+    pub fn create_morph_buffer(
+        device: &wgpu::Device,
+        label: &str,
+        morphs: &[MorphVertex],
+    ) -> wgpu::Buffer {
+        device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} Morph Buffer", label)),
+                contents: bytemuck::cast_slice(morphs),
+                usage: wgpu::BufferUsage::VERTEX,
+            }
+        )
+    }
 }
 
 impl Model {
@@ -132,61 +355,16 @@ impl Model {
             } else {
                 None
             };
-            let material_uniform = MaterialUniform {
-                use_texture: if diffuse_texture_w.is_some() { 1 } else { 0 },
-                _p1: (0.0, 0.0, 0.0).into(),
-                ambient_color: mat.ambient.into(),
-                _p2: 0,
-                diffuse_color: mat.diffuse.into(),
-                _p3: 0,
-                specular_color: mat.specular.into(),
-                _p4: 0,
-            };
-            let matuni_buffer = device.create_buffer_init(
-                &wgpu::util::BufferInitDescriptor {
-                    label: Some("Material Uniform Buffer"),
-                    contents: bytemuck::cast_slice(&[material_uniform]),
-                    usage: wgpu::BufferUsage::UNIFORM,
-                }
-            );
-            let diffuse_texture = if let Some(t) = diffuse_texture_w {
-                t
-            } else {
-                texture::Texture::load(
-                    device,
-                    queue,
-                    "./assets/default_texture.png",
-                )?
-            };
-            let bind_group = device.create_bind_group(
-                &wgpu::BindGroupDescriptor {
-                    layout,
-                    entries: &[
-                        wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 1,
-                            resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 2,
-                            resource: wgpu::BindingResource::Buffer(matuni_buffer.slice(..))
-                        },
-                    ],
-                    label: None,
-                }
-            );
-
-            materials.push(
-                Material {
-                    name: mat.name,
-                    diffuse_texture,
-                    matuni_buffer,
-                    bind_group,
-                }
-            );
+            materials.push(Material::new(
+                device,
+                queue,
+                layout,
+                mat.name,
+                diffuse_texture_w,
+                mat.ambient.into(),
+                mat.diffuse.into(),
+                mat.specular.into(),
+            )?);
         }
 
         let mut meshes = Vec::new();
@@ -210,6 +388,8 @@ impl Model {
                             m.mesh.normals[i * 3 + 1],
                             m.mesh.normals[i * 3 + 2],
                         ],
+                        joints: [0; 4],
+                        weights: [0.0; 4],
                     }
                 );
             }
@@ -229,6 +409,12 @@ impl Model {
                 }
             );
 
+            let morph_buffer = Mesh::create_morph_buffer(
+                device,
+                &format!("{:?}", path.as_ref()),
+                &vec![bytemuck::Zeroable::zeroed(); vertices.len()],
+            );
+
             meshes.push(
                 Mesh {
                     name: m.name,
@@ -237,11 +423,18 @@ impl Model {
                     num_elements: m.mesh.indices.len() as u32,
                     // 1つ以上はマテリアルは存在するはず
                     material: m.mesh.material_id.unwrap_or(0),
+                    morph_buffer,
+                    morph_target_num: 0,
+                    aabb: Aabb::from_points(
+                        m.mesh.positions
+                            .chunks(3)
+                            .map(|p| cgmath::Point3::new(p[0], p[1], p[2]))
+                    ),
                 }
             );
         }
 
-        Ok(Self { id, meshes, materials })
+        Ok(Self::from_parts(id, meshes, materials, None))
     }
 }
 
@@ -326,6 +519,7 @@ where
         // shm_bg: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_vertex_buffer(1, mesh.morph_buffer.slice(..));
         self.set_index_buffer(mesh.index_buffer.slice(..));
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, uni_bg, &[]);
@@ -385,6 +579,15 @@ pub struct Instance {
     pub position: Vector3<f32>,
     pub rotation: Quaternion<f32>,
     pub scale: f32,
+    pub visible: bool,
+    pub casts_shadow: bool,
+    pub receives_shadow: bool,
+    // モデルがスケルトンを持つ場合のみ使われる
+    pub skin: skin::SkinPlayer,
+    // モーフターゲットの重み。ターゲットの無いメッシュでは無視される
+    pub morph_weights: [f32; MAX_MORPH_TARGETS],
+    // 光源の球として登録されたか。どちらの InstanceGroupBook に属するかを表す
+    light: bool,
 }
 
 impl PartialEq for Instance {
@@ -401,25 +604,91 @@ impl Hash for Instance {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct InstanceRaw {
     transform: cgmath::Matrix4<f32>,
     transform_norm: cgmath::Matrix4<f32>,
+    flags: u32,
+    // ジョイントパレット中の先頭位置
+    joint_offset: u32,
+    _p: [u32; 2],
+    morph_weights: [f32; MAX_MORPH_TARGETS],
+}
+
+// shader.vert, bake.vert, shader.frag の INSTANCE_* と合わせること
+pub const INSTANCE_CASTS_SHADOW: u32 = 1 << 0;
+pub const INSTANCE_RECEIVES_SHADOW: u32 = 1 << 1;
+pub const INSTANCE_VISIBLE: u32 = 1 << 2;
+
+impl InstanceRaw {
+    fn is_visible(&self) -> bool {
+        self.flags & INSTANCE_VISIBLE != 0
+    }
+
+    // 見えないものは影も落とさない
+    fn casts_shadow(&self) -> bool {
+        self.is_visible() && self.flags & INSTANCE_CASTS_SHADOW != 0
+    }
 }
 
 unsafe impl bytemuck::Pod for InstanceRaw {}
 unsafe impl bytemuck::Zeroable for InstanceRaw {}
 
 impl Instance {
-    pub fn to_raw(&self) -> InstanceRaw {
-        let transform = cgmath::Matrix4::from_translation(self.position)
+    pub fn model(&self) -> &Rc<Model> {
+        &self.model
+    }
+
+    pub fn is_light(&self) -> bool {
+        self.light
+    }
+
+    // ShaderState が登録時に設定する
+    pub(crate) fn set_light(&mut self, light: bool) {
+        self.light = light;
+    }
+
+    pub fn transform(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position)
             * cgmath::Matrix4::from(self.rotation)
-            * cgmath::Matrix4::from_scale(self.scale);
+            * cgmath::Matrix4::from_scale(self.scale)
+    }
+
+    // ワールド空間での境界
+    pub fn world_aabb(&self) -> Aabb {
+        self.model.aabb.transform(&self.transform())
+    }
+
+    pub fn world_bounding_sphere(&self) -> BoundingSphere {
+        let sphere = &self.model.bounding_sphere;
+        BoundingSphere {
+            center: self.transform().transform_point(sphere.center),
+            radius: sphere.radius * self.scale.abs(),
+        }
+    }
+
+    pub fn to_raw(&self) -> InstanceRaw {
+        let transform = self.transform();
         let mut t = transform.invert().unwrap_or(transform);
         t.transpose_self();
+        let mut flags = 0;
+        if self.visible {
+            flags |= INSTANCE_VISIBLE;
+        }
+        if self.casts_shadow {
+            flags |= INSTANCE_CASTS_SHADOW;
+        }
+        if self.receives_shadow {
+            flags |= INSTANCE_RECEIVES_SHADOW;
+        }
+        let joint_num = self.model.skeleton.as_ref().map(|s| s.joint_num()).unwrap_or(0);
         InstanceRaw {
             transform,
             transform_norm: t,
+            flags,
+            joint_offset: (self.index * joint_num) as u32,
+            _p: [0; 2],
+            morph_weights: self.morph_weights,
         }
     }
 }
@@ -439,20 +708,44 @@ impl Model {
             position,
             rotation,
             scale,
+            visible: true,
+            casts_shadow: true,
+            receives_shadow: true,
+            skin: skin::SkinPlayer::new(),
+            morph_weights: model.morph_weights,
+            light: false,
         }
     }
 }
 
 use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
 
 pub struct ModelInstanceGroup {
     pub len: usize,
     pub buffer: wgpu::Buffer,
+    // 全インスタンス分のジョイント行列。スケルトンが無いモデルでもダミーを1つ持つ
+    pub joint_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
+    // GPUに書き込んだ内容の控え。変化の検出に使う
+    raws: RefCell<Vec<InstanceRaw>>,
+}
+
+impl ModelInstanceGroup {
+    // 1つも見えるインスタンスがなければ描画自体を省く
+    pub fn has_visible(&self) -> bool {
+        self.raws.borrow().iter().any(|raw| raw.is_visible())
+    }
+
+    pub fn has_shadow_caster(&self) -> bool {
+        self.raws.borrow().iter().any(|raw| raw.casts_shadow())
+    }
 }
 
 pub struct ModelInstanceGroupBook {
     pub group_book: HashMap<Rc<Model>, ModelInstanceGroup>,
+    // 前回の影の焼き込み以降に影を落とすインスタンスが動いたか
+    shadow_dirty: Cell<bool>,
 }
 
 impl ModelInstanceGroupBook {
@@ -484,6 +777,17 @@ impl ModelInstanceGroupBook {
                 }
             );
 
+            let joint_num = model.skeleton.as_ref().map(|s| s.joint_num()).unwrap_or(0);
+            let identity: [[f32; 4]; 4] = cgmath::Matrix4::<f32>::identity().into();
+            let identities = vec![identity; (joint_num * initial_data.len()).max(1)];
+            let joint_buffer = device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Joint Buffer"),
+                    contents: bytemuck::cast_slice(&identities),
+                    usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+                }
+            );
+
             let bind_group = device.create_bind_group(
                 &wgpu::BindGroupDescriptor {
                     layout,
@@ -491,7 +795,11 @@ impl ModelInstanceGroupBook {
                         wgpu::BindGroupEntry {
                             binding: 0,
                             resource: wgpu::BindingResource::Buffer(buffer.slice(..))
-                        }
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Buffer(joint_buffer.slice(..))
+                        },
                     ],
                     label: Some("instance_bind_group"),
                 }
@@ -500,12 +808,15 @@ impl ModelInstanceGroupBook {
             (model, ModelInstanceGroup {
                 len: initial_data.len(),
                 buffer,
+                joint_buffer,
                 bind_group,
+                raws: RefCell::new(initial_data),
             })
         }).collect::<HashMap<_, _>>();
 
         Self {
             group_book,
+            shadow_dirty: Cell::new(true),
         }
     }
 
@@ -516,12 +827,54 @@ impl ModelInstanceGroupBook {
         instance: &Instance,
     ) -> Result<()> {
         let raw = instance.to_raw();
-        let ref buffer = (self.group_book.get(&instance.model).context("Invalid Instance")?).buffer;
+        let group = self.group_book.get(&instance.model).context("Invalid Instance")?;
+
+        let mut raws = group.raws.borrow_mut();
+        let old = raws.get_mut(instance.index).context("Invalid Instance")?;
+        if *old == raw {
+            return Ok(());
+        }
+        // 影を落とす / 落としていたインスタンスが変化したら影を焼き直す
+        if old.casts_shadow() || raw.casts_shadow() {
+            self.shadow_dirty.set(true);
+        }
+        *old = raw;
+
         let offset = instance.index * std::mem::size_of::<InstanceRaw>();
-        queue.write_buffer(buffer, offset as u64, bytemuck::cast_slice(&[raw]));
+        queue.write_buffer(&group.buffer, offset as u64, bytemuck::cast_slice(&[raw]));
 
         Ok(())
     }
+
+    // スキニングの結果を書き込む。影も焼き直す
+    pub fn update_joints(
+        &self,
+        queue: &wgpu::Queue,
+        instance: &Instance,
+        palette: &[cgmath::Matrix4<f32>],
+    ) -> Result<()> {
+        let group = self.group_book.get(&instance.model).context("Invalid Instance")?;
+        let offset = instance.index * palette.len() * std::mem::size_of::<cgmath::Matrix4<f32>>();
+        let palette = palette.iter().map(|m| -> [[f32; 4]; 4] { (*m).into() }).collect::<Vec<_>>();
+        queue.write_buffer(&group.joint_buffer, offset as u64, bytemuck::cast_slice(&palette));
+        if instance.casts_shadow && instance.visible {
+            self.shadow_dirty.set(true);
+        }
+
+        Ok(())
+    }
+
+    pub fn contains(&self, instance: &Instance) -> bool {
+        self.group_book.contains_key(&instance.model)
+    }
+
+    pub fn is_shadow_dirty(&self) -> bool {
+        self.shadow_dirty.get()
+    }
+
+    pub fn set_shadow_dirty(&self, dirty: bool) {
+        self.shadow_dirty.set(dirty);
+    }
 }
 
 pub struct InstanceSetting {
@@ -543,6 +896,17 @@ impl InstanceSetting {
                         },
                         count: None,
                     },
+                    // ジョイントパレット
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::StorageBuffer {
+                            dynamic: false,
+                            readonly: true,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("instance_bind_group_layout"),
             }
@@ -575,6 +939,9 @@ where
         uni_bg: &'b wgpu::BindGroup,
     ) {
         for (model, group) in model_instance_group_book.group_book.iter() {
+            if !group.has_visible() {
+                continue;
+            }
             self.draw_model_instanced(
                 model,
                 0..(group.len as u32),
@@ -0,0 +1,853 @@
+use crate::shader_settings::hdr::{self, Exposure, SceneTarget, Tonemap, Tonemapper, TonemapBindings, HDR_FORMAT};
+use crate::shader_settings::texture;
+use anyhow::*;
+use std::path::Path;
+
+// トーンマップの前後に掛ける効果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostEffect {
+    // HDR のうちに掛けるので、並びに関係なくトーンマップの前
+    Bloom,
+    Fxaa,
+    Vignette,
+    // .cube の 3D LUT。読み込んでいなければ何もしない
+    ColorGrading,
+}
+
+impl PostEffect {
+    const ALL: &'static [(&'static str, PostEffect)] = &[
+        ("bloom", PostEffect::Bloom),
+        ("fxaa", PostEffect::Fxaa),
+        ("vignette", PostEffect::Vignette),
+        ("color_grading", PostEffect::ColorGrading),
+    ];
+
+    fn parse(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, effect)| *effect)
+            .with_context(|| format!("Unknown post effect: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BloomSetting {
+    // この明るさ (HDR の値) を超えた部分が滲む
+    pub threshold: f32,
+    // 閾値の前後をなめらかに繋ぐ幅
+    pub knee: f32,
+    pub intensity: f32,
+}
+
+impl Default for BloomSetting {
+    fn default() -> Self {
+        Self {
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VignetteSetting {
+    // 0 で効果なし、1 で四隅が真っ黒
+    pub intensity: f32,
+    // 中心から四隅までを 1 とした距離
+    pub radius: f32,
+    pub smoothness: f32,
+}
+
+impl Default for VignetteSetting {
+    fn default() -> Self {
+        Self {
+            intensity: 0.5,
+            radius: 1.0,
+            smoothness: 0.6,
+        }
+    }
+}
+
+// 縮小バッファの最大段数。1段ごとに縦横半分になる
+const BLOOM_LEVELS: usize = 6;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct BloomUniform {
+    texel_size: [f32; 2],
+    threshold: f32,
+    knee: f32,
+    intensity: f32,
+    prefilter: u32,
+    _padding: [f32; 2],
+}
+
+unsafe impl bytemuck::Pod for BloomUniform {}
+unsafe impl bytemuck::Zeroable for BloomUniform {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct PostUniform {
+    texel_size: [f32; 2],
+    _padding0: [f32; 2],
+    viewport: [f32; 4],
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_smoothness: f32,
+    lut_strength: f32,
+    lut_size: f32,
+    _padding1: [f32; 3],
+}
+
+unsafe impl bytemuck::Pod for PostUniform {}
+unsafe impl bytemuck::Zeroable for PostUniform {}
+
+// 1回の全画面パスで読むテクスチャとそのユニフォーム
+struct PostPass {
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+// 描き込む先の大きさごとに必要なもの。大きさかサンプル数が変わったら作り直す
+pub struct PostTargets {
+    pub scene: SceneTarget,
+    tonemap_bindings: TonemapBindings,
+    // LDR の効果を交互に読み書きする2枚
+    ldr: Vec<texture::Texture>,
+    ldr_passes: Vec<PostPass>,
+    // bloom_mips[i] は縦横 1 / 2^(i+1)
+    bloom_mips: Vec<texture::Texture>,
+    // bloom_down[i] は scene (i = 0) か bloom_mips[i-1] を読んで bloom_mips[i] に書く
+    bloom_down: Vec<PostPass>,
+    // bloom_up[i] は bloom_mips[i] を読んで bloom_mips[i-1] か scene (i = 0) に足す
+    bloom_up: Vec<PostPass>,
+}
+
+// 3D LUT。.cube と同じく赤が一番速く変わる並び
+pub struct ColorLut {
+    pub size: u32,
+    bind_group: wgpu::BindGroup,
+}
+
+// HDR のシーンを効果を掛けながらスワップチェーンの形式に落とす
+pub struct PostProcess {
+    pub tonemapper: Tonemapper,
+    // LDR の効果はこの順に掛ける。bool は有効かどうか
+    pub effects: Vec<(PostEffect, bool)>,
+    pub bloom: BloomSetting,
+    pub vignette: VignetteSetting,
+    // LUT を掛けた色と元の色の混ぜ具合
+    pub lut_strength: f32,
+    lut: Option<ColorLut>,
+    format: wgpu::TextureFormat,
+    layout: wgpu::BindGroupLayout,
+    lut_layout: wgpu::BindGroupLayout,
+    bloom_down_pipeline: wgpu::RenderPipeline,
+    // 1段上へ足し込む。最後は scene に足し戻す
+    bloom_up_pipeline: wgpu::RenderPipeline,
+    fxaa_pipeline: wgpu::RenderPipeline,
+    vignette_pipeline: wgpu::RenderPipeline,
+    grading_pipeline: wgpu::RenderPipeline,
+}
+
+impl PostProcess {
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("post_bind_group_layout"),
+            }
+        );
+        let lut_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D3,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("lut_bind_group_layout"),
+            }
+        );
+
+        let additive = wgpu::BlendDescriptor {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        // 透明な背景のスクリーンショットのため、アルファは描き込む先のものを残す
+        let keep_alpha = wgpu::BlendDescriptor {
+            src_factor: wgpu::BlendFactor::Zero,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+
+        let bloom_down_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "Bloom Downsample Pipeline",
+            &[&layout],
+            wgpu::include_spirv!("../bloom_downsample.frag.spv"),
+            HDR_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+        let bloom_up_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "Bloom Upsample Pipeline",
+            &[&layout],
+            wgpu::include_spirv!("../bloom_upsample.frag.spv"),
+            HDR_FORMAT,
+            additive,
+            keep_alpha,
+        );
+        let fxaa_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "FXAA Pipeline",
+            &[&layout],
+            wgpu::include_spirv!("../post_fxaa.frag.spv"),
+            format,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+        let vignette_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "Vignette Pipeline",
+            &[&layout],
+            wgpu::include_spirv!("../post_vignette.frag.spv"),
+            format,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+        let grading_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "Color Grading Pipeline",
+            &[&layout, &lut_layout],
+            wgpu::include_spirv!("../post_grading.frag.spv"),
+            format,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+
+        Self {
+            tonemapper: Tonemapper::new(device, format),
+            effects: vec![
+                (PostEffect::Bloom, true),
+                (PostEffect::ColorGrading, true),
+                (PostEffect::Vignette, false),
+                (PostEffect::Fxaa, false),
+            ],
+            bloom: BloomSetting::default(),
+            vignette: VignetteSetting::default(),
+            lut_strength: 1.0,
+            lut: None,
+            format,
+            layout,
+            lut_layout,
+            bloom_down_pipeline,
+            bloom_up_pipeline,
+            fxaa_pipeline,
+            vignette_pipeline,
+            grading_pipeline,
+        }
+    }
+
+    pub fn is_enabled(&self, effect: PostEffect) -> bool {
+        self.effects.iter().any(|&(e, enabled)| e == effect && enabled)
+    }
+
+    pub fn set_enabled(&mut self, effect: PostEffect, enabled: bool) {
+        for (e, en) in self.effects.iter_mut() {
+            if *e == effect {
+                *en = enabled;
+            }
+        }
+    }
+
+    pub fn toggle(&mut self, effect: PostEffect) {
+        self.set_enabled(effect, !self.is_enabled(effect));
+    }
+
+    // order に並べた効果を先頭に置く。書かなかったものは今の順のまま後ろに残す
+    pub fn set_order(&mut self, order: &[PostEffect]) {
+        let mut effects = order
+            .iter()
+            .filter_map(|&e| self.effects.iter().find(|(x, _)| *x == e).copied())
+            .collect::<Vec<_>>();
+        for &(e, enabled) in self.effects.iter() {
+            if !order.contains(&e) {
+                effects.push((e, enabled));
+            }
+        }
+        self.effects = effects;
+    }
+
+    pub fn lut(&self) -> Option<&ColorLut> {
+        self.lut.as_ref()
+    }
+
+    pub fn load_lut<P: AsRef<Path>>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+    ) -> Result<()> {
+        let src = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Cannot read {:?}", path.as_ref()))?;
+        let (size, table) = parse_cube(&src)
+            .with_context(|| format!("Invalid LUT {:?}", path.as_ref()))?;
+        self.lut = Some(self.create_lut(device, queue, size, &table));
+        Ok(())
+    }
+
+    pub fn clear_lut(&mut self) {
+        self.lut = None;
+    }
+
+    fn create_lut(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        size: u32,
+        table: &[[f32; 3]],
+    ) -> ColorLut {
+        // 補間はテクスチャに任せるので 8bit で足りる
+        let texels = table
+            .iter()
+            .flat_map(|c| {
+                let q = |v: f32| (v.max(0.0).min(1.0) * 255.0).round() as u8;
+                vec![q(c[0]), q(c[1]), q(c[2]), 255]
+            })
+            .collect::<Vec<_>>();
+        let extent = wgpu::Extent3d { width: size, height: size, depth: size };
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label: Some("lut_texture"),
+                size: extent,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D3,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+            }
+        );
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &texels,
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 4 * size,
+                rows_per_image: size,
+            },
+            extent,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            }
+        );
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &self.lut_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&sampler),
+                    },
+                ],
+                label: Some("lut_bind_group"),
+            }
+        );
+
+        ColorLut { size, bind_group }
+    }
+
+    // 設定ファイルの書式 (1行1つ、# 以降はコメント)
+    //
+    //   order <effect>...                 LDR の効果を掛ける順
+    //   enable <effect> / disable <effect>
+    //   bloom <threshold> <knee> <intensity>
+    //   vignette <intensity> <radius> <smoothness>
+    //   lut <path> [strength]             .cube ファイル。path は設定ファイルからの相対
+    //   tonemap <operator> [white]        aces, reinhard, filmic のいずれか
+    //   exposure manual <ev>
+    //   exposure auto <ev> [key] [adaptation_time]
+    //
+    // effect は bloom, fxaa, vignette, color_grading のいずれか
+    pub fn load<P: AsRef<Path>>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read {:?}", path))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let err = || format!("line {}: {}", n + 1, line);
+            let floats = |count: usize| -> Result<Vec<f32>> {
+                if words.len() != count + 1 {
+                    bail!("Expected {} values: {}", count, err());
+                }
+                words[1..]
+                    .iter()
+                    .map(|w| w.parse::<f32>().with_context(err))
+                    .collect()
+            };
+            match words[0] {
+                "order" => {
+                    let order = words[1..]
+                        .iter()
+                        .map(|w| PostEffect::parse(w))
+                        .collect::<Result<Vec<_>>>()
+                        .with_context(err)?;
+                    self.set_order(&order);
+                }
+                "enable" | "disable" => {
+                    let effect = PostEffect::parse(words.get(1).with_context(err)?).with_context(err)?;
+                    self.set_enabled(effect, words[0] == "enable");
+                }
+                "bloom" => {
+                    let v = floats(3)?;
+                    self.bloom = BloomSetting { threshold: v[0], knee: v[1], intensity: v[2] };
+                }
+                "vignette" => {
+                    let v = floats(3)?;
+                    self.vignette = VignetteSetting { intensity: v[0], radius: v[1], smoothness: v[2] };
+                }
+                "lut" => {
+                    let lut_path = dir.join(words.get(1).with_context(err)?);
+                    self.load_lut(device, queue, &lut_path).with_context(err)?;
+                    if let Some(strength) = words.get(2) {
+                        self.lut_strength = strength.parse::<f32>().with_context(err)?;
+                    }
+                }
+                "tonemap" => {
+                    let tonemapper = &mut self.tonemapper;
+                    tonemapper.tonemap = Tonemap::parse(words.get(1).with_context(err)?).with_context(err)?;
+                    if let Some(white) = words.get(2) {
+                        tonemapper.white = white.parse::<f32>().with_context(err)?;
+                    }
+                    if words.len() > 3 {
+                        bail!("Too many values: {}", err());
+                    }
+                }
+                "exposure" => {
+                    if words.len() < 3 {
+                        bail!("Expected manual <ev> or auto <ev> [key] [adaptation_time]: {}", err());
+                    }
+                    let values = words[2..]
+                        .iter()
+                        .map(|w| w.parse::<f32>().with_context(err))
+                        .collect::<Result<Vec<_>>>()?;
+                    let tonemapper = &mut self.tonemapper;
+                    match (words[1], values.as_slice()) {
+                        ("manual", &[ev]) => tonemapper.exposure = Exposure::Manual(ev),
+                        ("auto", &[ev, ref rest @ ..]) if rest.len() <= 2 => {
+                            tonemapper.exposure = Exposure::Auto(ev);
+                            if let Some(&key) = rest.get(0) {
+                                tonemapper.key = key;
+                            }
+                            if let Some(&time) = rest.get(1) {
+                                tonemapper.adaptation_time = time;
+                            }
+                        }
+                        _ => bail!("Expected manual <ev> or auto <ev> [key] [adaptation_time]: {}", err()),
+                    }
+                }
+                _ => bail!("Unknown directive: {}", err()),
+            }
+        }
+        Ok(())
+    }
+
+    fn create_pass(
+        &self,
+        device: &wgpu::Device,
+        source: &texture::Texture,
+        size: u64,
+        label: &str,
+    ) -> PostPass {
+        let uniform_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&source.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                    },
+                ],
+                label: Some(label),
+            }
+        );
+        PostPass { uniform_buffer, bind_group }
+    }
+
+    // 中間のテクスチャはフレームをまたいで使い回す
+    pub fn create_targets(
+        &self,
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> PostTargets {
+        let scene = SceneTarget::new(device, width, height, sample_count);
+        let tonemap_bindings = self.tonemapper.bind(device, &scene);
+
+        let post_size = std::mem::size_of::<PostUniform>() as u64;
+        let ldr = (0..2)
+            .map(|_| texture::Texture::create_render_texture(device, width, height, self.format, "post_texture"))
+            .collect::<Vec<_>>();
+        let ldr_passes = ldr
+            .iter()
+            .map(|t| self.create_pass(device, t, post_size, "post_pass"))
+            .collect();
+
+        // 縦横どちらかが 2 を切る手前まで縮める
+        let bloom_mips = (1..=BLOOM_LEVELS as u32)
+            .map(|i| (width >> i, height >> i))
+            .take_while(|&(w, h)| w >= 2 && h >= 2)
+            .map(|(w, h)| texture::Texture::create_render_texture(device, w, h, HDR_FORMAT, "bloom_texture"))
+            .collect::<Vec<_>>();
+        let bloom_size = std::mem::size_of::<BloomUniform>() as u64;
+        let bloom_down = (0..bloom_mips.len())
+            .map(|i| {
+                let source = if i == 0 { &scene.color } else { &bloom_mips[i - 1] };
+                self.create_pass(device, source, bloom_size, "bloom_down_pass")
+            })
+            .collect();
+        let bloom_up = bloom_mips
+            .iter()
+            .map(|t| self.create_pass(device, t, bloom_size, "bloom_up_pass"))
+            .collect();
+
+        PostTargets {
+            scene,
+            tonemap_bindings,
+            ldr,
+            ldr_passes,
+            bloom_mips,
+            bloom_down,
+            bloom_up,
+        }
+    }
+
+    // targets.scene に描いたものを output に仕上げる。
+    // viewport は分割して描くときの、画像全体に対するこのタイルの位置と大きさ (0..1)
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        targets: &PostTargets,
+        output: &wgpu::TextureView,
+        adapt_dt: Option<f32>,
+        viewport: [f32; 4],
+    ) {
+        if self.is_enabled(PostEffect::Bloom) && !targets.bloom_mips.is_empty() {
+            self.render_bloom(encoder, queue, targets);
+        }
+
+        // (パイプライン, LUT のバインドグループ)
+        let passes = self.effects
+            .iter()
+            .filter(|&&(_, enabled)| enabled)
+            .filter_map(|&(effect, _)| match effect {
+                PostEffect::Bloom => None,
+                PostEffect::Fxaa => Some((&self.fxaa_pipeline, None)),
+                PostEffect::Vignette => Some((&self.vignette_pipeline, None)),
+                PostEffect::ColorGrading => self.lut
+                    .as_ref()
+                    .map(|lut| (&self.grading_pipeline, Some(&lut.bind_group))),
+            })
+            .collect::<Vec<_>>();
+
+        // LDR の効果が無ければ直接 output に落とす
+        let tonemap_output = if passes.is_empty() { output } else { &targets.ldr[0].view };
+        self.tonemapper.render(encoder, queue, &targets.tonemap_bindings, tonemap_output, adapt_dt);
+        if passes.is_empty() {
+            return;
+        }
+
+        let scene = &targets.scene;
+        let lut_size = self.lut.as_ref().map_or(1.0, |lut| lut.size as f32);
+        let uniform = PostUniform {
+            texel_size: [1.0 / scene.width as f32, 1.0 / scene.height as f32],
+            _padding0: [0.0; 2],
+            viewport,
+            vignette_intensity: self.vignette.intensity,
+            vignette_radius: self.vignette.radius,
+            vignette_smoothness: self.vignette.smoothness,
+            lut_strength: self.lut_strength,
+            lut_size,
+            _padding1: [0.0; 3],
+        };
+        for pass in targets.ldr_passes.iter() {
+            queue.write_buffer(&pass.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        }
+
+        for (i, &(pipeline, lut_bind_group)) in passes.iter().enumerate() {
+            let dest = if i + 1 == passes.len() { output } else { &targets.ldr[(i + 1) % 2].view };
+            let mut render_pass = begin_pass(encoder, dest, wgpu::LoadOp::Clear(wgpu::Color::BLACK));
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &targets.ldr_passes[i % 2].bind_group, &[]);
+            if let Some(bind_group) = lut_bind_group {
+                render_pass.set_bind_group(1, bind_group, &[]);
+            }
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+
+    // 明るい部分を縮めながらぼかし、拡大しながら足し合わせて scene に戻す
+    fn render_bloom(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        targets: &PostTargets,
+    ) {
+        let scene = &targets.scene;
+        let size = |i: usize| -> (u32, u32) {
+            if i == 0 {
+                (scene.width, scene.height)
+            } else {
+                (scene.width >> i, scene.height >> i)
+            }
+        };
+        let uniform = |(w, h): (u32, u32), intensity: f32, prefilter: bool| BloomUniform {
+            texel_size: [1.0 / w as f32, 1.0 / h as f32],
+            threshold: self.bloom.threshold,
+            knee: self.bloom.knee.max(0.0001),
+            intensity,
+            prefilter: prefilter as u32,
+            _padding: [0.0; 2],
+        };
+
+        for (i, pass) in targets.bloom_down.iter().enumerate() {
+            // 読む側 (scene か1段上) の大きさ
+            let u = uniform(size(i), 1.0, i == 0);
+            queue.write_buffer(&pass.uniform_buffer, 0, bytemuck::cast_slice(&[u]));
+
+            let mut render_pass = begin_pass(
+                encoder,
+                &targets.bloom_mips[i].view,
+                wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+            );
+            render_pass.set_pipeline(&self.bloom_down_pipeline);
+            render_pass.set_bind_group(0, &pass.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        for (i, pass) in targets.bloom_up.iter().enumerate().rev() {
+            let (dest, intensity) = if i == 0 {
+                (&scene.color.view, self.bloom.intensity)
+            } else {
+                (&targets.bloom_mips[i - 1].view, 1.0)
+            };
+            let u = uniform(size(i + 1), intensity, false);
+            queue.write_buffer(&pass.uniform_buffer, 0, bytemuck::cast_slice(&[u]));
+
+            let mut render_pass = begin_pass(encoder, dest, wgpu::LoadOp::Load);
+            render_pass.set_pipeline(&self.bloom_up_pipeline);
+            render_pass.set_bind_group(0, &pass.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+fn begin_pass<'a>(
+    encoder: &'a mut wgpu::CommandEncoder,
+    view: &'a wgpu::TextureView,
+    load: wgpu::LoadOp<wgpu::Color>,
+) -> wgpu::RenderPass<'a> {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        color_attachments: &[
+            wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load,
+                    store: true,
+                }
+            }
+        ],
+        depth_stencil_attachment: None,
+    })
+}
+
+// Adobe の .cube 形式 (3D のみ)。値の並びと LUT の一辺の長さを返す
+fn parse_cube(src: &str) -> Result<(u32, Vec<[f32; 3]>)> {
+    let mut size = None;
+    let mut table = Vec::new();
+    for (n, line) in src.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let err = || format!("line {}: {}", n + 1, line);
+        match words[0] {
+            "TITLE" => {}
+            "LUT_3D_SIZE" => {
+                let s = words.get(1).with_context(err)?.parse::<u32>().with_context(err)?;
+                if s < 2 {
+                    bail!("LUT size must be at least 2: {}", err());
+                }
+                size = Some(s);
+            }
+            "LUT_1D_SIZE" => bail!("1D LUT is not supported: {}", err()),
+            "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                // 0..1 以外の範囲には対応しない
+                let expected = if words[0] == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                for w in words[1..].iter() {
+                    if w.parse::<f32>().with_context(err)? != expected {
+                        bail!("Unsupported LUT domain: {}", err());
+                    }
+                }
+            }
+            _ => {
+                if words.len() != 3 {
+                    bail!("Invalid LUT entry: {}", err());
+                }
+                let mut rgb = [0.0; 3];
+                for (c, w) in rgb.iter_mut().zip(words.iter()) {
+                    *c = w.parse::<f32>().with_context(err)?;
+                }
+                table.push(rgb);
+            }
+        }
+    }
+
+    let size = size.context("LUT_3D_SIZE is missing")?;
+    let expected = (size * size * size) as usize;
+    if table.len() != expected {
+        bail!("LUT has {} entries, expected {}", table.len(), expected);
+    }
+    Ok((size, table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 赤が一番速く変わる順に並べた恒等 LUT
+    fn identity_cube(size: u32) -> String {
+        let mut src = format!("TITLE \"identity\"\n# comment\nLUT_3D_SIZE {}\nDOMAIN_MIN 0 0 0\nDOMAIN_MAX 1 1 1\n", size);
+        let max = (size - 1) as f32;
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    src.push_str(&format!("{} {} {}\n", r as f32 / max, g as f32 / max, b as f32 / max));
+                }
+            }
+        }
+        src
+    }
+
+    #[test]
+    fn parse_cube_reads_identity() {
+        let (size, table) = parse_cube(&identity_cube(3)).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(table.len(), 27);
+        assert_eq!(table[0], [0.0, 0.0, 0.0]);
+        assert_eq!(table[1], [0.5, 0.0, 0.0]);
+        assert_eq!(table[3], [0.0, 0.5, 0.0]);
+        assert_eq!(table[26], [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn parse_cube_rejects_malformed_input() {
+        let bad = [
+            // 個数が足りない
+            "LUT_3D_SIZE 2\n0 0 0\n",
+            // サイズが無い
+            "0 0 0\n",
+            "LUT_3D_SIZE 1\n0 0 0\n",
+            "LUT_3D_SIZE x\n",
+            "LUT_1D_SIZE 16\n",
+            "LUT_3D_SIZE 2\nDOMAIN_MAX 2 2 2\n",
+            "LUT_3D_SIZE 2\n0 0\n",
+            "LUT_3D_SIZE 2\n0 0 red\n",
+        ];
+        for src in bad.iter() {
+            assert!(parse_cube(src).is_err(), "accepted {:?}", src);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::shader_settings::bookmark::CameraPose;
+use crate::shader_settings::bounds::BoundingSphere;
+use anyhow::*;
+use cgmath::*;
+use std::path::{Path, PathBuf};
+
+// 連番画像の書き出し設定。画像の大きさはウィンドウとは関係ない
+#[derive(Debug, Clone)]
+pub struct SequenceSetting {
+    pub width: u32,
+    pub height: u32,
+    // アニメーションはこの間隔で進める
+    pub fps: f32,
+    pub turntable_degrees: f32,
+    pub turntable_frames: usize,
+    // この下に turntable_<時刻> などのディレクトリを作る
+    pub dir: PathBuf,
+}
+
+impl Default for SequenceSetting {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            fps: 30.0,
+            turntable_degrees: 360.0,
+            turntable_frames: 120,
+            dir: PathBuf::from("renders"),
+        }
+    }
+}
+
+// 時刻付きのカメラ姿勢の列。操作を記録して後から一定間隔で再生する
+#[derive(Debug, Clone, Default)]
+pub struct CameraPath {
+    pub keys: Vec<(f32, CameraPose)>,
+}
+
+impl CameraPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 1行1キー。key <time> <x> <y> <z> <yaw> <pitch> <fovy> <kind> <ortho_height>
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let src = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Cannot read {:?}", path.as_ref()))?;
+        let mut keys = Vec::new();
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let err = || format!("line {}: {}", n + 1, line);
+            if words[0] != "key" || words.len() < 10 {
+                bail!("Invalid camera path key: {}", err());
+            }
+            let time = words[1].parse::<f32>().with_context(err)?;
+            keys.push((time, CameraPose::parse_fields(&words[2..]).with_context(err)?));
+        }
+        keys.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        Ok(Self { keys })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut src = String::from("# key <time> <x> <y> <z> <yaw> <pitch> <fovy> <perspective|orthographic> <ortho_height>\n");
+        for (time, pose) in self.keys.iter() {
+            src.push_str(&format!("key {} {}\n", time, pose.to_fields()));
+        }
+        std::fs::write(path.as_ref(), src).with_context(|| format!("Cannot write {:?}", path.as_ref()))
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map(|k| k.0).unwrap_or(0.0)
+    }
+
+    // 直前のキーから dt 後の姿勢を追加する
+    pub fn record(&mut self, dt: f32, pose: CameraPose) {
+        let time = match self.keys.last() {
+            Some(k) => k.0 + dt,
+            None => 0.0,
+        };
+        let n = self.keys.len();
+        // 止まっている間は最後のキーの時刻だけ進める
+        if n >= 2 && self.keys[n - 1].1 == pose && self.keys[n - 2].1 == pose {
+            self.keys[n - 1].0 = time;
+        } else {
+            self.keys.push((time, pose));
+        }
+    }
+
+    pub fn sample(&self, time: f32) -> Option<CameraPose> {
+        let first = self.keys.first()?;
+        if time <= first.0 {
+            return Some(first.1);
+        }
+        for w in self.keys.windows(2) {
+            let (t0, p0) = w[0];
+            let (t1, p1) = w[1];
+            if time <= t1 {
+                let t = if t1 > t0 { (time - t0) / (t1 - t0) } else { 1.0 };
+                return Some(p0.lerp(&p1, t));
+            }
+        }
+        self.keys.last().map(|k| k.1)
+    }
+
+    // 1秒に fps 枚の間隔で最後のキーまで並べる
+    pub fn frames(&self, fps: f32) -> Vec<CameraPose> {
+        if self.keys.is_empty() || fps <= 0.0 {
+            return Vec::new();
+        }
+        let count = (self.duration() * fps).floor() as usize + 1;
+        (0..count)
+            .filter_map(|i| self.sample(i as f32 / fps))
+            .collect()
+    }
+}
+
+// 球の中心の周りを y 軸まわりに degrees だけ frames 枚で回る。360 度の倍数なら最後の1枚は
+// 終点の手前で止めて繋がるようにし、それ以外は終点まで回る。仰角などは start を引き継ぎ、
+// 球全体が収まる距離まで離れる
+pub fn turntable(
+    start: &CameraPose,
+    sphere: &BoundingSphere,
+    degrees: f32,
+    frames: usize,
+) -> Vec<CameraPose> {
+    let radius = sphere.radius.max(0.001);
+    // 横長の画面を想定して縦の視野角で測る
+    let distance = radius / (start.fovy / 2.0).sin();
+    let ortho_height = radius * 2.0;
+    let looped = degrees != 0.0 && degrees % 360.0 == 0.0;
+    let steps = if looped { frames } else { frames.saturating_sub(1) };
+
+    (0..frames)
+        .map(|i| {
+            let t = if steps > 0 { i as f32 / steps as f32 } else { 0.0 };
+            let yaw = start.yaw + Rad::from(Deg(degrees * t));
+            let dir = Vector3::new(
+                yaw.0.cos() * start.pitch.0.cos(),
+                start.pitch.0.sin(),
+                yaw.0.sin() * start.pitch.0.cos(),
+            );
+            CameraPose {
+                position: sphere.center - dir * distance,
+                yaw,
+                ortho_height,
+                ..*start
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_settings::camera::ProjectionKind;
+
+    fn pose(x: f32) -> CameraPose {
+        CameraPose {
+            position: Point3::new(x, 0.0, 0.0),
+            yaw: Rad(0.0),
+            pitch: Rad(0.0),
+            fovy: Deg(60.0).into(),
+            kind: ProjectionKind::Perspective,
+            ortho_height: 2.0,
+        }
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    fn sphere() -> BoundingSphere {
+        BoundingSphere { center: Point3::new(1.0, 2.0, 3.0), radius: 1.0 }
+    }
+
+    #[test]
+    fn full_turn_stops_one_step_before_the_start() {
+        let poses = turntable(&pose(0.0), &sphere(), 360.0, 4);
+        let yaws = poses.iter().map(|p| Deg::from(p.yaw).0).collect::<Vec<_>>();
+        assert_eq!(poses.len(), 4);
+        for (yaw, expected) in yaws.iter().zip([0.0, 90.0, 180.0, 270.0].iter()) {
+            assert_near(*yaw, *expected);
+        }
+    }
+
+    #[test]
+    fn partial_turn_reaches_the_end() {
+        let poses = turntable(&pose(0.0), &sphere(), 90.0, 4);
+        assert_near(Deg::from(poses[0].yaw).0, 0.0);
+        assert_near(Deg::from(poses[3].yaw).0, 90.0);
+        assert_eq!(turntable(&pose(0.0), &sphere(), 90.0, 1).len(), 1);
+    }
+
+    #[test]
+    fn turntable_keeps_the_sphere_at_a_fixed_distance() {
+        let sphere = sphere();
+        let distances = turntable(&pose(0.0), &sphere, 360.0, 8)
+            .iter()
+            .map(|p| (p.position - sphere.center).magnitude())
+            .collect::<Vec<_>>();
+        // 縦の視野角 60 度の半分で半径 1 の球が収まる距離
+        for d in distances {
+            assert_near(d, 2.0);
+        }
+    }
+
+    #[test]
+    fn sample_interpolates_and_clamps() {
+        let path = CameraPath { keys: vec![(0.0, pose(0.0)), (2.0, pose(4.0))] };
+        assert_near(path.sample(-1.0).unwrap().position.x, 0.0);
+        assert_near(path.sample(0.5).unwrap().position.x, 1.0);
+        assert_near(path.sample(3.0).unwrap().position.x, 4.0);
+        assert!(CameraPath::new().sample(0.0).is_none());
+    }
+
+    #[test]
+    fn frames_cover_the_whole_path() {
+        let path = CameraPath { keys: vec![(0.0, pose(0.0)), (1.0, pose(2.0))] };
+        let xs = path.frames(4.0).iter().map(|p| p.position.x).collect::<Vec<_>>();
+        assert_eq!(xs.len(), 5);
+        assert_near(xs[2], 1.0);
+        assert_near(xs[4], 2.0);
+        assert!(path.frames(0.0).is_empty());
+    }
+
+    #[test]
+    fn record_merges_stationary_keys() {
+        let mut path = CameraPath::new();
+        path.record(0.1, pose(0.0));
+        path.record(0.1, pose(0.0));
+        path.record(0.1, pose(0.0));
+        path.record(0.1, pose(0.0));
+        path.record(0.1, pose(1.0));
+        let times = path.keys.iter().map(|k| k.0).collect::<Vec<_>>();
+        assert_eq!(path.keys.len(), 3);
+        assert_near(times[0], 0.0);
+        assert_near(times[1], 0.3);
+        assert_near(times[2], 0.4);
+        assert_eq!(path.keys[2].1, pose(1.0));
+    }
+}
@@ -0,0 +1,294 @@
+use crate::shader_settings::texture;
+use crate::shader_settings::light::Light;
+use crate::shader_settings::debug_line::{LineList, LineRenderer};
+use wgpu::util::DeviceExt;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct ShadowDebugUniform {
+    layer: u32,
+    znear: f32,
+    zfar: f32,
+    _p: u32,
+}
+
+unsafe impl bytemuck::Pod for ShadowDebugUniform {}
+unsafe impl bytemuck::Zeroable for ShadowDebugUniform {}
+
+// 焼いたシャドウマップを画面の隅に表示する
+pub struct ShadowDebug {
+    pub show_overlay: bool,
+    pub show_frustums: bool,
+    // light_book 中の何番目の光源を表示するか
+    pub light_index: usize,
+    uniform: ShadowDebugUniform,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    overlay_pipeline: wgpu::RenderPipeline,
+    frustum_lines: LineList,
+    line_renderer: LineRenderer,
+}
+
+impl ShadowDebug {
+    // 画面の短辺に対するオーバーレイの大きさ
+    const OVERLAY_RATIO: f32 = 0.3;
+    const OVERLAY_MARGIN: f32 = 8.0;
+
+    pub fn new(
+        device: &wgpu::Device,
+        sc_desc: &wgpu::SwapChainDescriptor,
+        shadow_texture: &texture::Texture,
+        uniform_layout: &wgpu::BindGroupLayout,
+        // シーンを描き込むテクスチャの形式
+        scene_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let uniform = ShadowDebugUniform {
+            layer: 0,
+            znear: 0.1,
+            zfar: 100.0,
+            _p: 0,
+        };
+
+        let uniform_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Shadow Debug Uniform Buffer"),
+                contents: bytemuck::cast_slice(&[uniform]),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            }
+        );
+
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2Array,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("shadow_debug_bind_group_layout"),
+            }
+        );
+
+        let sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Nearest,
+                min_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            }
+        );
+
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&shadow_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                    },
+                ],
+                label: Some("shadow_debug_bind_group"),
+            }
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Shadow Debug Pipeline Layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let vs_module = device.create_shader_module(wgpu::include_spirv!("../shadow_debug.vert.spv"));
+        let fs_module = device.create_shader_module(wgpu::include_spirv!("../shadow_debug.frag.spv"));
+
+        let overlay_pipeline = device.create_render_pipeline(
+            &wgpu::RenderPipelineDescriptor {
+                label: Some("Shadow Debug Pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &vs_module,
+                    entry_point: "main",
+                },
+                fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                    module: &fs_module,
+                    entry_point: "main",
+                }),
+                rasterization_state: Some(
+                    wgpu::RasterizationStateDescriptor {
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: wgpu::CullMode::None,
+                        depth_bias: 0,
+                        depth_bias_slope_scale: 0.0,
+                        depth_bias_clamp: 0.0,
+                        clamp_depth: false,
+                    }
+                ),
+                color_states: &[
+                    wgpu::ColorStateDescriptor {
+                        format: sc_desc.format,
+                        color_blend: wgpu::BlendDescriptor::REPLACE,
+                        alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                        write_mask: wgpu::ColorWrite::ALL,
+                    },
+                ],
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                depth_stencil_state: None,
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: wgpu::IndexFormat::Uint32,
+                    vertex_buffers: &[],
+                },
+                sample_count: 1,
+                sample_mask: !0,
+                alpha_to_coverage_enabled: false,
+            }
+        );
+
+        // オーバーレイはトーンマップ後の画面に描くが、錐台はシーンと同じパスで描く
+        let line_renderer = LineRenderer::new(device, scene_format, uniform_layout, sample_count);
+
+        Self {
+            show_overlay: false,
+            show_frustums: false,
+            light_index: 0,
+            uniform,
+            uniform_buffer,
+            bind_group,
+            overlay_pipeline,
+            frustum_lines: LineList::new(),
+            line_renderer,
+        }
+    }
+
+    pub fn next_light(&mut self, light_num: usize) {
+        if light_num == 0 {
+            self.light_index = 0;
+        } else {
+            self.light_index = (self.light_index + 1) % light_num;
+        }
+    }
+
+    pub fn set_sample_count(
+        &mut self,
+        device: &wgpu::Device,
+        scene_format: wgpu::TextureFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+        sample_count: u32,
+    ) {
+        self.line_renderer.set_sample_count(device, scene_format, uniform_layout, sample_count);
+    }
+
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        lights: &[Rc<RefCell<Light>>],
+    ) {
+        if self.show_overlay {
+            if let Some(r_light) = lights.get(self.light_index) {
+                let light = r_light.borrow();
+                let projection = light.shadow.projection();
+                self.uniform.layer = light.shadow.layer() as u32;
+                self.uniform.znear = projection.znear();
+                self.uniform.zfar = projection.zfar();
+                queue.write_buffer(
+                    &self.uniform_buffer,
+                    0,
+                    bytemuck::cast_slice(&[self.uniform])
+                );
+            }
+        }
+
+        self.frustum_lines.clear();
+        if self.show_frustums {
+            for r_light in lights.iter() {
+                let light = r_light.borrow();
+                self.frustum_lines.frustum(light.shadow.view_proj(), light.color);
+            }
+        }
+        self.line_renderer.upload(device, queue, &self.frustum_lines);
+    }
+
+    // メインのレンダーパスの中で呼ぶ
+    pub fn draw_frustums<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, uni_bg: &'a wgpu::BindGroup) {
+        if self.show_frustums {
+            self.line_renderer.draw(render_pass, uni_bg);
+        }
+    }
+
+    // メインのレンダーパスの後に、描画済みのフレームに重ねる
+    pub fn render_overlay(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        sc_desc: &wgpu::SwapChainDescriptor,
+    ) {
+        if !self.show_overlay {
+            return;
+        }
+
+        let size = sc_desc.width.min(sc_desc.height) as f32 * Self::OVERLAY_RATIO;
+        let x = sc_desc.width as f32 - size - Self::OVERLAY_MARGIN;
+        let y = sc_desc.height as f32 - size - Self::OVERLAY_MARGIN;
+        if x < 0.0 || y < 0.0 {
+            return;
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    }
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_viewport(x, y, size, size, 0.0, 1.0);
+        render_pass.set_pipeline(&self.overlay_pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -11,6 +11,10 @@ pub struct ShadowUniform {
     tex_width: u32,
     tex_height: u32,
     darkness: f32,
+    filter_mode: u32,
+    filter_samples: u32,
+    filter_radius: f32,
+    depth_bias: f32,
     _p: u32,
 }
 
@@ -18,38 +22,139 @@ unsafe impl bytemuck::Pod for ShadowUniform {}
 unsafe impl bytemuck::Zeroable for ShadowUniform {}
 
 impl ShadowUniform {
-    fn new(tex_width: u32, tex_height: u32, darkness: f32) -> Self {
-        Self {
+    fn new(
+        tex_width: u32,
+        tex_height: u32,
+        darkness: f32,
+        filter: ShadowFilter,
+        bias: ShadowBias,
+    ) -> Self {
+        let mut res = Self {
             view_proj: cgmath::Matrix4::identity(),
             tex_width,
             tex_height,
             darkness,
+            filter_mode: 0,
+            filter_samples: 0,
+            filter_radius: 0.0,
+            depth_bias: bias.shader_bias,
             _p: 0,
+        };
+        res.set_filter(filter);
+        res
+    }
+
+    fn set_filter(&mut self, filter: ShadowFilter) {
+        use ShadowFilter::*;
+
+        let (mode, samples, radius) = match filter {
+            Hard => (0, 1, 0.0),
+            Pcf { kernel } => (1, kernel.max(1).min(Self::MAX_PCF_KERNEL), 1.0),
+            Poisson { samples, spread } => (2, samples.max(1).min(Self::MAX_SAMPLES), spread),
+            Pcss { samples, search_scale } => (3, samples.max(1).min(Self::MAX_SAMPLES), search_scale),
+        };
+        self.filter_mode = mode;
+        self.filter_samples = samples;
+        self.filter_radius = radius;
+    }
+
+    // shader.frag の POISSON_DISK の要素数と合わせること
+    const MAX_SAMPLES: u32 = 16;
+    // PCF は kernel^2 回読むので、大きすぎる値で GPU が止まらないように抑える
+    const MAX_PCF_KERNEL: u32 = 7;
+}
+
+/// 影の輪郭をどうぼかすか
+#[derive(Debug, Clone, Copy)]
+pub enum ShadowFilter {
+    /// 1回だけハードウェア比較する (1テクセル分のくっきりした影)
+    Hard,
+    /// kernel x kernel テクセルの PCF
+    Pcf {
+        kernel: u32,
+    },
+    /// Poisson disk 上の samples 点で PCF。spread はテクセル単位の半径
+    Poisson {
+        samples: u32,
+        spread: f32,
+    },
+    /// Percentage-closer soft shadows。半影の大きさは光源の radius で決まる
+    /// search_scale は遮蔽物探索範囲の倍率
+    Pcss {
+        samples: u32,
+        search_scale: f32,
+    },
+}
+
+impl Default for ShadowFilter {
+    fn default() -> Self {
+        ShadowFilter::Hard
+    }
+}
+
+/// シャドウアクネ対策の深度バイアス
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowBias {
+    /// ラスタライズ時の固定バイアス
+    pub constant: i32,
+    /// ラスタライズ時の傾きに応じたバイアス
+    pub slope_scale: f32,
+    pub clamp: f32,
+    /// fragment shader 側で比較前に引く深度
+    pub shader_bias: f32,
+}
+
+impl Default for ShadowBias {
+    fn default() -> Self {
+        Self {
+            constant: 2,
+            slope_scale: 2.0,
+            clamp: 0.0,
+            shader_bias: 0.0,
         }
     }
 }
 
 pub struct ShadowUniformBuffer {
     pub buffer: wgpu::Buffer,
+    capacity: usize,
 }
 
 impl ShadowUniformBuffer {
-    pub fn new(device: &wgpu::Device, uniforms: &[ShadowUniform]) -> Self {
-        let buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
+    // 中身は各 ShadowMap::update で書き込まれる
+    pub fn new(device: &wgpu::Device, len: usize) -> Self {
+        let capacity = len.max(1).next_power_of_two();
+        let buffer = Self::create_buffer(device, capacity);
+
+        Self {
+            buffer,
+            capacity,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(
+            &wgpu::BufferDescriptor {
                 label: Some("Shadow Uniforms Buffer"),
-                contents: bytemuck::cast_slice(uniforms),
+                size: (capacity * std::mem::size_of::<ShadowUniform>()) as wgpu::BufferAddress,
                 usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
             }
-        );
+        )
+    }
 
-        Self {
-            buffer,
+    // len 個入らなければ作り直す。作り直した場合は中身が空なので true を返す
+    pub fn reserve(&mut self, device: &wgpu::Device, len: usize) -> bool {
+        if len <= self.capacity {
+            return false;
         }
+        self.capacity = len.next_power_of_two();
+        self.buffer = Self::create_buffer(device, self.capacity);
+        true
     }
 
     pub fn update_uniform(&mut self, queue: &wgpu::Queue, shadowmap: &ShadowMap) {
-        let offset = shadowmap.id * std::mem::size_of::<ShadowUniform>();
+        let offset = shadowmap.layer * std::mem::size_of::<ShadowUniform>();
         let offset = offset as u64;
         queue.write_buffer(
             &self.buffer,
@@ -73,18 +178,23 @@ pub enum DirUpdateWay {
 }
 
 pub struct ShadowMap {
-    id: usize,
+    // shadow_texture のレイヤ番号。ライトバッファ上のスロットと同じ
+    layer: usize,
     pub position: Point3<f32>,
     pub direction: Vector3<f32>,
     dir_update_way: DirUpdateWay,
     projection: Projection,
     pub shadow_uniform: ShadowUniform,
+    filter: ShadowFilter,
+    bias: ShadowBias,
     pub render_pipeline: wgpu::RenderPipeline,
     // pub texture: Texture,
     target_view: wgpu::TextureView,
-    // bake_layout: wgpu::BindGroupLayout,
+    bake_layout: wgpu::BindGroupLayout,
     uniform_buffer_for_bake: wgpu::Buffer,
     bake_bind_group: wgpu::BindGroup,
+    // 前回の焼き込み以降に view_proj 等が変わったか
+    dirty: bool,
 
     // pub tex_layout: wgpu::BindGroupLayout,
     // pub tex_bind_group: wgpu::BindGroup,
@@ -93,7 +203,7 @@ pub struct ShadowMap {
 impl ShadowMap {
     const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
-    fn view_config<'a>(id: usize) -> wgpu::TextureViewDescriptor<'a> {
+    fn view_config<'a>(layer: usize) -> wgpu::TextureViewDescriptor<'a> {
         wgpu::TextureViewDescriptor {
             label: Some("shadow"),
             format: None,
@@ -101,25 +211,28 @@ impl ShadowMap {
             aspect: wgpu::TextureAspect::All,
             base_mip_level: 0,
             level_count: None,
-            base_array_layer: id as u32,
+            base_array_layer: layer as u32,
             array_layer_count: std::num::NonZeroU32::new(1),
         }
     }
 
+    // レイヤは ShaderState に登録する際に割り当てられる
     pub fn new(
-        id: usize,
         position: Point3<f32>,
         init_vec: Vector3<f32>,
         darkness: f32,
         dir_update_way: DirUpdateWay,
         projection: Projection,
+        filter: ShadowFilter,
+        bias: ShadowBias,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         sc_desc: &wgpu::SwapChainDescriptor,
         instance_layout: &wgpu::BindGroupLayout,
         shadow_texture: &wgpu::Texture,
     ) -> Self {
-        let v_conf = Self::view_config(id);
+        let layer = 0;
+        let v_conf = Self::view_config(layer);
         let target_view = shadow_texture.create_view(&v_conf);
         let bake_layout = device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor {
@@ -137,7 +250,13 @@ impl ShadowMap {
                 label: Some("shadowMap_bind_group_layout"),
             }
         );
-        let shadow_uniform = ShadowUniform::new(sc_desc.width, sc_desc.height, darkness);
+        let shadow_uniform = ShadowUniform::new(
+            sc_desc.width,
+            sc_desc.height,
+            darkness,
+            filter,
+            bias,
+        );
 
         let uniform_buffer_for_bake = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -160,36 +279,28 @@ impl ShadowMap {
             }
         );
 
-        let render_pipeline_layout =
-            device.create_pipeline_layout(
-                &wgpu::PipelineLayoutDescriptor {
-                    label: Some("Render Pipeline Layout"),
-                    bind_group_layouts: &[
-                        &bake_layout,
-                        &instance_layout,
-                    ],
-                    push_constant_ranges: &[],
-                }
-            );
-
         let render_pipeline = ShadowMap::create_render_pipeline(
             device,
-            render_pipeline_layout,
-            wgpu::include_spirv!("../bake.vert.spv"),
+            &bake_layout,
+            instance_layout,
+            &bias,
         );
 
         let res = Self {
-            id,
+            layer,
             position,
             direction: init_vec,
             dir_update_way,
             projection,
             shadow_uniform,
+            filter,
+            bias,
             render_pipeline,
             target_view,
-            // bake_layout,
+            bake_layout,
             uniform_buffer_for_bake,
             bake_bind_group,
+            dirty: true,
 
             // tex_layout,
             // tex_bind_group,
@@ -253,7 +364,11 @@ impl ShadowMap {
             n,
             axis,
         );
-        self.shadow_uniform.view_proj = self.projection.calc_matrix() * m;
+        let view_proj = self.projection.calc_matrix() * m;
+        if view_proj != self.shadow_uniform.view_proj {
+            self.dirty = true;
+        }
+        self.shadow_uniform.view_proj = view_proj;
 
         shadow_uniform_buffer.update_uniform(queue, self);
 
@@ -264,12 +379,108 @@ impl ShadowMap {
         );
     }
 
+    // shadow_texture の何番目のレイヤに焼くか
+    pub fn layer(&self) -> usize {
+        self.layer
+    }
+
+    pub fn set_layer(&mut self, layer: usize, shadow_texture: &wgpu::Texture) {
+        if self.layer == layer {
+            return;
+        }
+        self.layer = layer;
+        self.target_view = shadow_texture.create_view(&Self::view_config(layer));
+        self.dirty = true;
+    }
+
+    pub fn projection(&self) -> &Projection {
+        &self.projection
+    }
+
+    // view_proj も作り直して uniform に書き込む
+    pub fn set_projection(
+        &mut self,
+        projection: Projection,
+        queue: &wgpu::Queue,
+        shadow_uniform_buffer: &mut ShadowUniformBuffer,
+    ) {
+        self.projection = projection;
+        self.dirty = true;
+        self.update_view_proj(queue, shadow_uniform_buffer);
+    }
+
+    pub fn view_proj(&self) -> Matrix4<f32> {
+        self.shadow_uniform.view_proj
+    }
+
+    pub fn filter(&self) -> ShadowFilter {
+        self.filter
+    }
+
+    pub fn set_filter(
+        &mut self,
+        filter: ShadowFilter,
+        queue: &wgpu::Queue,
+        shadow_uniform_buffer: &mut ShadowUniformBuffer,
+    ) {
+        self.filter = filter;
+        self.shadow_uniform.set_filter(filter);
+        shadow_uniform_buffer.update_uniform(queue, self);
+    }
+
+    pub fn bias(&self) -> ShadowBias {
+        self.bias
+    }
+
+    // ラスタライズ側のバイアスはパイプラインに焼き込まれているので作り直す
+    pub fn set_bias(
+        &mut self,
+        bias: ShadowBias,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        instance_layout: &wgpu::BindGroupLayout,
+        shadow_uniform_buffer: &mut ShadowUniformBuffer,
+    ) {
+        self.bias = bias;
+        self.shadow_uniform.depth_bias = bias.shader_bias;
+        self.render_pipeline = ShadowMap::create_render_pipeline(
+            device,
+            &self.bake_layout,
+            instance_layout,
+            &bias,
+        );
+        self.dirty = true;
+        shadow_uniform_buffer.update_uniform(queue, self);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // 次のフレームで強制的に焼き直す
+    pub fn invalidate(&mut self) {
+        self.dirty = true;
+    }
+
     fn create_render_pipeline(
         device: &wgpu::Device,
-        render_pipeline_layout: wgpu::PipelineLayout,
-        vert_src: wgpu::ShaderModuleSource,
+        bake_layout: &wgpu::BindGroupLayout,
+        instance_layout: &wgpu::BindGroupLayout,
+        bias: &ShadowBias,
     ) -> wgpu::RenderPipeline {
-        let vs_module = device.create_shader_module(vert_src);
+        let render_pipeline_layout =
+            device.create_pipeline_layout(
+                &wgpu::PipelineLayoutDescriptor {
+                    label: Some("Render Pipeline Layout"),
+                    bind_group_layouts: &[
+                        bake_layout,
+                        instance_layout,
+                    ],
+                    push_constant_ranges: &[],
+                }
+            );
+
+        let vs_module = device.create_shader_module(wgpu::include_spirv!("../bake.vert.spv"));
     
         // 設定値参考
         // https://github.com/gfx-rs/wgpu-rs/blob/master/examples/shadow/main.rs
@@ -288,9 +499,9 @@ impl ShadowMap {
                         // Counter clockwise の略。右手系標準ということ
                         front_face: wgpu::FrontFace::Ccw,
                         cull_mode: wgpu::CullMode::Back,
-                        depth_bias: 2,
-                        depth_bias_slope_scale: 2.0,
-                        depth_bias_clamp: 0.0,
+                        depth_bias: bias.constant,
+                        depth_bias_slope_scale: bias.slope_scale,
+                        depth_bias_clamp: bias.clamp,
                         clamp_depth: device.features().contains(wgpu::Features::DEPTH_CLAMPING),
                     }
                 ),
@@ -309,7 +520,8 @@ impl ShadowMap {
                     index_format: wgpu::IndexFormat::Uint32,
                     vertex_buffers: &[
                         // Vertex::desc()
-                        model::ModelVertex::desc()
+                        model::ModelVertex::desc(),
+                        model::MorphVertex::desc(),
                     ],
                 },
                 sample_count: 1,
@@ -327,7 +539,7 @@ impl ShadowMap {
     }
 
     pub fn render_to_texture(
-        &self,
+        &mut self,
         encoder: &mut wgpu::CommandEncoder,
         // instance_setting: &InstanceSetting,
         model_instance_group_book: &ModelInstanceGroupBook,
@@ -356,7 +568,9 @@ impl ShadowMap {
             &self.bake_bind_group,
         );
         // borrow end
-        // drop(render_pass);
+        drop(render_pass);
+
+        self.dirty = false;
 
         // queue.submit(std::iter::once(encoder.finish()));
     }
@@ -385,8 +599,12 @@ where
         uni_bg: &'b wgpu::BindGroup,
     ) {
         for (model, group) in model_instance_group_book.group_book.iter() {
+            if !group.has_shadow_caster() {
+                continue;
+            }
             for mesh in &model.meshes {
                 self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                self.set_vertex_buffer(1, mesh.morph_buffer.slice(..));
                 self.set_index_buffer(mesh.index_buffer.slice(..));
                 self.set_bind_group(0, &uni_bg, &[]);
                 self.set_bind_group(1, &group.bind_group, &[]);
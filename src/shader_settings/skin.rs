@@ -0,0 +1,281 @@
+use crate::shader_settings::animation::{Track, Interpolate};
+use cgmath::*;
+
+// スケルタルアニメーション
+// 関節 (ジョイント) の行列パレットを計算して、頂点シェーダでスキニングする
+
+#[derive(Debug, Clone, Copy)]
+pub struct Trs {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Default for Trs {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::zero(),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Trs {
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    fn blend(&self, other: &Self, t: f32) -> Self {
+        Self {
+            translation: self.translation.interpolate(&other.translation, t),
+            rotation: self.rotation.interpolate(&other.rotation, t),
+            scale: self.scale.interpolate(&other.scale, t),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkeletonNode {
+    pub parent: Option<usize>,
+    // アニメーションしていないときの姿勢
+    pub rest: Trs,
+}
+
+// 1つのノードに対するトラック。無いものは rest のまま
+#[derive(Debug, Clone, Default)]
+pub struct NodeTracks {
+    pub translation: Option<Track<Vector3<f32>>>,
+    pub rotation: Option<Track<Quaternion<f32>>>,
+    pub scale: Option<Track<Vector3<f32>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SkinClip {
+    pub name: String,
+    pub duration: f32,
+    // (ノード番号, トラック)
+    pub channels: Vec<(usize, NodeTracks)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    pub nodes: Vec<SkeletonNode>,
+    // パレットの i 番目がどのノードか
+    pub joints: Vec<usize>,
+    pub inverse_binds: Vec<Matrix4<f32>>,
+    pub clips: Vec<SkinClip>,
+}
+
+impl Skeleton {
+    pub fn joint_num(&self) -> usize {
+        self.joints.len()
+    }
+
+    pub fn clip_index(&self, name: &str) -> Option<usize> {
+        self.clips.iter().position(|c| c.name == name)
+    }
+
+    // 各レイヤのクリップを重み付きで混ぜた各ノードのローカル姿勢
+    fn sample_pose(&self, layers: &[SkinLayer]) -> Vec<Trs> {
+        let mut pose = self.nodes.iter().map(|n| n.rest).collect::<Vec<_>>();
+        let mut accum = vec![0.0f32; self.nodes.len()];
+
+        for layer in layers.iter() {
+            if layer.weight <= 0.0 {
+                continue;
+            }
+            let clip = match self.clips.get(layer.clip) {
+                Some(c) => c,
+                None => continue,
+            };
+            let time = layer.local_time(clip.duration);
+
+            for (node, tracks) in clip.channels.iter() {
+                let node = *node;
+                let mut sampled = self.nodes[node].rest;
+                if let Some(v) = tracks.translation.as_ref().and_then(|t| t.sample(time)) {
+                    sampled.translation = v;
+                }
+                if let Some(q) = tracks.rotation.as_ref().and_then(|t| t.sample(time)) {
+                    sampled.rotation = q;
+                }
+                if let Some(v) = tracks.scale.as_ref().and_then(|t| t.sample(time)) {
+                    sampled.scale = v;
+                }
+
+                // 重みの累積で割ることで、合計が1でなくても平均になる
+                accum[node] += layer.weight;
+                let t = layer.weight / accum[node];
+                pose[node] = pose[node].blend(&sampled, t);
+            }
+        }
+
+        pose
+    }
+
+    pub fn compute_palette(&self, layers: &[SkinLayer]) -> Vec<Matrix4<f32>> {
+        let pose = self.sample_pose(layers);
+
+        // 親が先に並んでいるとは限らないのでメモ化しながら辿る
+        let mut globals: Vec<Option<Matrix4<f32>>> = vec![None; self.nodes.len()];
+        fn global(
+            i: usize,
+            nodes: &[SkeletonNode],
+            pose: &[Trs],
+            globals: &mut Vec<Option<Matrix4<f32>>>,
+        ) -> Matrix4<f32> {
+            if let Some(m) = globals[i] {
+                return m;
+            }
+            let local = pose[i].to_matrix();
+            let m = match nodes[i].parent {
+                Some(p) => global(p, nodes, pose, globals) * local,
+                None => local,
+            };
+            globals[i] = Some(m);
+            m
+        }
+
+        self.joints
+            .iter()
+            .zip(self.inverse_binds.iter())
+            .map(|(&joint, inv)| global(joint, &self.nodes, &pose, &mut globals) * inv)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkinLayer {
+    pub clip: usize,
+    pub time: f32,
+    pub speed: f32,
+    pub weight: f32,
+    pub looping: bool,
+}
+
+impl SkinLayer {
+    pub fn new(clip: usize, weight: f32) -> Self {
+        Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            weight,
+            looping: true,
+        }
+    }
+
+    fn local_time(&self, duration: f32) -> f32 {
+        if duration <= 0.0 {
+            0.0
+        } else if self.looping {
+            self.time.rem_euclid(duration)
+        } else {
+            self.time.max(0.0).min(duration)
+        }
+    }
+}
+
+// インスタンスごとの再生状態。複数のクリップを重みで混ぜられる
+#[derive(Debug, Clone, Default)]
+pub struct SkinPlayer {
+    pub layers: Vec<SkinLayer>,
+}
+
+impl SkinPlayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn play(&mut self, clip: usize, weight: f32) {
+        self.layers.push(SkinLayer::new(clip, weight));
+    }
+
+    pub fn set_weight(&mut self, clip: usize, weight: f32) {
+        for layer in self.layers.iter_mut().filter(|l| l.clip == clip) {
+            layer.weight = weight;
+        }
+    }
+
+    pub fn stop(&mut self, clip: usize) {
+        self.layers.retain(|l| l.clip != clip);
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        for layer in self.layers.iter_mut() {
+            layer.time += dt * layer.speed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_settings::animation::{Easing, Keyframe};
+
+    fn trs(x: f32, y: f32, z: f32, angle: f32) -> Trs {
+        Trs {
+            translation: Vector3::new(x, y, z),
+            rotation: Quaternion::from_angle_z(Deg(angle)),
+            ..Trs::default()
+        }
+    }
+
+    fn assert_near(a: Matrix4<f32>, b: Matrix4<f32>) {
+        let m = a - b;
+        let diff = [m.x, m.y, m.z, m.w].iter().map(|c| c.magnitude()).fold(0.0, f32::max);
+        assert!(diff < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    // 根 (2) → 1 → 0 の鎖。親が後ろに並んでいて、パレットの順もノードの順と違う
+    fn chain() -> Skeleton {
+        let nodes = vec![
+            SkeletonNode { parent: Some(1), rest: trs(0.0, 1.0, 0.0, 0.0) },
+            SkeletonNode { parent: Some(2), rest: trs(0.0, 1.0, 0.0, 30.0) },
+            SkeletonNode { parent: None, rest: trs(1.0, 0.0, 0.0, 0.0) },
+        ];
+        let g2 = nodes[2].rest.to_matrix();
+        let g1 = g2 * nodes[1].rest.to_matrix();
+        let g0 = g1 * nodes[0].rest.to_matrix();
+        let joints = vec![2, 0, 1];
+        let inverse_binds = [g2, g0, g1].iter().map(|m| m.invert().unwrap()).collect();
+        Skeleton { nodes, joints, inverse_binds, clips: Vec::new() }
+    }
+
+    #[test]
+    fn rest_pose_gives_identity_palette() {
+        let palette = chain().compute_palette(&[]);
+        assert_eq!(palette.len(), 3);
+        for m in palette {
+            assert_near(m, Matrix4::identity());
+        }
+    }
+
+    #[test]
+    fn palette_is_global_times_inverse_bind() {
+        let mut skeleton = chain();
+        let rotation = Quaternion::from_angle_z(Deg(90.0));
+        skeleton.clips.push(SkinClip {
+            name: "bend".to_string(),
+            duration: 1.0,
+            channels: vec![(1, NodeTracks {
+                rotation: Some(Track::new(vec![Keyframe { time: 0.0, value: rotation, easing: Easing::Linear }])),
+                ..NodeTracks::default()
+            })],
+        });
+        let palette = skeleton.compute_palette(&[SkinLayer::new(0, 1.0)]);
+
+        let local1 = Trs { rotation, ..skeleton.nodes[1].rest }.to_matrix();
+        let g2 = skeleton.nodes[2].rest.to_matrix();
+        let g1 = g2 * local1;
+        let g0 = g1 * skeleton.nodes[0].rest.to_matrix();
+        let expected = [g2, g0, g1];
+        for (i, m) in palette.iter().enumerate() {
+            assert_near(*m, expected[i] * skeleton.inverse_binds[i]);
+        }
+        // 根は動いていない
+        assert_near(palette[0], Matrix4::identity());
+    }
+}
@@ -0,0 +1,650 @@
+use crate::shader_settings::hdr::{SceneTarget, HDR_FORMAT};
+use crate::shader_settings::ssao::XorShift;
+use anyhow::*;
+use cgmath::*;
+use std::path::Path;
+use wgpu::util::DeviceExt;
+
+// snow_update.comp の local_size
+const SNOW_GROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Particle {
+    // w は揺れの位相
+    position: [f32; 4],
+    // w は大きさの倍率
+    velocity: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for Particle {}
+unsafe impl bytemuck::Zeroable for Particle {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct SnowSimUniform {
+    center: [f32; 3],
+    dt: f32,
+    extent: [f32; 3],
+    time: f32,
+    wind: [f32; 3],
+    fall_speed: f32,
+    turbulence: f32,
+    count: u32,
+    frame: u32,
+    _padding: u32,
+}
+
+unsafe impl bytemuck::Pod for SnowSimUniform {}
+unsafe impl bytemuck::Zeroable for SnowSimUniform {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct SnowRenderUniform {
+    right: [f32; 3],
+    size: f32,
+    up: [f32; 3],
+    fade_distance: f32,
+    color: [f32; 3],
+    opacity: f32,
+}
+
+unsafe impl bytemuck::Pod for SnowRenderUniform {}
+unsafe impl bytemuck::Zeroable for SnowRenderUniform {}
+
+// 雪を降らせる範囲と降り方
+#[derive(Debug, Clone)]
+pub struct SnowEmitter {
+    // 粒の数。変えたら Snow::set_emitter で作り直す
+    pub count: u32,
+    // カメラを中心とした箱の半分の大きさ。抜けた粒は反対側から戻ってくる
+    pub extent: Vector3<f32>,
+    // 落ちる速さ (m/s)
+    pub fall_speed: f32,
+    pub wind: Vector3<f32>,
+    // ふらつきの速さ (m/s)
+    pub turbulence: f32,
+    // 粒の半径 (m)
+    pub size: f32,
+    // モデルとの距離がこれより近いと薄くする
+    pub fade_distance: f32,
+    // HDR の色
+    pub color: [f32; 3],
+    pub opacity: f32,
+}
+
+impl Default for SnowEmitter {
+    fn default() -> Self {
+        Self {
+            count: 16384,
+            extent: Vector3::new(15.0, 8.0, 15.0),
+            fall_speed: 1.0,
+            wind: Vector3::new(0.3, 0.0, 0.1),
+            turbulence: 0.4,
+            size: 0.02,
+            fade_distance: 0.3,
+            color: [1.2, 1.2, 1.3],
+            opacity: 0.9,
+        }
+    }
+}
+
+// 雪を重ねるシーンの深度のバインドグループ。SceneTarget ごとに持つ
+pub struct SnowTarget {
+    bind_group: wgpu::BindGroup,
+}
+
+// コンピュートシェーダで動かし、ビルボードで描く雪の粒
+pub struct Snow {
+    pub enabled: bool,
+    emitter: SnowEmitter,
+    time: f32,
+    frame: u32,
+    // update から render までに1歩進める必要があるか
+    pending_step: bool,
+    particle_buffer: wgpu::Buffer,
+    sim_uniform_buffer: wgpu::Buffer,
+    render_uniform_buffer: wgpu::Buffer,
+    sim_layout: wgpu::BindGroupLayout,
+    sim_bind_group: wgpu::BindGroup,
+    sim_pipeline: wgpu::ComputePipeline,
+    // 粒と描画の uniform。粒を作り直したらこれも作り直す
+    render_layout: wgpu::BindGroupLayout,
+    render_bind_group: wgpu::BindGroup,
+    // シーンの深度。MSAA かどうかでレイアウトが変わる
+    depth_layout: wgpu::BindGroupLayout,
+    depth_layout_msaa: wgpu::BindGroupLayout,
+    render_pipeline: wgpu::RenderPipeline,
+    render_pipeline_msaa: wgpu::RenderPipeline,
+    depth_sampler: wgpu::Sampler,
+}
+
+impl Snow {
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_layout: &wgpu::BindGroupLayout,
+        center: Point3<f32>,
+    ) -> Self {
+        let emitter = SnowEmitter::default();
+
+        let sim_uniform_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Snow Sim Uniform Buffer"),
+                size: std::mem::size_of::<SnowSimUniform>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let render_uniform_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Snow Render Uniform Buffer"),
+                size: std::mem::size_of::<SnowRenderUniform>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+
+        let sim_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::COMPUTE,
+                        ty: wgpu::BindingType::StorageBuffer {
+                            dynamic: false,
+                            readonly: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::COMPUTE,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("snow_sim_bind_group_layout"),
+            }
+        );
+
+        let render_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::StorageBuffer {
+                            dynamic: false,
+                            readonly: true,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("snow_render_bind_group_layout"),
+            }
+        );
+
+        let depth_layout_for = |multisampled, label| device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some(label),
+            }
+        );
+        let depth_layout = depth_layout_for(false, "snow_depth_bind_group_layout");
+        let depth_layout_msaa = depth_layout_for(true, "snow_depth_msaa_bind_group_layout");
+
+        let sim_pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Snow Sim Pipeline Layout"),
+                bind_group_layouts: &[&sim_layout],
+                push_constant_ranges: &[],
+            }
+        );
+        let sim_module = device.create_shader_module(wgpu::include_spirv!("../snow_update.comp.spv"));
+        let sim_pipeline = device.create_compute_pipeline(
+            &wgpu::ComputePipelineDescriptor {
+                label: Some("Snow Sim Pipeline"),
+                layout: Some(&sim_pipeline_layout),
+                compute_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &sim_module,
+                    entry_point: "main",
+                },
+            }
+        );
+
+        let render_pipeline = create_snow_pipeline(
+            device,
+            &[uniform_layout, &render_layout, &depth_layout],
+            wgpu::include_spirv!("../snow.frag.spv"),
+        );
+        let render_pipeline_msaa = create_snow_pipeline(
+            device,
+            &[uniform_layout, &render_layout, &depth_layout_msaa],
+            wgpu::include_spirv!("../snow_msaa.frag.spv"),
+        );
+
+        // 深度はそのまま読む
+        let depth_sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Nearest,
+                min_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            }
+        );
+
+        let particle_buffer = create_particle_buffer(device, &emitter, center);
+        let sim_bind_group = create_particle_bind_group(
+            device,
+            &sim_layout,
+            &particle_buffer,
+            &sim_uniform_buffer,
+            "snow_sim_bind_group",
+        );
+        let render_bind_group = create_particle_bind_group(
+            device,
+            &render_layout,
+            &particle_buffer,
+            &render_uniform_buffer,
+            "snow_render_bind_group",
+        );
+
+        Self {
+            enabled: true,
+            emitter,
+            time: 0.0,
+            frame: 0,
+            pending_step: false,
+            particle_buffer,
+            sim_uniform_buffer,
+            render_uniform_buffer,
+            sim_layout,
+            sim_bind_group,
+            sim_pipeline,
+            render_layout,
+            render_bind_group,
+            depth_layout,
+            depth_layout_msaa,
+            render_pipeline,
+            render_pipeline_msaa,
+            depth_sampler,
+        }
+    }
+
+    pub fn emitter(&self) -> &SnowEmitter {
+        &self.emitter
+    }
+
+    // 粒の数が変わったときは center の周りに撒き直す
+    pub fn set_emitter(&mut self, device: &wgpu::Device, emitter: SnowEmitter, center: Point3<f32>) {
+        if emitter.count != self.emitter.count {
+            self.particle_buffer = create_particle_buffer(device, &emitter, center);
+            self.sim_bind_group = create_particle_bind_group(
+                device,
+                &self.sim_layout,
+                &self.particle_buffer,
+                &self.sim_uniform_buffer,
+                "snow_sim_bind_group",
+            );
+            self.render_bind_group = create_particle_bind_group(
+                device,
+                &self.render_layout,
+                &self.particle_buffer,
+                &self.render_uniform_buffer,
+                "snow_render_bind_group",
+            );
+        }
+        self.emitter = emitter;
+    }
+
+    // 経過時間と視点を送る。実際に動かすのは次の simulate
+    pub fn update(&mut self, queue: &wgpu::Queue, dt: f32, center: Point3<f32>) {
+        if !self.enabled || dt <= 0.0 {
+            return;
+        }
+        // 引っかかったフレームで粒が箱を飛び越えないようにする
+        let dt = dt.min(0.1);
+        self.time += dt;
+        self.frame = self.frame.wrapping_add(1);
+        let e = &self.emitter;
+        let uniform = SnowSimUniform {
+            center: center.into(),
+            dt,
+            extent: e.extent.into(),
+            time: self.time,
+            wind: e.wind.into(),
+            fall_speed: e.fall_speed,
+            turbulence: e.turbulence,
+            count: e.count,
+            frame: self.frame,
+            _padding: 0,
+        };
+        queue.write_buffer(&self.sim_uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        self.pending_step = true;
+    }
+
+    pub fn simulate(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if !self.enabled || !self.pending_step || self.emitter.count == 0 {
+            return;
+        }
+        self.pending_step = false;
+        let mut compute_pass = encoder.begin_compute_pass();
+        compute_pass.set_pipeline(&self.sim_pipeline);
+        compute_pass.set_bind_group(0, &self.sim_bind_group, &[]);
+        compute_pass.dispatch((self.emitter.count + SNOW_GROUP_SIZE - 1) / SNOW_GROUP_SIZE, 1, 1);
+    }
+
+    // 深度は画面の大きさやサンプル数で作り直されるので、SceneTarget を作るたびにこれも作る
+    pub fn create_target(&self, device: &wgpu::Device, target: &SceneTarget) -> SnowTarget {
+        let layout = if target.sample_count > 1 {
+            &self.depth_layout_msaa
+        } else {
+            &self.depth_layout
+        };
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&target.depth.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.depth_sampler),
+                    },
+                ],
+                label: Some("snow_depth_bind_group"),
+            }
+        );
+        SnowTarget { bind_group }
+    }
+
+    // target に描いたシーンの上に重ねる。MSAA なら解決後の色に描き、深度はサンプル 0 を読む。
+    // snow_target は target から create_target で作ったもの。
+    // uniform_bind_group はシーンと同じもの (視点と inv_view_proj を使う)
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        target: &SceneTarget,
+        snow_target: &SnowTarget,
+        uniform_bind_group: &wgpu::BindGroup,
+        forward: Vector3<f32>,
+    ) {
+        if !self.enabled || self.emitter.count == 0 {
+            return;
+        }
+
+        // 視線に垂直な面に粒を立てる。真上や真下を見ているときは z 軸を基準にする
+        let reference = if forward.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+        let right = forward.cross(reference).normalize();
+        let up = right.cross(forward).normalize();
+        let e = &self.emitter;
+        let uniform = SnowRenderUniform {
+            right: right.into(),
+            size: e.size,
+            up: up.into(),
+            fade_distance: e.fade_distance,
+            color: e.color,
+            opacity: e.opacity,
+        };
+        queue.write_buffer(&self.render_uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+        let pipeline = if target.sample_count > 1 {
+            &self.render_pipeline_msaa
+        } else {
+            &self.render_pipeline
+        };
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &target.color.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    }
+                }
+            ],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, uniform_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.render_bind_group, &[]);
+        render_pass.set_bind_group(2, &snow_target.bind_group, &[]);
+        render_pass.draw(0..6, 0..e.count);
+    }
+
+    // 設定ファイルの書式 (1行1つ、# 以降はコメント)
+    //
+    //   enable / disable
+    //   count <n>
+    //   volume <x> <y> <z>                カメラを中心とした箱の半分の大きさ
+    //   fall_speed <v>
+    //   wind <x> <y> <z>
+    //   turbulence <v>
+    //   size <radius>
+    //   fade <distance>
+    //   color <r> <g> <b> [opacity]
+    pub fn load<P: AsRef<Path>>(
+        &mut self,
+        device: &wgpu::Device,
+        path: P,
+        center: Point3<f32>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read {:?}", path))?;
+        let mut emitter = self.emitter.clone();
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let err = || format!("line {}: {}", n + 1, line);
+            let floats = |counts: &[usize]| -> Result<Vec<f32>> {
+                if !counts.contains(&(words.len() - 1)) {
+                    bail!("Expected {:?} values: {}", counts, err());
+                }
+                words[1..]
+                    .iter()
+                    .map(|w| w.parse::<f32>().with_context(err))
+                    .collect()
+            };
+            match words[0] {
+                "enable" | "disable" => self.enabled = words[0] == "enable",
+                "count" => {
+                    emitter.count = words
+                        .get(1)
+                        .with_context(err)?
+                        .parse::<u32>()
+                        .with_context(err)?;
+                }
+                "volume" => {
+                    let v = floats(&[3])?;
+                    emitter.extent = Vector3::new(v[0].max(0.1), v[1].max(0.1), v[2].max(0.1));
+                }
+                "fall_speed" => emitter.fall_speed = floats(&[1])?[0],
+                "wind" => {
+                    let v = floats(&[3])?;
+                    emitter.wind = Vector3::new(v[0], v[1], v[2]);
+                }
+                "turbulence" => emitter.turbulence = floats(&[1])?[0],
+                "size" => emitter.size = floats(&[1])?[0],
+                "fade" => emitter.fade_distance = floats(&[1])?[0],
+                "color" => {
+                    let v = floats(&[3, 4])?;
+                    emitter.color = [v[0], v[1], v[2]];
+                    if let Some(&opacity) = v.get(3) {
+                        emitter.opacity = opacity;
+                    }
+                }
+                _ => bail!("Unknown directive: {}", err()),
+            }
+        }
+        self.set_emitter(device, emitter, center);
+        Ok(())
+    }
+}
+
+// 箱の中に一様に撒く。大きさと揺れの位相は粒ごとに変える
+fn create_particle_buffer(
+    device: &wgpu::Device,
+    emitter: &SnowEmitter,
+    center: Point3<f32>,
+) -> wgpu::Buffer {
+    let mut rng = XorShift(0x9e37_79b9);
+    let mut signed = || rng.next_f32() * 2.0 - 1.0;
+    let particles = (0..emitter.count.max(1))
+        .map(|_| {
+            let e = emitter.extent;
+            let p = center + Vector3::new(signed() * e.x, signed() * e.y, signed() * e.z);
+            let phase = (signed() + 1.0) * std::f32::consts::PI;
+            let scale = 0.6 + 0.4 * (signed() + 1.0);
+            Particle {
+                position: [p.x, p.y, p.z, phase],
+                velocity: [0.0, -emitter.fall_speed, 0.0, scale],
+            }
+        })
+        .collect::<Vec<_>>();
+    device.create_buffer_init(
+        &wgpu::util::BufferInitDescriptor {
+            label: Some("Snow Particle Buffer"),
+            contents: bytemuck::cast_slice(&particles),
+            usage: wgpu::BufferUsage::STORAGE,
+        }
+    )
+}
+
+// 粒と uniform の組。シミュレーションと描画でレイアウトだけが違う
+fn create_particle_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    particle_buffer: &wgpu::Buffer,
+    uniform_buffer: &wgpu::Buffer,
+    label: &str,
+) -> wgpu::BindGroup {
+    device.create_bind_group(
+        &wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(particle_buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                },
+            ],
+            label: Some(label),
+        }
+    )
+}
+
+// 深度は読むだけなので深度の添付は無く、色は半透明で重ねる
+fn create_snow_pipeline(
+    device: &wgpu::Device,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+    frag_src: wgpu::ShaderModuleSource,
+) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(
+        &wgpu::PipelineLayoutDescriptor {
+            label: Some("Snow Pipeline Layout"),
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        }
+    );
+    let vs_module = device.create_shader_module(wgpu::include_spirv!("../snow.vert.spv"));
+    let fs_module = device.create_shader_module(frag_src);
+
+    device.create_render_pipeline(
+        &wgpu::RenderPipelineDescriptor {
+            label: Some("Snow Pipeline"),
+            layout: Some(&layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &vs_module,
+                entry_point: "main",
+            },
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: &fs_module,
+                entry_point: "main",
+            }),
+            rasterization_state: Some(
+                wgpu::RasterizationStateDescriptor {
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: wgpu::CullMode::None,
+                    depth_bias: 0,
+                    depth_bias_slope_scale: 0.0,
+                    depth_bias_clamp: 0.0,
+                    clamp_depth: false,
+                }
+            ),
+            color_states: &[
+                wgpu::ColorStateDescriptor {
+                    format: HDR_FORMAT,
+                    color_blend: wgpu::BlendDescriptor {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    // 透明な背景のスクリーンショットでも透明度は変えない
+                    alpha_blend: wgpu::BlendDescriptor {
+                        src_factor: wgpu::BlendFactor::Zero,
+                        dst_factor: wgpu::BlendFactor::One,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    write_mask: wgpu::ColorWrite::ALL,
+                },
+            ],
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint32,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        }
+    )
+}
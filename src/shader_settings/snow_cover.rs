@@ -0,0 +1,270 @@
+use crate::shader_settings::camera::Projection;
+use crate::shader_settings::model::ModelInstanceGroupBook;
+use crate::shader_settings::shadowmap::{self, DirUpdateWay, ShadowMap, ShadowUniformBuffer};
+use crate::shader_settings::texture;
+use anyhow::*;
+use cgmath::*;
+use std::path::Path;
+
+// 上空から見下ろした深度の解像度
+const SKY_MAP_SIZE: u32 = 2048;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct SnowCoverUniform {
+    sky_view_proj: Matrix4<f32>,
+    color: [f32; 3],
+    coverage: f32,
+    min_up: f32,
+    blend: f32,
+    depth_bias: f32,
+    enabled: u32,
+}
+
+unsafe impl bytemuck::Pod for SnowCoverUniform {}
+unsafe impl bytemuck::Zeroable for SnowCoverUniform {}
+
+// 上を向いた面に積もる雪。屋根の下などは上空からの深度で判定して積もらせない。
+// 上空の深度は影と同じ ShadowMap で真下向きの平行投影として焼く
+pub struct SnowCover {
+    pub enabled: bool,
+    // 線形の色
+    pub color: [f32; 3],
+    // 0 なら積もらず、1 なら min_up より上を向いた面が全て白くなる
+    coverage: f32,
+    // (目標, 1秒あたりの変化量)
+    fade: Option<(f32, f32)>,
+    // 法線の上向き成分がこれより小さい面には積もらない
+    pub min_up: f32,
+    // 積もる境目をぼかす幅
+    pub blend: f32,
+    // 上空の深度と比べるときに引く値
+    pub depth_bias: f32,
+    // 上空の深度を焼く範囲 (一辺、m) と、焼く視点の高さ
+    area: f32,
+    height: f32,
+    // 上空の視点を置いた水平位置。カメラがテクセル単位で動いたときだけ動かす
+    origin: Option<Vector2<f32>>,
+    sky: ShadowMap,
+    // sky 専用。ライトのものを上書きしないように分けておく
+    sky_uniform_buffer: ShadowUniformBuffer,
+    pub sky_texture: texture::Texture,
+    pub uniform_buffer: wgpu::Buffer,
+}
+
+impl SnowCover {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        instance_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let sky_desc = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            format: texture::Texture::DEPTH_FORMAT,
+            width: SKY_MAP_SIZE,
+            height: SKY_MAP_SIZE,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+        let sky_texture = texture::Texture::create_depth_texture(device, &sky_desc, 1, "snow_sky_texture");
+
+        let area = 60.0;
+        let height = 50.0;
+        let sky = ShadowMap::new(
+            Point3::new(0.0, height, 0.0),
+            -Vector3::unit_y(),
+            0.0,
+            DirUpdateWay::Constant { dir: -Vector3::unit_y() },
+            Projection::new_orthographic(SKY_MAP_SIZE, SKY_MAP_SIZE, area, 0.1, height * 2.0),
+            shadowmap::ShadowFilter::Hard,
+            shadowmap::ShadowBias::default(),
+            device,
+            queue,
+            &sky_desc,
+            instance_layout,
+            &sky_texture.texture,
+        );
+        let sky_uniform_buffer = ShadowUniformBuffer::new(device, 1);
+
+        let uniform_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("Snow Cover Uniform Buffer"),
+                size: std::mem::size_of::<SnowCoverUniform>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+
+        let mut cover = Self {
+            enabled: true,
+            color: [0.9, 0.92, 0.95],
+            coverage: 0.5,
+            fade: None,
+            min_up: 0.3,
+            blend: 0.15,
+            depth_bias: 0.002,
+            area,
+            height,
+            origin: None,
+            sky,
+            sky_uniform_buffer,
+            sky_texture,
+            uniform_buffer,
+        };
+        cover.update(queue, 0.0, Point3::new(0.0, 0.0, 0.0));
+        cover
+    }
+
+    pub fn coverage(&self) -> f32 {
+        self.coverage
+    }
+
+    // すぐに変える。進行中の fade_to は止める
+    pub fn set_coverage(&mut self, coverage: f32) {
+        self.coverage = coverage.max(0.0).min(1.0);
+        self.fade = None;
+    }
+
+    // seconds 秒かけて target まで積もらせる (あるいは溶かす)
+    pub fn fade_to(&mut self, target: f32, seconds: f32) {
+        let target = target.max(0.0).min(1.0);
+        if seconds <= 0.0 {
+            self.set_coverage(target);
+            return;
+        }
+        self.fade = Some((target, (target - self.coverage).abs() / seconds));
+    }
+
+    // 上空の深度を焼く範囲。変えたら次のフレームで焼き直す
+    pub fn set_area(&mut self, queue: &wgpu::Queue, area: f32, height: f32) {
+        self.area = area.max(1.0);
+        self.height = height.max(1.0);
+        self.sky.set_projection(
+            Projection::new_orthographic(
+                SKY_MAP_SIZE,
+                SKY_MAP_SIZE,
+                self.area,
+                0.1,
+                self.height * 2.0,
+            ),
+            queue,
+            &mut self.sky_uniform_buffer,
+        );
+        self.origin = None;
+    }
+
+    // 積もり具合を進め、カメラの真上に上空の視点を合わせる
+    pub fn update(&mut self, queue: &wgpu::Queue, dt: f32, eye: Point3<f32>) {
+        if let Some((target, speed)) = self.fade {
+            let step = speed * dt;
+            if (target - self.coverage).abs() <= step {
+                self.coverage = target;
+                self.fade = None;
+            } else {
+                self.coverage += step * (target - self.coverage).signum();
+            }
+        }
+
+        // テクセルの大きさ単位で動かして、焼き直しのたびに縁がちらつかないようにする
+        let texel = self.area / SKY_MAP_SIZE as f32;
+        let snapped = Vector2::new((eye.x / texel).round() * texel, (eye.z / texel).round() * texel);
+        // 範囲の 1/8 以上離れるまでは焼き直さない
+        let moved = self
+            .origin
+            .map(|o| (o - snapped).magnitude() > self.area / 8.0)
+            .unwrap_or(true);
+        if moved {
+            self.origin = Some(snapped);
+            self.sky.update(
+                Some(Vector3::new(snapped.x, self.height, snapped.y)),
+                Some(-Vector3::unit_y()),
+                queue,
+                &mut self.sky_uniform_buffer,
+            );
+        }
+
+        let uniform = SnowCoverUniform {
+            sky_view_proj: self.sky.view_proj(),
+            color: self.color,
+            coverage: self.coverage,
+            min_up: self.min_up,
+            blend: self.blend.max(0.001),
+            depth_bias: self.depth_bias,
+            enabled: self.enabled as u32,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    // 影を落とすインスタンスを屋根とみなして焼く。instances_moved なら視点が同じでも焼き直す
+    pub fn render_sky(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        model_instance_group_book: &ModelInstanceGroupBook,
+        instances_moved: bool,
+    ) {
+        if !self.enabled || (!instances_moved && !self.sky.is_dirty()) {
+            return;
+        }
+        self.sky.render_to_texture(encoder, model_instance_group_book);
+    }
+
+    // 設定ファイルの書式 (1行1つ、# 以降はコメント)
+    //
+    //   enable / disable
+    //   color <r> <g> <b>
+    //   coverage <value>                  0..1
+    //   fade <target> <seconds>           coverage を時間をかけて変える
+    //   slope <min_up> <blend>
+    //   bias <depth>
+    //   area <size> <height>              上空の深度を焼く範囲と高さ
+    pub fn load<P: AsRef<Path>>(&mut self, queue: &wgpu::Queue, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read {:?}", path))?;
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let err = || format!("line {}: {}", n + 1, line);
+            let floats = |count: usize| -> Result<Vec<f32>> {
+                if words.len() != count + 1 {
+                    bail!("Expected {} values: {}", count, err());
+                }
+                words[1..]
+                    .iter()
+                    .map(|w| w.parse::<f32>().with_context(err))
+                    .collect()
+            };
+            match words[0] {
+                "enable" | "disable" => self.enabled = words[0] == "enable",
+                "color" => {
+                    let v = floats(3)?;
+                    self.color = [v[0], v[1], v[2]];
+                }
+                "coverage" => self.set_coverage(floats(1)?[0]),
+                "fade" => {
+                    let v = floats(2)?;
+                    self.fade_to(v[0], v[1]);
+                }
+                "slope" => {
+                    let v = floats(2)?;
+                    self.min_up = v[0];
+                    self.blend = v[1];
+                }
+                "bias" => self.depth_bias = floats(1)?[0],
+                "area" => {
+                    let v = floats(2)?;
+                    self.set_area(queue, v[0], v[1]);
+                }
+                _ => bail!("Unknown directive: {}", err()),
+            }
+        }
+        let eye = self
+            .origin
+            .map(|o| Point3::new(o.x, 0.0, o.y))
+            .unwrap_or_else(|| Point3::new(0.0, 0.0, 0.0));
+        self.update(queue, 0.0, eye);
+        Ok(())
+    }
+}
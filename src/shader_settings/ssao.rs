@@ -0,0 +1,482 @@
+use crate::shader_settings::hdr;
+use crate::shader_settings::texture;
+use anyhow::*;
+use cgmath::*;
+
+// ssao.frag の MAX_SAMPLES と合わせること
+pub const MAX_SAMPLES: usize = 64;
+// 画面に敷き詰める回転ノイズの一辺。ssao_blur.frag の範囲と合わせること
+const NOISE_SIZE: u32 = 4;
+
+const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const AO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SsaoUniform {
+    view_proj: Matrix4<f32>,
+    inv_view_proj: Matrix4<f32>,
+    eye: Vector4<f32>,
+    forward: Vector4<f32>,
+    noise_scale: [f32; 2],
+    radius: f32,
+    intensity: f32,
+    bias: f32,
+    sample_count: u32,
+    _padding: [f32; 2],
+    kernel: [[f32; 4]; MAX_SAMPLES],
+}
+
+unsafe impl bytemuck::Pod for SsaoUniform {}
+unsafe impl bytemuck::Zeroable for SsaoUniform {}
+
+// AO を求めるときのカメラ。切り出し (crop) 込みの view_proj を渡す
+pub struct SsaoView {
+    pub view_proj: Matrix4<f32>,
+    pub eye: Point3<f32>,
+    pub forward: Vector3<f32>,
+}
+
+// 描き込む先の大きさごとに必要なもの。
+// MSAA の深度はシェーダから読めないので、法線と一緒に1サンプルの深度を描き直す
+pub struct SsaoTargets {
+    width: u32,
+    height: u32,
+    normal: texture::Texture,
+    depth: texture::Texture,
+    raw: texture::Texture,
+    ao: texture::Texture,
+    uniform_buffer: wgpu::Buffer,
+    ssao_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+    // シーンを描くときに set = 3 に置く
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl SsaoTargets {
+    // 法線の前処理パスの描き込み先
+    pub fn begin_prepass<'a>(&'a self, encoder: &'a mut wgpu::CommandEncoder) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &self.normal.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    }
+                }
+            ],
+            depth_stencil_attachment: Some(
+                wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: &self.depth.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }
+            ),
+        })
+    }
+}
+
+// 画面空間の環境光遮蔽。結果は shader.frag の環境光に掛ける
+pub struct Ssao {
+    pub enabled: bool,
+    // 遮蔽物を探す半球の半径 (ワールド座標)
+    pub radius: f32,
+    // 1 で全て遮られたとき真っ黒
+    pub intensity: f32,
+    // 同じ面で自分自身を遮らないための余裕
+    pub bias: f32,
+    // MAX_SAMPLES まで
+    pub samples: usize,
+    kernel: [[f32; 4]; MAX_SAMPLES],
+    noise: texture::Texture,
+    // 無効なときに置く 1x1 の白
+    white: texture::Texture,
+    point_sampler: wgpu::Sampler,
+    pub layout: wgpu::BindGroupLayout,
+    ssao_layout: wgpu::BindGroupLayout,
+    disabled_bind_group: wgpu::BindGroup,
+    pub prepass_pipeline: wgpu::RenderPipeline,
+    ssao_pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+}
+
+impl Ssao {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture_layout: &wgpu::BindGroupLayout,
+        uniform_layout: &wgpu::BindGroupLayout,
+        instance_layout: &wgpu::BindGroupLayout,
+    ) -> Result<Self> {
+        let mut rng = XorShift(0x2545_f491);
+
+        // 中心寄りに多く置いた半球内の点
+        let mut kernel = [[0.0; 4]; MAX_SAMPLES];
+        for (i, k) in kernel.iter_mut().enumerate() {
+            let v = Vector3::new(rng.next_f32() * 2.0 - 1.0, rng.next_f32() * 2.0 - 1.0, rng.next_f32());
+            let v = if v.magnitude2() > 0.0 { v.normalize() } else { Vector3::unit_z() };
+            let t = i as f32 / MAX_SAMPLES as f32;
+            let v = v * rng.next_f32() * (0.1 + 0.9 * t * t);
+            *k = [v.x, v.y, v.z, 0.0];
+        }
+
+        // 接平面内の向き。z は使わない
+        let noise_pixels = (0..NOISE_SIZE * NOISE_SIZE)
+            .flat_map(|_| {
+                let q = |v: f32| (v * 255.0).round() as u8;
+                vec![q(rng.next_f32()), q(rng.next_f32()), 128, 255]
+            })
+            .collect::<Vec<_>>();
+        let noise = create_texture_with_data(
+            device,
+            queue,
+            NOISE_SIZE,
+            &noise_pixels,
+            wgpu::AddressMode::Repeat,
+            "ssao_noise_texture",
+        );
+        let white = create_texture_with_data(
+            device,
+            queue,
+            1,
+            &[255, 255, 255, 255],
+            wgpu::AddressMode::ClampToEdge,
+            "ssao_white_texture",
+        );
+
+        let point_sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Nearest,
+                min_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            }
+        );
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                component_type: wgpu::TextureComponentType::Float,
+                dimension: wgpu::TextureViewDimension::D2,
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Sampler {
+                comparison: false,
+            },
+            count: None,
+        };
+
+        // AO の読み出しとぼかしで共通
+        let layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[texture_entry(0), sampler_entry(1)],
+                label: Some("ao_bind_group_layout"),
+            }
+        );
+        let ssao_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    texture_entry(0),
+                    texture_entry(1),
+                    texture_entry(2),
+                    sampler_entry(3),
+                    sampler_entry(4),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("ssao_bind_group_layout"),
+            }
+        );
+
+        let disabled_bind_group = create_ao_bind_group(device, &layout, &white, "ao_disabled_bind_group");
+
+        let prepass_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Normal Prepass Pipeline Layout"),
+                bind_group_layouts: &[texture_layout, uniform_layout, instance_layout],
+                push_constant_ranges: &[],
+            }
+        );
+        let prepass_pipeline = super::create_render_pipeline(
+            device,
+            &prepass_layout,
+            NORMAL_FORMAT,
+            1,
+            wgpu::include_spirv!("../shader.vert.spv"),
+            wgpu::include_spirv!("../normal_prepass.frag.spv"),
+        )?;
+        let ssao_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "SSAO Pipeline",
+            &[&ssao_layout],
+            wgpu::include_spirv!("../ssao.frag.spv"),
+            AO_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+        let blur_pipeline = hdr::create_fullscreen_pipeline(
+            device,
+            "SSAO Blur Pipeline",
+            &[&layout],
+            wgpu::include_spirv!("../ssao_blur.frag.spv"),
+            AO_FORMAT,
+            wgpu::BlendDescriptor::REPLACE,
+            wgpu::BlendDescriptor::REPLACE,
+        );
+
+        Ok(Self {
+            enabled: true,
+            radius: 0.5,
+            intensity: 1.0,
+            bias: 0.025,
+            samples: 16,
+            kernel,
+            noise,
+            white,
+            point_sampler,
+            layout,
+            ssao_layout,
+            disabled_bind_group,
+            prepass_pipeline,
+            ssao_pipeline,
+            blur_pipeline,
+        })
+    }
+
+    pub fn create_targets(&self, device: &wgpu::Device, width: u32, height: u32) -> SsaoTargets {
+        let desc = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            format: NORMAL_FORMAT,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+        let normal = texture::Texture::create_render_texture(device, width, height, NORMAL_FORMAT, "ssao_normal_texture");
+        let depth = texture::Texture::create_depth_texture(device, &desc, 1, "ssao_depth_texture");
+        let raw = texture::Texture::create_render_texture(device, width, height, AO_FORMAT, "ssao_raw_texture");
+        let ao = texture::Texture::create_render_texture(device, width, height, AO_FORMAT, "ssao_texture");
+
+        let uniform_buffer = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("SSAO Uniform Buffer"),
+                size: std::mem::size_of::<SsaoUniform>() as wgpu::BufferAddress,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let ssao_bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &self.ssao_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&depth.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&normal.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&self.noise.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Sampler(&self.point_sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::Sampler(&self.noise.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                    },
+                ],
+                label: Some("ssao_bind_group"),
+            }
+        );
+        let blur_bind_group = create_ao_bind_group(device, &self.layout, &raw, "ssao_blur_bind_group");
+        let bind_group = create_ao_bind_group(device, &self.layout, &ao, "ao_bind_group");
+
+        SsaoTargets {
+            width,
+            height,
+            normal,
+            depth,
+            raw,
+            ao,
+            uniform_buffer,
+            ssao_bind_group,
+            blur_bind_group,
+            bind_group,
+        }
+    }
+
+    // シーンを描くときに set = 3 に置くもの。無効なら AO は掛からない
+    pub fn bind_group<'a>(&'a self, targets: Option<&'a SsaoTargets>) -> &'a wgpu::BindGroup {
+        match targets {
+            Some(targets) if self.enabled => &targets.bind_group,
+            _ => &self.disabled_bind_group,
+        }
+    }
+
+    // begin_prepass で法線と深度を描いた後に呼ぶ
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        targets: &SsaoTargets,
+        view: &SsaoView,
+    ) {
+        let uniform = SsaoUniform {
+            view_proj: view.view_proj,
+            inv_view_proj: view.view_proj.invert().unwrap_or_else(Matrix4::identity),
+            eye: view.eye.to_homogeneous(),
+            forward: view.forward.normalize().extend(0.0),
+            noise_scale: [
+                targets.width as f32 / NOISE_SIZE as f32,
+                targets.height as f32 / NOISE_SIZE as f32,
+            ],
+            radius: self.radius,
+            intensity: self.intensity,
+            bias: self.bias,
+            sample_count: self.samples.min(MAX_SAMPLES) as u32,
+            _padding: [0.0; 2],
+            kernel: self.kernel,
+        };
+        queue.write_buffer(&targets.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+        for &(pipeline, bind_group, output) in [
+            (&self.ssao_pipeline, &targets.ssao_bind_group, &targets.raw.view),
+            (&self.blur_pipeline, &targets.blur_bind_group, &targets.ao.view),
+        ].iter() {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: output,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                            store: true,
+                        }
+                    }
+                ],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+fn create_ao_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    texture: &texture::Texture,
+    label: &str,
+) -> wgpu::BindGroup {
+    device.create_bind_group(
+        &wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some(label),
+        }
+    )
+}
+
+// size x size の RGBA8 のテクスチャ
+fn create_texture_with_data(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    size: u32,
+    pixels: &[u8],
+    address_mode: wgpu::AddressMode,
+    label: &str,
+) -> texture::Texture {
+    let extent = wgpu::Extent3d { width: size, height: size, depth: 1 };
+    let texture = device.create_texture(
+        &wgpu::TextureDescriptor {
+            label: Some(label),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        }
+    );
+    queue.write_texture(
+        wgpu::TextureCopyView {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        pixels,
+        wgpu::TextureDataLayout {
+            offset: 0,
+            bytes_per_row: 4 * size,
+            rows_per_image: size,
+        },
+        extent,
+    );
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(
+        &wgpu::SamplerDescriptor {
+            address_mode_u: address_mode,
+            address_mode_v: address_mode,
+            address_mode_w: address_mode,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        }
+    );
+    texture::Texture { texture, view, sampler }
+}
+
+// カーネルとノイズを毎回同じにするための簡単な乱数
+pub(crate) struct XorShift(pub(crate) u32);
+
+impl XorShift {
+    // 0..1
+    pub(crate) fn next_f32(&mut self) -> f32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        (x >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
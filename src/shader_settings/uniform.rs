@@ -3,6 +3,9 @@ use wgpu::util::DeviceExt;
 use crate::shader_settings::camera::{Camera, Projection};
 use crate::shader_settings::light::Light;
 use crate::shader_settings::texture;
+use crate::shader_settings::environment::Environment;
+use crate::shader_settings::fog::Fog;
+use crate::shader_settings::snow_cover::SnowCover;
 // use crate::shader_settings::shadowmap;
 
 // uniformの設定
@@ -12,6 +15,9 @@ pub struct Uniforms {
     view_position: cgmath::Vector4<f32>,
     view_proj: cgmath::Matrix4<f32>,
     light_num: u32,
+    _padding: [u32; 3],
+    // 背景を描くときに画面の点から視線の向きを求める
+    inv_view_proj: cgmath::Matrix4<f32>,
 }
 
 unsafe impl bytemuck::Pod for Uniforms {}
@@ -24,13 +30,30 @@ impl Uniforms {
             view_position: Zero::zero(),
             view_proj: cgmath::Matrix4::identity(),
             light_num,
+            _padding: [0; 3],
+            inv_view_proj: cgmath::Matrix4::identity(),
         }
     }
 
     // 視点変更時に呼び出す必要がありそう
     pub fn update_view_proj(&mut self, camera: &Camera, projection: &Projection) {
+        self.update_view_proj_cropped(camera, projection, cgmath::Matrix4::identity());
+    }
+
+    pub fn view_proj(&self) -> cgmath::Matrix4<f32> {
+        self.view_proj
+    }
+
+    // 画面の一部だけを描くとき。crop は射影後に掛ける
+    pub fn update_view_proj_cropped(
+        &mut self,
+        camera: &Camera,
+        projection: &Projection,
+        crop: cgmath::Matrix4<f32>,
+    ) {
         self.view_position = camera.position.to_homogeneous();
-        self.view_proj = projection.calc_matrix() * camera.calc_matrix();
+        self.view_proj = crop * projection.calc_matrix() * camera.calc_matrix();
+        self.inv_view_proj = self.view_proj.invert().unwrap_or_else(cgmath::Matrix4::identity);
     }
 }
 
@@ -39,6 +62,7 @@ pub struct UniformSetting {
     pub buffer: wgpu::Buffer,
     pub layout: wgpu::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
+    shadow_raw_sampler: wgpu::Sampler,
 }
 
 impl UniformSetting {
@@ -49,6 +73,9 @@ impl UniformSetting {
         light_buffer: &wgpu::Buffer,
         shadow_uniform_buffer: &wgpu::Buffer,
         shadow_texture: &texture::Texture,
+        environment: &Environment,
+        fog: &Fog,
+        snow_cover: &SnowCover,
         // shadow_texture: &texture::Texture,
         // shadowmap: &shadowmap::ShadowMap,
         // shadowmaps: &[&shadowmap::ShadowMap],
@@ -114,14 +141,157 @@ impl UniformSetting {
                         },
                         count: None,
                     },
+                    // PCSS の遮蔽物探索用。比較せずに深度を読む
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                        },
+                        count: None,
+                    },
+                    // 環境マップ、放射照度、鏡面反射の前計算、BRDF の表
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::Cube,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::Cube,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::Cube,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 9,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 10,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 11,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // 霧
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 12,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // 積もる雪。上空からの深度と設定
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 13,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            component_type: wgpu::TextureComponentType::Float,
+                            dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 14,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("uniform_bind_group_layout"),
             }
         );
 
-        let bind_group = device.create_bind_group(
+        let shadow_raw_sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Nearest,
+                min_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            }
+        );
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &layout,
+            &buffer,
+            light_buffer,
+            shadow_uniform_buffer,
+            shadow_texture,
+            &shadow_raw_sampler,
+            environment,
+            fog,
+            snow_cover,
+        );
+
+        Self {
+            uniforms,
+            buffer,
+            layout,
+            bind_group,
+            shadow_raw_sampler,
+        }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffer: &wgpu::Buffer,
+        light_buffer: &wgpu::Buffer,
+        shadow_uniform_buffer: &wgpu::Buffer,
+        shadow_texture: &texture::Texture,
+        shadow_raw_sampler: &wgpu::Sampler,
+        environment: &Environment,
+        fog: &Fog,
+        snow_cover: &SnowCover,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(
             &wgpu::BindGroupDescriptor {
-                layout: &layout,
+                layout,
                 entries: &[
                     wgpu::BindGroupEntry {
                         binding: 0,
@@ -143,16 +313,83 @@ impl UniformSetting {
                         binding: 4,
                         resource: wgpu::BindingResource::Sampler(&shadow_texture.sampler),
                     },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: wgpu::BindingResource::Sampler(shadow_raw_sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: wgpu::BindingResource::TextureView(&environment.environment.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: wgpu::BindingResource::TextureView(&environment.irradiance.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 8,
+                        resource: wgpu::BindingResource::TextureView(&environment.prefiltered.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 9,
+                        resource: wgpu::BindingResource::TextureView(&environment.brdf_lut.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 10,
+                        resource: wgpu::BindingResource::Sampler(&environment.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 11,
+                        resource: wgpu::BindingResource::Buffer(environment.uniform_buffer.slice(..)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 12,
+                        resource: wgpu::BindingResource::Buffer(fog.buffer.slice(..)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 13,
+                        resource: wgpu::BindingResource::TextureView(&snow_cover.sky_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 14,
+                        resource: wgpu::BindingResource::Buffer(snow_cover.uniform_buffer.slice(..)),
+                    },
                 ],
                 label: Some("uniform_bind_group"),
             }
+        )
+    }
+
+    // ライトバッファや環境マップ等を作り直したときに呼ぶ
+    pub fn rebind(
+        &mut self,
+        device: &wgpu::Device,
+        light_buffer: &wgpu::Buffer,
+        shadow_uniform_buffer: &wgpu::Buffer,
+        shadow_texture: &texture::Texture,
+        environment: &Environment,
+        fog: &Fog,
+        snow_cover: &SnowCover,
+    ) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.layout,
+            &self.buffer,
+            light_buffer,
+            shadow_uniform_buffer,
+            shadow_texture,
+            &self.shadow_raw_sampler,
+            environment,
+            fog,
+            snow_cover,
         );
+    }
 
-        Self {
-            uniforms,
-            buffer,
-            layout,
-            bind_group,
-        }
+    pub fn set_light_num(&mut self, queue: &wgpu::Queue, light_num: u32) {
+        self.uniforms.light_num = light_num;
+        queue.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms])
+        );
     }
 }
\ No newline at end of file
@@ -0,0 +1,24 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2DArray t_shadow;
+layout(set = 0, binding = 1) uniform sampler s_shadow;
+layout(set = 0, binding = 2)
+uniform DebugUniform {
+    uint u_layer;
+    float u_znear;
+    float u_zfar;
+};
+
+void main() {
+    float depth = texture(sampler2DArray(t_shadow, s_shadow), vec3(v_tex_coords, u_layer)).r;
+
+    // wgpu の深度 [0, 1] を OpenGL の NDC [-1, 1] に戻してから線形化する
+    float z_ndc = depth * 2.0 - 1.0;
+    float linear = 2.0 * u_znear * u_zfar / (u_zfar + u_znear - z_ndc * (u_zfar - u_znear));
+    float v = clamp((linear - u_znear) / (u_zfar - u_znear), 0.0, 1.0);
+
+    f_color = vec4(v, v, v, 1.0);
+}
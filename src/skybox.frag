@@ -0,0 +1,34 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+// シーンと同じバインドグループを set = 0 に置いて使う
+layout(set = 0, binding = 0)
+uniform Uniforms {
+    vec3 u_view_position;
+    mat4 u_view_proj;
+    uint u_light_num;
+    mat4 u_inv_view_proj;
+};
+
+layout(set = 0, binding = 6) uniform textureCube t_environment;
+layout(set = 0, binding = 10) uniform sampler s_environment;
+layout(set = 0, binding = 11)
+uniform EnvironmentUniform {
+    float u_env_intensity;
+    float u_prefiltered_max_lod;
+    uint u_env_loaded;
+    float u_skybox_lod;
+};
+
+void main() {
+    vec2 ndc = vec2(v_tex_coords.x * 2.0 - 1.0, 1.0 - v_tex_coords.y * 2.0);
+    // 近い面と遠い面の差なら平行投影でも向きが出る
+    vec4 near = u_inv_view_proj * vec4(ndc, 0.0, 1.0);
+    vec4 far = u_inv_view_proj * vec4(ndc, 1.0, 1.0);
+    vec3 dir = normalize(far.xyz / far.w - near.xyz / near.w);
+
+    vec3 color = textureLod(samplerCube(t_environment, s_environment), dir, u_skybox_lod).rgb;
+    f_color = vec4(color * u_env_intensity, 1.0);
+}
@@ -0,0 +1,53 @@
+#version 450
+
+layout(location = 0) out vec2 v_uv;
+layout(location = 1) out float v_distance;
+
+layout(set = 0, binding = 0)
+uniform Uniforms {
+    vec3 u_view_position;
+    mat4 u_view_proj;
+    uint u_light_num;
+    mat4 u_inv_view_proj;
+};
+
+struct Particle {
+    vec4 position;
+    vec4 velocity;
+};
+
+layout(set = 1, binding = 0)
+readonly buffer Particles {
+    Particle particles[];
+};
+
+layout(set = 1, binding = 1)
+uniform SnowRenderUniform {
+    vec3 u_right;
+    float u_size;
+    vec3 u_up;
+    float u_fade_distance;
+    vec3 u_color;
+    float u_opacity;
+};
+
+// 2枚の三角形で四角形を作る
+const vec2 CORNERS[6] = vec2[](
+    vec2(-1.0, -1.0),
+    vec2( 1.0, -1.0),
+    vec2( 1.0,  1.0),
+    vec2(-1.0, -1.0),
+    vec2( 1.0,  1.0),
+    vec2(-1.0,  1.0)
+);
+
+void main() {
+    Particle p = particles[gl_InstanceIndex];
+    vec2 corner = CORNERS[gl_VertexIndex];
+    float size = u_size * p.velocity.w;
+    vec3 world = p.position.xyz + (u_right * corner.x + u_up * corner.y) * size;
+
+    v_uv = corner;
+    v_distance = distance(world, u_view_position);
+    gl_Position = u_view_proj * vec4(world, 1.0);
+}
@@ -0,0 +1,48 @@
+#version 450
+
+// MSAA の深度を読む版。それ以外は snow.frag と同じ
+layout(location = 0) in vec2 v_uv;
+layout(location = 1) in float v_distance;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0)
+uniform Uniforms {
+    vec3 u_view_position;
+    mat4 u_view_proj;
+    uint u_light_num;
+    mat4 u_inv_view_proj;
+};
+
+layout(set = 1, binding = 1)
+uniform SnowRenderUniform {
+    vec3 u_right;
+    float u_size;
+    vec3 u_up;
+    float u_fade_distance;
+    vec3 u_color;
+    float u_opacity;
+};
+
+// シーンの深度。粒がモデルに刺さって見えないように手前で薄くする
+layout(set = 2, binding = 0) uniform texture2DMS t_depth;
+layout(set = 2, binding = 1) uniform sampler s_depth;
+
+void main() {
+    // 丸くぼかした粒
+    float alpha = 1.0 - smoothstep(0.4, 1.0, length(v_uv));
+    if (alpha <= 0.0) {
+        discard;
+    }
+
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    vec2 uv = gl_FragCoord.xy / vec2(textureSize(sampler2DMS(t_depth, s_depth)));
+    float depth = texelFetch(sampler2DMS(t_depth, s_depth), pixel, 0).r;
+    vec4 scene = u_inv_view_proj * vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    float scene_distance = distance(scene.xyz / scene.w, u_view_position);
+    // 奥に何も無ければ深度は 1 なので薄くならない
+    float fade = depth >= 1.0
+        ? 1.0
+        : clamp((scene_distance - v_distance) / max(u_fade_distance, 0.0001), 0.0, 1.0);
+
+    f_color = vec4(u_color, alpha * fade * u_opacity);
+}
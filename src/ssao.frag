@@ -0,0 +1,86 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_depth;
+layout(set = 0, binding = 1) uniform texture2D t_normal;
+layout(set = 0, binding = 2) uniform texture2D t_noise;
+layout(set = 0, binding = 3) uniform sampler s_point;
+// ノイズは画面に敷き詰める
+layout(set = 0, binding = 4) uniform sampler s_noise;
+
+// ssao.rs の MAX_SAMPLES と合わせること
+const uint MAX_SAMPLES = 64;
+
+layout(set = 0, binding = 5)
+uniform SsaoUniform {
+    mat4 u_view_proj;
+    mat4 u_inv_view_proj;
+    vec4 u_eye;
+    vec4 u_forward;
+    vec2 u_noise_scale;
+    float u_radius;
+    float u_intensity;
+    float u_bias;
+    uint u_sample_count;
+    vec4 u_kernel[MAX_SAMPLES];
+};
+
+vec3 world_position(vec2 uv, float depth) {
+    vec2 ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    vec4 p = u_inv_view_proj * vec4(ndc, depth, 1.0);
+    return p.xyz / p.w;
+}
+
+// 視線方向の距離。透視投影でも平行投影でも同じように比べられる
+float view_depth(vec3 p) {
+    return dot(p - u_eye.xyz, u_forward.xyz);
+}
+
+void main() {
+    vec2 uv = v_tex_coords;
+    float depth = texture(sampler2D(t_depth, s_point), uv).r;
+    vec4 normal = texture(sampler2D(t_normal, s_point), uv);
+    if (depth >= 1.0 || normal.a == 0.0) {
+        f_color = vec4(1.0);
+        return;
+    }
+
+    vec3 p = world_position(uv, depth);
+    vec3 n = normalize(normal.xyz);
+
+    // 画素ごとに半球を回して、少ないサンプル数の縞をノイズに変える
+    vec3 random = texture(sampler2D(t_noise, s_noise), uv * u_noise_scale).xyz * 2.0 - 1.0;
+    vec3 tangent = random - n * dot(random, n);
+    if (dot(tangent, tangent) < 0.0001) {
+        tangent = abs(n.y) < 0.99 ? cross(n, vec3(0.0, 1.0, 0.0)) : cross(n, vec3(1.0, 0.0, 0.0));
+    }
+    tangent = normalize(tangent);
+    mat3 tbn = mat3(tangent, cross(n, tangent), n);
+
+    float occlusion = 0.0;
+    uint count = min(u_sample_count, MAX_SAMPLES);
+    for (uint i = 0; i < count; i++) {
+        vec3 s = p + tbn * u_kernel[i].xyz * u_radius;
+        vec4 clip = u_view_proj * vec4(s, 1.0);
+        if (clip.w <= 0.0) {
+            continue;
+        }
+        vec2 suv = clip.xy / clip.w * vec2(0.5, -0.5) + 0.5;
+        if (any(lessThan(suv, vec2(0.0))) || any(greaterThan(suv, vec2(1.0)))) {
+            continue;
+        }
+        float sd = texture(sampler2D(t_depth, s_point), suv).r;
+        if (sd >= 1.0) {
+            continue;
+        }
+        vec3 q = world_position(suv, sd);
+        // 半径より遠く離れた面は遮蔽物として数えない
+        float range = smoothstep(0.0, 1.0, u_radius / max(distance(p, q), 0.0001));
+        occlusion += (view_depth(q) <= view_depth(s) - u_bias ? 1.0 : 0.0) * range;
+    }
+
+    float ao = clamp(1.0 - u_intensity * occlusion / max(float(count), 1.0), 0.0, 1.0);
+    f_color = vec4(ao, ao, ao, 1.0);
+}
@@ -0,0 +1,20 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_ao;
+layout(set = 0, binding = 1) uniform sampler s_ao;
+
+// ノイズの大きさ (4x4) と同じ範囲を平均して縞を消す
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(sampler2D(t_ao, s_ao), 0));
+    float sum = 0.0;
+    for (int y = -2; y < 2; y++) {
+        for (int x = -2; x < 2; x++) {
+            sum += texture(sampler2D(t_ao, s_ao), v_tex_coords + vec2(x, y) * texel).r;
+        }
+    }
+    float ao = sum / 16.0;
+    f_color = vec4(ao, ao, ao, 1.0);
+}
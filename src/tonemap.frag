@@ -0,0 +1,71 @@
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_hdr;
+layout(set = 0, binding = 1) uniform sampler s_hdr;
+layout(set = 0, binding = 2)
+uniform TonemapUniform {
+    uint u_operator; // 0: aces, 1: reinhard, 2: filmic
+    float u_exposure;
+    uint u_auto_exposure;
+    float u_white;
+};
+// luminance_average.comp が書き込む
+layout(set = 0, binding = 3)
+readonly buffer Exposure {
+    float average_luminance;
+    float auto_exposure;
+};
+
+// Narkowicz による ACES の近似
+vec3 aces(vec3 x) {
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+
+// u_white 以上を白にする拡張版
+vec3 reinhard(vec3 x) {
+    return x * (1.0 + x / (u_white * u_white)) / (1.0 + x);
+}
+
+// Hable (Uncharted 2) のカーブ
+vec3 hable(vec3 x) {
+    const float A = 0.15;
+    const float B = 0.50;
+    const float C = 0.10;
+    const float D = 0.20;
+    const float E = 0.02;
+    const float F = 0.30;
+    return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+}
+
+vec3 filmic(vec3 x) {
+    return hable(x * 2.0) / hable(vec3(u_white));
+}
+
+void main() {
+    vec4 hdr = texture(sampler2D(t_hdr, s_hdr), v_tex_coords);
+
+    float exposure = u_exposure;
+    if (u_auto_exposure == 1) {
+        exposure *= auto_exposure;
+    }
+    vec3 color = hdr.rgb * exposure;
+
+    if (u_operator == 0) {
+        color = aces(color);
+    } else if (u_operator == 1) {
+        color = reinhard(color);
+    } else {
+        color = filmic(color);
+    }
+
+    // ガンマ補正は sRGB のスワップチェーンに任せる
+    f_color = vec4(clamp(color, 0.0, 1.0), hdr.a);
+}